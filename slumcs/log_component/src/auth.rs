@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use config::Config;
+use rand::RngCore;
+use send_log::auth::{verify, NONCE_LEN};
+
+/* auth - which clients may write to the log
+ *
+ * Authentication is required as soon as either key is set in config.toml:
+ *
+ *  auth_secret    secret shared by every application
+ *  [auth_clients] per-application secrets, e.g. billing = "s3cret";
+ *                 an application listed here must use its own secret
+ *
+ * Clients then have to complete the handshake described in
+ * send_log's auth.rs before their records are accepted, and every record
+ * they send is stamped with the application name they authenticated as.
+ * */
+
+pub struct Secrets {
+    shared: Option<String>,
+    per_app: HashMap<String, String>,
+}
+
+impl Secrets {
+    //None when neither key is set and anyone may connect
+    pub fn from_config(settings: &Config) -> Result<Option<Secrets>, String> {
+        let shared = settings.get_string("auth_secret").ok();
+        let mut per_app = HashMap::new();
+        if let Ok(table) = settings.get_table("auth_clients") {
+            for (app, secret) in table {
+                let secret = secret.into_string().map_err(|e| format!("auth_clients.{app}: {e}"))?;
+                per_app.insert(app, secret);
+            }
+        }
+        if shared.is_none() && per_app.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Secrets { shared, per_app }))
+    }
+
+    pub fn challenge() -> Vec<u8> {
+        let mut nonce = vec![0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        nonce
+    }
+
+    //Checks the client's mac over `nonce` with the secret for `app`
+    pub fn check(&self, app: &str, nonce: &[u8], mac: &[u8]) -> Result<(), String> {
+        let secret = match self.per_app.get(app) {
+            Some(secret) => secret,
+            None => self.shared.as_ref().ok_or_else(|| format!("unknown application \"{app}\""))?,
+        };
+        if verify(secret.as_bytes(), nonce, app, mac) {
+            Ok(())
+        } else {
+            Err(format!("bad credentials for \"{app}\""))
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+
+use send_log::compress::Codec;
+use send_log::protocol::{encode_frame, now_millis, Frame, FrameDecoder, Record, Severity};
+
+use crate::auth::Secrets;
+use crate::limit::Limiter;
+use crate::metrics::Metrics;
+use crate::output::Entry;
+use crate::writer::Job;
+
+//Sender name on the records log_component writes about its own clients
+pub const SERVER_SENDER: &str = "log_component";
+
+//Runs on its own thread for each stream client (TCP, TLS or Unix
+//socket). Blocks until the client sends something, decodes every
+//complete frame and passes the records on to the writer, so a record
+//reaches the log as soon as its last byte arrives.
+//
+//Whatever ends the connection (the client hanging up, a read error or a
+//malformed frame) only ends this thread. The connect and disconnect are
+//logged with the peer address and how much the client sent.
+//
+//`stream` is the plain socket or a TLS session over it; a TLS handshake
+//happens on the first read, so a failed one ends up as a read error.
+//
+//With `secrets` set the client must authenticate before its first record
+//(see auth.rs) and its records carry the application name it proved.
+//
+//A sync from the client is answered with an ack once the writer has
+//persisted every record before it (see writer.rs).
+//
+//Records over the rate limits are dropped, sampled or held back by
+//`limiter` (see limit.rs).
+//
+//A client may offer codecs and then send its records in compressed
+//batches; `codecs` are the ones this server accepts (see send_log's
+//compress.rs).
+//
+//The client counts as connected in `metrics` until this returns.
+pub fn serve<S: Read + Write>(
+    mut stream: S,
+    peer_field: String,
+    entries: Sender<Job>,
+    secrets: Option<Arc<Secrets>>,
+    mut limiter: Limiter,
+    codecs: &[Codec],
+    metrics: &Metrics,
+) {
+    let _connected = metrics.connected();
+    let _ = entries.send(server_entry(Severity::Info, "client connected", &[("peer", &peer_field)]).into());
+
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = [0; 4096];
+    let mut records = 0u64;
+    let mut bytes = 0u64;
+    let mut identity: Option<String> = None;
+    //Application name and nonce of a challenge awaiting its answer
+    let mut challenge: Option<(String, Vec<u8>)> = None;
+
+    let (severity, reason) = 'read: loop {
+        let n = match stream.read(&mut buffer) {
+            Ok(0) if decoder.pending() > 0 => {
+                break (Severity::Warn, format!("closed mid-frame with {} bytes pending", decoder.pending()))
+            }
+            Ok(0) => break (Severity::Info, "closed".to_string()),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => break (Severity::Warn, format!("read error: {e}")),
+        };
+        bytes += n as u64;
+        metrics.received(n);
+
+        decoder.push(&buffer[..n]);
+        loop {
+            let mut received = Vec::new();
+            match decoder.next_frame() {
+                Ok(Some(Frame::Record(record))) => received.push(record),
+                Ok(Some(Frame::Batch { codec, data })) => match unpack(codec, &data, codecs) {
+                    Ok(batch) => received = batch,
+                    Err(e) => break 'read (Severity::Warn, format!("protocol error: {e}")),
+                },
+                //The first codec offered that this server allows, if any
+                Ok(Some(Frame::Offer { codecs: offered })) => {
+                    let codec = offered.iter().filter_map(|c| c.parse::<Codec>().ok()).find(|c| codecs.contains(c));
+                    if let Err(e) = reply(&mut stream, Frame::Accept { codec }) {
+                        break 'read (Severity::Warn, format!("write error: {e}"));
+                    }
+                }
+                //Acked once the writer has everything before it on disk
+                Ok(Some(Frame::Sync { seq })) => {
+                    if secrets.is_some() && identity.is_none() {
+                        let _ = reply(&mut stream, Frame::Reject { reason: "authentication required".to_string() });
+                        break 'read (Severity::Warn, "sync before authentication".to_string());
+                    }
+                    let (ack, persisted) = mpsc::channel();
+                    if entries.send(Job::Sync(ack)).is_err() {
+                        return;
+                    }
+                    let error = persisted.recv().unwrap_or_else(|_| Some("log_component is shutting down".to_string()));
+                    if let Err(e) = reply(&mut stream, Frame::Ack { seq, error }) {
+                        break 'read (Severity::Warn, format!("write error: {e}"));
+                    }
+                }
+                Ok(Some(Frame::Hello { app })) => {
+                    let answer = match secrets {
+                        Some(_) => {
+                            let nonce = Secrets::challenge();
+                            challenge = Some((app, nonce.clone()));
+                            Frame::Challenge { nonce }
+                        }
+                        None => Frame::Welcome { identity: app },
+                    };
+                    if let Err(e) = reply(&mut stream, answer) {
+                        break 'read (Severity::Warn, format!("write error: {e}"));
+                    }
+                }
+                Ok(Some(Frame::Auth { mac })) => {
+                    let (Some(secrets), Some((app, nonce))) = (&secrets, challenge.take()) else {
+                        break 'read (Severity::Warn, "protocol error: auth without a challenge".to_string());
+                    };
+                    if let Err(reason) = secrets.check(&app, &nonce, &mac) {
+                        let _ = reply(&mut stream, Frame::Reject { reason: reason.clone() });
+                        break 'read (Severity::Warn, format!("authentication failed: {reason}"));
+                    }
+                    if let Err(e) = reply(&mut stream, Frame::Welcome { identity: app.clone() }) {
+                        break 'read (Severity::Warn, format!("write error: {e}"));
+                    }
+                    let _ = entries.send(server_entry(Severity::Info, "client authenticated", &[("peer", &peer_field), ("app", &app)]).into());
+                    identity = Some(app);
+                }
+                Ok(Some(_)) => break 'read (Severity::Warn, "protocol error: unexpected server frame".to_string()),
+                Ok(None) => break,
+                Err(e) => break 'read (Severity::Warn, format!("protocol error: {e}")),
+            }
+
+            if !received.is_empty() && secrets.is_some() && identity.is_none() {
+                let _ = reply(&mut stream, Frame::Reject { reason: "authentication required".to_string() });
+                break 'read (Severity::Warn, "record before authentication".to_string());
+            }
+            for record in received {
+                records += 1;
+                let entry = Entry {
+                    record,
+                    received: now_millis(),
+                    peer: Some(peer_field.clone()),
+                    identity: identity.clone(),
+                };
+                if !limiter.admit(&entry) {
+                    continue;
+                }
+                if entries.send(entry.into()).is_err() {
+                    return;
+                }
+            }
+        }
+    };
+
+    let records = records.to_string();
+    let bytes = bytes.to_string();
+    let mut fields = vec![("peer", peer_field.as_str())];
+    if let Some(app) = &identity {
+        fields.push(("app", app));
+    }
+    fields.extend([("records", records.as_str()), ("bytes", bytes.as_str()), ("reason", reason.as_str())]);
+    let _ = entries.send(server_entry(severity, "client disconnected", &fields).into());
+}
+
+//The records in a batch frame. Only record frames may be inside, and
+//only whole ones.
+fn unpack(codec: Codec, data: &[u8], allowed: &[Codec]) -> Result<Vec<Record>, String> {
+    if !allowed.contains(&codec) {
+        return Err(format!("batch compressed with {}, which was not accepted", codec.as_str()));
+    }
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&codec.decompress(data).map_err(|e| format!("bad {} batch: {e}", codec.as_str()))?);
+    let mut records = Vec::new();
+    loop {
+        match decoder.next_frame().map_err(|e| e.to_string())? {
+            Some(Frame::Record(record)) => records.push(record),
+            Some(_) => return Err("batch holds something other than records".to_string()),
+            None if decoder.pending() > 0 => return Err("batch ends mid-frame".to_string()),
+            None => return Ok(records),
+        }
+    }
+}
+
+fn reply<S: Write>(stream: &mut S, frame: Frame) -> io::Result<()> {
+    stream.write_all(&encode_frame(&frame))?;
+    stream.flush()
+}
+
+pub fn server_entry(severity: Severity, msg: &str, fields: &[(&str, &str)]) -> Entry {
+    let record = Record::new(SERVER_SENDER, severity, msg).with_fields(fields);
+    Entry { received: record.timestamp, record, peer: None, identity: None }
+}
@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::{Config, Value};
+use send_log::protocol::Severity;
+
+use crate::connection::server_entry;
+use crate::metrics::sender_key;
+use crate::output::Entry;
+use crate::writer::Job;
+
+/* limit - keeps one chatty client from flooding the log
+ *
+ * With a [rate_limit] table in config.toml, records from clients pass
+ * through token buckets before they reach the writer: one set per
+ * connection and one shared by all clients. A record takes one token from
+ * the records/s buckets and its size (sender, message and fields) from
+ * the bytes/s buckets. Every key is optional; an unset rate is unlimited.
+ *
+ *  [rate_limit]
+ *  client_records_per_sec = 100
+ *  client_bytes_per_sec = 65536
+ *  global_records_per_sec = 1000
+ *  global_bytes_per_sec = 1048576
+ *  burst_secs = 1              how many seconds of traffic a bucket holds
+ *  policy = "drop"             what happens to records over the limit
+ *  sample_every = 10           with sample, the one in N that is kept
+ *  summary_interval_ms = 10000 how often dropped records are reported
+ *
+ * Policies:
+ *
+ *  drop       (default) records over the limit are discarded
+ *  sample     one in sample_every records over the limit is kept
+ *  push_back  the connection stops reading until tokens are available,
+ *             so TCP flow control slows the client down
+ *
+ * UDP has no connections: datagrams (send_log and syslog alike) only
+ * count against the global limits, and push_back drops them instead as
+ * there is no one to push back on.
+ *
+ * Every summary_interval_ms, a warning is logged for each client that had
+ * records dropped, with how many. The server's own records are never
+ * limited, except for the warnings about a client's malformed messages
+ * or datagrams: those are charged to that client as if it had sent them,
+ * so that garbage cannot flood the log in their place. The totals kept
+ * for metrics.rs are per sender, capped like its other per-sender
+ * counters.
+ *
+ * SIGHUP reloads the table: connections keep their place but start over
+ * with full buckets under the new limits from their next record.
+ * */
+
+const KEYS: [&str; 8] = [
+    "client_records_per_sec",
+    "client_bytes_per_sec",
+    "global_records_per_sec",
+    "global_bytes_per_sec",
+    "burst_secs",
+    "policy",
+    "sample_every",
+    "summary_interval_ms",
+];
+
+//summary_interval_ms when unset, or while there are no limits
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Drop,
+    Sample,
+    PushBack,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Policy, String> {
+        match s {
+            "drop" => Ok(Policy::Drop),
+            "sample" => Ok(Policy::Sample),
+            "push_back" => Ok(Policy::PushBack),
+            _ => Err(format!("unknown rate_limit.policy \"{s}\", expected drop, sample or push_back")),
+        }
+    }
+}
+
+struct Bucket {
+    //Tokens added per second, and the most it holds
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, burst: f64) -> Bucket {
+        let capacity = (rate * burst).max(1.0);
+        Bucket { rate, capacity, tokens: capacity, last: Instant::now() }
+    }
+
+    //How long until `n` tokens are there; a record bigger than the whole
+    //bucket only waits for it to fill up
+    fn wait(&mut self, n: f64, now: Instant) -> Duration {
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+        let missing = n.min(self.capacity) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    fn take(&mut self, n: f64) {
+        self.tokens = (self.tokens - n).max(0.0);
+    }
+}
+
+//A records/s and a bytes/s bucket, either of which may be unlimited
+#[derive(Default)]
+struct Buckets {
+    records: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+impl Buckets {
+    fn new(records: Option<f64>, bytes: Option<f64>, burst: f64) -> Buckets {
+        Buckets { records: records.map(|r| Bucket::new(r, burst)), bytes: bytes.map(|r| Bucket::new(r, burst)) }
+    }
+
+    fn wait(&mut self, size: f64, now: Instant) -> Duration {
+        let records = self.records.as_mut().map_or(Duration::ZERO, |b| b.wait(1.0, now));
+        let bytes = self.bytes.as_mut().map_or(Duration::ZERO, |b| b.wait(size, now));
+        records.max(bytes)
+    }
+
+    fn take(&mut self, size: f64) {
+        if let Some(b) = &mut self.records {
+            b.take(1.0);
+        }
+        if let Some(b) = &mut self.bytes {
+            b.take(size);
+        }
+    }
+}
+
+pub struct RateLimits {
+    client_records: Option<f64>,
+    client_bytes: Option<f64>,
+    burst: f64,
+    policy: Policy,
+    sample_every: u64,
+    summary_interval: Duration,
+    global: Mutex<Buckets>,
+}
+
+impl RateLimits {
+    //None without a [rate_limit] table
+    pub fn from_config(settings: &Config) -> Result<Option<RateLimits>, String> {
+        let Ok(table) = settings.get_table("rate_limit") else {
+            return Ok(None);
+        };
+        if let Some(key) = table.keys().find(|k| !KEYS.contains(&k.as_str())) {
+            return Err(format!("unknown key rate_limit.{key}"));
+        }
+        let rate = |key: &str| -> Result<Option<f64>, String> {
+            match table.get(key).cloned().map(Value::into_float) {
+                None => Ok(None),
+                Some(Ok(x)) if x > 0.0 && x.is_finite() => Ok(Some(x)),
+                Some(_) => Err(format!("rate_limit.{key} must be a positive number")),
+            }
+        };
+        let policy = match table.get("policy").cloned().map(Value::into_string) {
+            None => Policy::Drop,
+            Some(Ok(x)) => x.parse()?,
+            Some(Err(e)) => return Err(format!("rate_limit.policy: {e}")),
+        };
+        let burst = rate("burst_secs")?.unwrap_or(1.0);
+        let sample_every = rate("sample_every")?.map_or(10, |x| x as u64).max(1);
+        let summary_interval = rate("summary_interval_ms")?.map_or(SUMMARY_INTERVAL, |x| Duration::from_millis((x as u64).max(1)));
+        let global = Buckets::new(rate("global_records_per_sec")?, rate("global_bytes_per_sec")?, burst);
+        Ok(Some(RateLimits {
+            client_records: rate("client_records_per_sec")?,
+            client_bytes: rate("client_bytes_per_sec")?,
+            burst,
+            policy,
+            sample_every,
+            summary_interval,
+            global: Mutex::new(global),
+        }))
+    }
+}
+
+//The limits in force, which SIGHUP may replace, and what they dropped
+#[derive(Default)]
+pub struct Limits {
+    current: RwLock<Option<Arc<RateLimits>>>,
+    //Records dropped per client since the last summary, with the
+    //application it authenticated as
+    dropped: Mutex<HashMap<String, (Option<String>, u64)>>,
+    //Records dropped per sender since the server started, for metrics.rs,
+    //with senders past MAX_SENDERS counted as "other"
+    totals: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Limits {
+    pub fn new(limits: Option<RateLimits>) -> Limits {
+        Limits { current: RwLock::new(limits.map(Arc::new)), ..Limits::default() }
+    }
+
+    //Puts `limits` in force; each client picks them up with its next record
+    pub fn replace(&self, limits: Option<RateLimits>) {
+        *self.current.write().unwrap() = limits.map(Arc::new);
+    }
+
+    fn current(&self) -> Option<Arc<RateLimits>> {
+        self.current.read().unwrap().clone()
+    }
+
+    //Logs the dropped records every summary_interval_ms, until the writer
+    //goes away
+    pub fn report(&self, entries: Sender<Job>) {
+        loop {
+            thread::sleep(self.current().map_or(SUMMARY_INTERVAL, |l| l.summary_interval));
+            let dropped = std::mem::take(&mut *self.dropped.lock().unwrap());
+            let mut clients: Vec<_> = dropped.into_iter().collect();
+            clients.sort();
+            for (peer, (identity, count)) in clients {
+                let count = count.to_string();
+                let mut fields = vec![("peer", peer.as_str())];
+                if let Some(app) = &identity {
+                    fields.push(("app", app));
+                }
+                fields.push(("dropped", &count));
+                let entry = server_entry(Severity::Warn, "records dropped by rate limit", &fields);
+                if entries.send(entry.into()).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn dropped_totals(&self) -> Vec<(String, u64)> {
+        self.totals.lock().unwrap().iter().map(|(sender, n)| (sender.clone(), *n)).collect()
+    }
+}
+
+//The limits as one client sees them
+pub struct Limiter {
+    limits: Arc<Limits>,
+    //The limits `client` was set up for
+    current: Option<Arc<RateLimits>>,
+    client: Buckets,
+    //Whether this client can be pushed back on
+    stream: bool,
+    //Records over the limit so far, for sampling
+    over: u64,
+}
+
+impl Limiter {
+    //For a connection: its own buckets as well as the global ones
+    pub fn stream(limits: &Arc<Limits>) -> Limiter {
+        Limiter { limits: limits.clone(), current: None, client: Buckets::default(), stream: true, over: 0 }
+    }
+
+    //For datagrams: the global buckets only
+    pub fn datagram(limits: &Arc<Limits>) -> Limiter {
+        Limiter { limits: limits.clone(), current: None, client: Buckets::default(), stream: false, over: 0 }
+    }
+
+    //Starts over with fresh buckets when the limits in force are not the
+    //ones this client last saw
+    fn update(&mut self) {
+        let current = self.limits.current();
+        let unchanged = match (&current, &self.current) {
+            (Some(new), Some(old)) => Arc::ptr_eq(new, old),
+            (new, old) => new.is_none() && old.is_none(),
+        };
+        if unchanged {
+            return;
+        }
+        self.client = match (&current, self.stream) {
+            (Some(l), true) => Buckets::new(l.client_records, l.client_bytes, l.burst),
+            _ => Buckets::default(),
+        };
+        self.current = current;
+        self.over = 0;
+    }
+
+    //Whether `entry` goes on to the writer. With push_back this blocks
+    //until it may.
+    pub fn admit(&mut self, entry: &Entry) -> bool {
+        self.admit_for(entry, entry.peer.as_deref())
+    }
+
+    //Like admit, for the server's warning about something `peer` sent
+    pub fn admit_warning(&mut self, warning: &Entry, peer: &str) -> bool {
+        self.admit_for(warning, Some(peer))
+    }
+
+    //Charges `entry` to this client, counting it against `peer` when it
+    //is dropped
+    fn admit_for(&mut self, entry: &Entry, peer: Option<&str>) -> bool {
+        self.update();
+        let Some(limits) = &self.current else { return true };
+        let record = &entry.record;
+        let size = (record.sender.len()
+            + record.payload.len()
+            + record.fields.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()) as f64;
+        loop {
+            let now = Instant::now();
+            let mut global = limits.global.lock().unwrap();
+            let wait = self.client.wait(size, now).max(global.wait(size, now));
+            if wait.is_zero() {
+                self.client.take(size);
+                global.take(size);
+                return true;
+            }
+            drop(global);
+            match limits.policy {
+                Policy::PushBack if self.stream => thread::sleep(wait),
+                Policy::Sample => {
+                    self.over += 1;
+                    if (self.over - 1).is_multiple_of(limits.sample_every) {
+                        return true;
+                    }
+                    break;
+                }
+                _ => break,
+            }
+        }
+        let peer = peer.unwrap_or_default().to_string();
+        let mut dropped = self.limits.dropped.lock().unwrap();
+        let client = dropped.entry(peer).or_insert((None, 0));
+        client.0 = entry.identity.clone();
+        client.1 += 1;
+        drop(dropped);
+        let mut totals = self.limits.totals.lock().unwrap();
+        let sender = sender_key(&totals, &entry.record.sender).to_string();
+        *totals.entry(sender).or_insert(0) += 1;
+        false
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use send_log::compress::Codec;
+use send_log::protocol::{now_millis, Frame, FrameDecoder, Severity};
+
+use crate::auth::Secrets;
+use crate::connection::{self, server_entry};
+use crate::limit::{Limiter, Limits};
+use crate::metrics::Metrics;
+use crate::output::Entry;
+use crate::writer::Job;
+
+/* listen - the sockets clients reach log_component on
+ *
+ *  tcp   always, on log_ip:log_port, with TLS when configured
+ *  unix  when log_socket is set, a Unix domain socket at that path
+ *  udp   when log_udp = true, datagrams on log_ip:log_port
+ *
+ * Stream clients get a connection thread each (see connection.rs). UDP
+ * has no connections: a single thread decodes each datagram on its own
+ * and hands its records to the writer.
+ *
+ * Stream clients, syslog's included, are registered in Clients while they
+ * are served. At shutdown Clients turns new ones away and stops reading
+ * from the others once they have been read to the end of what they had
+ * sent, so their records still reach the writer. Datagrams still waiting
+ * in the socket are lost.
+ * */
+
+//Datagrams never exceed this
+const MAX_DATAGRAM: usize = 65_536;
+
+//A stream client's socket, kept to stop reading from it at shutdown
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    //Reads return what has already arrived, then end of stream
+    fn stop_reading(&self) {
+        let _ = match self {
+            Socket::Tcp(s) => s.shutdown(Shutdown::Read),
+            Socket::Unix(s) => s.shutdown(Shutdown::Read),
+        };
+    }
+}
+
+#[derive(Default)]
+struct Open {
+    stopping: bool,
+    next: u64,
+    sockets: HashMap<u64, Socket>,
+}
+
+//The stream clients being served
+#[derive(Default)]
+pub struct Clients {
+    open: Mutex<Open>,
+    //Signalled whenever a client is done
+    closed: Condvar,
+}
+
+//Keeps a client registered until dropped
+pub struct Served {
+    clients: Arc<Clients>,
+    id: u64,
+}
+
+impl Drop for Served {
+    fn drop(&mut self) {
+        self.clients.open.lock().unwrap().sockets.remove(&self.id);
+        self.clients.closed.notify_all();
+    }
+}
+
+impl Clients {
+    //None once shutting down, or if the socket cannot be kept
+    pub fn add(self: &Arc<Self>, socket: io::Result<Socket>) -> Option<Served> {
+        let socket = socket.ok()?;
+        let mut open = self.open.lock().unwrap();
+        if open.stopping {
+            return None;
+        }
+        let id = open.next;
+        open.next += 1;
+        open.sockets.insert(id, socket);
+        Some(Served { clients: self.clone(), id })
+    }
+
+    //Turns new clients away and waits up to `timeout` for the others to
+    //finish what they sent. Returns how many were still being read.
+    pub fn stop(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut open = self.open.lock().unwrap();
+        open.stopping = true;
+        for socket in open.sockets.values() {
+            socket.stop_reading();
+        }
+        while !open.sockets.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            open = self.closed.wait_timeout(open, left).unwrap().0;
+        }
+        open.sockets.len()
+    }
+}
+
+//What every stream client is served with
+#[derive(Clone)]
+pub struct Service {
+    pub secrets: Option<Arc<Secrets>>,
+    pub limits: Arc<Limits>,
+    pub codecs: Vec<Codec>,
+    pub metrics: Arc<Metrics>,
+    pub clients: Arc<Clients>,
+    pub records: Sender<Job>,
+}
+
+impl Service {
+    //Serves one client on this thread, registered until it is done
+    fn serve<S: Read + Write>(self, stream: S, peer: String, _served: Served) {
+        let limiter = Limiter::stream(&self.limits);
+        connection::serve(stream, peer, self.records, self.secrets, limiter, &self.codecs, &self.metrics);
+    }
+}
+
+pub fn tcp(listener: TcpListener, tls: Option<Arc<ServerConfig>>, service: Service) {
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                let Some(served) = service.clients.add(stream.try_clone().map(Socket::Tcp)) else {
+                    continue;
+                };
+                eprintln!("New connection from {}", peer);
+                let service = service.clone();
+                let peer = peer.to_string();
+                match &tls {
+                    Some(config) => {
+                        let session = ServerConnection::new(config.clone()).expect("Error starting TLS session");
+                        let stream = StreamOwned::new(session, stream);
+                        thread::spawn(move || service.serve(stream, peer, served))
+                    }
+                    None => thread::spawn(move || service.serve(stream, peer, served)),
+                };
+            }
+            Err(e) => eprintln!("Error accepting connection: {}", e),
+        }
+    }
+}
+
+//Binds log_socket, replacing a socket file left behind by an earlier run
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "exists and is not a socket")),
+        Err(_) => {}
+    }
+    UnixListener::bind(path)
+}
+
+//Local clients have no address of their own, so they are all known by
+//the socket path
+pub fn unix(listener: UnixListener, path: String, service: Service) {
+    let peer = format!("unix:{path}");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let Some(served) = service.clients.add(stream.try_clone().map(Socket::Unix)) else {
+                    continue;
+                };
+                eprintln!("New connection on {}", path);
+                let service = service.clone();
+                let peer = peer.clone();
+                thread::spawn(move || service.serve(stream, peer, served));
+            }
+            Err(e) => eprintln!("Error accepting connection: {}", e),
+        }
+    }
+}
+
+pub fn udp(socket: UdpSocket, limits: Arc<Limits>, records: Sender<Job>) {
+    let mut limiter = Limiter::datagram(&limits);
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Error receiving datagram: {}", e);
+                continue;
+            }
+        };
+        let peer = peer.to_string();
+
+        //A datagram holds whole frames; anything left over is an error
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&buffer[..n]);
+        let problem = loop {
+            match decoder.next_frame() {
+                Ok(Some(Frame::Record(record))) => {
+                    let entry = Entry { record, received: now_millis(), peer: Some(peer.clone()), identity: None };
+                    if !limiter.admit(&entry) {
+                        continue;
+                    }
+                    if records.send(entry.into()).is_err() {
+                        return;
+                    }
+                }
+                Ok(Some(_)) => break Some("unexpected handshake frame".to_string()),
+                Ok(None) if decoder.pending() > 0 => break Some(format!("{} trailing bytes", decoder.pending())),
+                Ok(None) => break None,
+                Err(e) => break Some(format!("protocol error: {e}")),
+            }
+        };
+        if let Some(reason) = problem {
+            let fields = [("peer", peer.as_str()), ("reason", reason.as_str())];
+            let warning = server_entry(Severity::Warn, "bad datagram", &fields);
+            if limiter.admit_warning(&warning, &peer) {
+                let _ = records.send(warning.into());
+            }
+        }
+    }
+}
@@ -1,65 +1,354 @@
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+/* log_component - the global logging server
+ *
+ * A listener thread accepts TCP clients and starts a thread per
+ * connection (see connection.rs); Unix socket and UDP clients are taken
+ * in on threads of their own (see listen.rs), and the main thread waits
+ * for signals. Connection threads block on their own socket and
+ * pass each complete record over a channel to a single writer thread,
+ * which owns the log file (see writer.rs). Nothing polls or sleeps: a
+ * record is written as soon as it has been received.
+ *
+ * The configuration is found as described in send_log's settings.rs:
+ * --config <path>, SLUMCS_CONFIG, then ./config.toml and the standard
+ * locations. log_ip, log_port and log_file are required.
+ *
+ * Optional config.toml keys for the log file (see rotate.rs):
+ *
+ *  output_format     plain, json or logfmt (see output.rs)
+ *  rotate_max_bytes  rotate before the file would exceed this size
+ *  rotate_interval   never, hourly or daily
+ *  rotate_compress   gzip rotated files
+ *  rotate_keep       how many rotated files to keep (0 = all)
+ *  fsync             never, every_record or interval (see writer.rs)
+ *  fsync_interval_ms how often interval fsyncs (1000)
+ *  reorder_window_ms hold records this long to write them in the order
+ *                    of their logical clocks (see reorder.rs)
+ *
+ * Optional listeners besides TCP on log_ip:log_port:
+ *
+ *  log_socket        also accept clients on this Unix domain socket
+ *  log_udp           also take records as UDP datagrams on log_port;
+ *                    refused when clients must authenticate or present
+ *                    certificates, as datagrams carry no identity
+ *  syslog_port       receive syslog messages over UDP and TCP on this
+ *                    port, on syslog_ip or log_ip (see syslog.rs); when
+ *                    clients must authenticate or present certificates,
+ *                    only with syslog_unauthenticated = true
+ *  subscribe_port    stream written lines live to subscribers, filtered
+ *                    by sender, level or text, on subscribe_ip or log_ip
+ *                    (see subscribe.rs); with tls or authentication,
+ *                    only on a loopback address unless
+ *                    subscribe_unauthenticated = true
+ *  metrics_port      serve Prometheus metrics and a health check over
+ *                    HTTP, on metrics_ip or log_ip (see metrics.rs); with
+ *                    ring sinks and tls or authentication, only on a
+ *                    loopback address unless ring_unauthenticated = true
+ *
+ * With a [rate_limit] table, records/s and bytes/s limits apply per
+ * connection and across all clients, and records over them are dropped,
+ * sampled or held back (see limit.rs).
+ *
+ * With a [relay] table every client record is also forwarded to another
+ * log_component, buffering while it is unreachable (see relay.rs).
+ *
+ * [sinks.<name>] tables and [[routes]] send records to other files,
+ * stdout, another log_component or a ring buffer in memory, by sender,
+ * severity or field values (see sink.rs).
+ *
+ * With tls = true clients connect over TLS, optionally with client
+ * certificates (see tls.rs). With auth_secret or [auth_clients] set,
+ * clients must authenticate before they can log (see auth.rs). Clients may
+ * send compressed batches of records with any codec listed in compression
+ * ("zstd,deflate" by default, "none" to refuse; see send_log's
+ * compress.rs).
+ *
+ * SIGHUP reloads config.toml without dropping anyone: log_file and the
+ * keys for it above (output_format, rotate_*, fsync*) and the [rate_limit]
+ * table take effect at once, and log_file is opened afresh, so an external
+ * logrotate can move it away and signal the server afterwards. Everything
+ * else, listeners included, needs a restart. A configuration that does
+ * not load is reported in the log and the old one kept.
+ *
+ * SIGINT and SIGTERM shut the server down cleanly: new clients are turned
+ * away, connected ones are read to the end of what they had sent, for up
+ * to shutdown_timeout_ms (5000), then log_file is fsynced and closed and
+ * the server exits.
+ * */
+
+use std::net::{IpAddr, TcpListener, UdpSocket};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use config::{Config, File};
+use std::env;
+use std::fmt::Display;
+use std::process::exit;
+use config::Config;
+use send_log::compress::{self, Codec};
+use send_log::protocol::Severity;
+use send_log::settings;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+mod auth;
+mod connection;
+mod limit;
+mod listen;
+mod metrics;
+mod output;
+mod relay;
+mod reorder;
+mod rotate;
+mod sink;
+mod subscribe;
+mod syslog;
+mod tls;
+mod writer;
+
+use connection::server_entry;
+use limit::{Limits, RateLimits};
+use listen::{Clients, Service};
+use metrics::Metrics;
+use relay::Relay;
+use reorder::Reorder;
+use rotate::RotatingFile;
+use sink::Router;
+use subscribe::Subscribers;
+use writer::{FileSettings, Job, Writer};
+
+//Reports a configuration problem and stops before anything is started
+fn fatal(msg: impl Display) -> ! {
+    eprintln!("log_component: {}", msg);
+    exit(1);
+}
+
+//Checks the keys the server cannot run without, reporting every problem
+//at once rather than one per restart
+fn required_keys(settings: &Config) -> Result<(String, u16, String), String> {
+    let mut problems = Vec::new();
+    let mut get = |key: &str| match settings.get_string(key) {
+        Ok(x) if !x.trim().is_empty() => Some(x),
+        Ok(_) => {
+            problems.push(format!("\"{key}\" is empty"));
+            None
+        }
+        Err(_) => {
+            problems.push(format!("\"{key}\" is missing"));
+            None
+        }
+    };
+    let log_ip = get("log_ip");
+    let log_port = get("log_port");
+    let log_file = get("log_file");
+    let log_port = log_port.and_then(|port| match port.parse::<u16>() {
+        Ok(x) => Some(x),
+        Err(_) => {
+            problems.push(format!("\"log_port\" must be a port number between 0 and 65535, not {port}"));
+            None
+        }
+    });
+    match (log_ip, log_port, log_file) {
+        (Some(ip), Some(port), Some(file)) => Ok((ip, port, file)),
+        _ => Err(problems.join(", ")),
+    }
+}
+
+//An optional port key, which must be a valid port when set
+fn optional_port(settings: &Config, key: &str) -> Option<u16> {
+    let port = settings.get_string(key).ok()?;
+    match port.parse::<u16>() {
+        Ok(x) => Some(x),
+        Err(_) => fatal(format!("\"{key}\" must be a port number between 0 and 65535, not {port}")),
+    }
+}
+
+//Whether a listener on `ip` can only be reached from this host
+fn loopback(ip: &str) -> bool {
+    ip == "localhost" || ip.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+//Puts what SIGHUP can change in config.toml into effect
+fn reload(config_path: &Path, limits: &Limits, records: &Sender<Job>) -> Result<(), String> {
+    let settings = settings::load(config_path).map_err(|e| e.to_string())?;
+    let (_, _, log_file) = required_keys(&settings)?;
+    let file = FileSettings::from_config(&settings, log_file)?;
+    let rate_limits = RateLimits::from_config(&settings)?;
+    limits.replace(rate_limits);
+    let _ = records.send(Job::Reload(Box::new(file)));
+    Ok(())
+}
+
+//Stops taking records, waits for the writer to close log_file and exits
+fn shutdown(signal: &str, clients: &Clients, timeout: Duration, records: &Sender<Job>) -> ! {
+    let _ = records.send(server_entry(Severity::Info, "shutting down", &[("signal", signal)]).into());
+    let left = clients.stop(timeout);
+    if left > 0 {
+        let left = left.to_string();
+        let _ = records.send(server_entry(Severity::Warn, "clients still sending at shutdown", &[("clients", &left)]).into());
+    }
+    let (ack, closed) = mpsc::channel();
+    let _ = records.send(Job::Stop(ack));
+    match closed.recv() {
+        Ok(Some(e)) => fatal(format!("log_file may be incomplete: {e}")),
+        _ => exit(0),
+    }
+}
 
 fn main() {
-    let current_dir = std::env::current_dir().unwrap();
-    //GRADING NOTE: This config file path does not work
-    let config_path_buf = current_dir.join("../config.toml");
-    let config_path = config_path_buf.to_str().unwrap();
-
-    let settings = match Config::builder()
-        .add_source(File::with_name(config_path))
-        .build() {
-            Ok(x) => x,
-            Err(x) => panic!("Could not open configuration file: {}", x),
-        };
-
-    let log_ip = settings.get_string("log_ip").unwrap();
-    let log_port = settings.get_int("log_port").unwrap();
-    let log_file = settings.get_string("log_file").unwrap();
-
-    let mut log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&log_file)
-        .expect("Error opening log file");
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let explicit = settings::take_config_arg(&mut args).unwrap_or_else(|e| fatal(e));
+    if let Some(arg) = args.first() {
+        fatal(format!("unexpected argument \"{arg}\"\nUsage: log_component [--config <path>]"));
+    }
+    let config_path = settings::locate(explicit.as_deref()).unwrap_or_else(|e| fatal(e));
+    let settings = settings::load(&config_path).unwrap_or_else(|e| fatal(e));
+    let (log_ip, log_port, log_file) = required_keys(&settings)
+        .unwrap_or_else(|e| fatal(format!("invalid configuration in {}: {}", config_path.display(), e)));
 
-    let listener = TcpListener::bind(format!("{}:{}", log_ip, log_port))
-        .expect("Error binding to socket");
+    let file = FileSettings::from_config(&settings, log_file).unwrap_or_else(|e| fatal(e));
+    let shutdown_timeout = Duration::from_millis(settings.get_int("shutdown_timeout_ms").map_or(5000, |x| x.max(0) as u64));
+    let reorder_window = settings.get_int("reorder_window_ms").map_or(0, |x| x.max(0) as u64);
+
+    let tls = tls::server_config(&settings).unwrap_or_else(|e| fatal(e));
+    let secrets = auth::Secrets::from_config(&settings)
+        .unwrap_or_else(|e| fatal(e))
+        .map(Arc::new);
 
-    let mut connections: Vec<TcpStream> = vec![];
+    let codecs = match settings.get_string("compression") {
+        Ok(x) => compress::parse_codecs(&x).unwrap_or_else(|e| fatal(format!("invalid compression: {e}"))),
+        Err(_) => vec![Codec::Zstd, Codec::Deflate],
+    };
+    let limits = Arc::new(Limits::new(RateLimits::from_config(&settings).unwrap_or_else(|e| fatal(e))));
+    let relay = Relay::from_config(&settings).unwrap_or_else(|e| fatal(e));
+    let router = Router::from_config(&settings).unwrap_or_else(|e| fatal(e));
+    let rings = Arc::new(router.rings());
+
+    let log_file = RotatingFile::open(&file.path, file.rotate.clone())
+        .unwrap_or_else(|e| fatal(format!("cannot open log_file {}: {}", file.path.display(), e)));
+
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM]).expect("Error installing signal handlers");
+
+    let unix = settings.get_string("log_socket").ok().map(|path| {
+        let listener = listen::bind_unix(Path::new(&path))
+            .unwrap_or_else(|e| fatal(format!("cannot listen on log_socket {}: {}", path, e)));
+        (listener, path)
+    });
+    //With secrets or client certificates every client is identified, so
+    //listeners that cannot tell who is sending must not be open to anyone
+    let identified = secrets.is_some() || (tls.is_some() && settings.get_string("tls_client_ca_file").is_ok());
+    let udp = match settings.get_bool("log_udp").unwrap_or(false) {
+        true if identified => fatal("log_udp cannot be combined with authentication or client certificates: datagrams carry no identity"),
+        true => Some(UdpSocket::bind(format!("{}:{}", log_ip, log_port))
+            .unwrap_or_else(|e| fatal(format!("cannot listen for udp on {}:{}: {}", log_ip, log_port, e)))),
+        false => None,
+    };
+    let syslog_open = settings.get_bool("syslog_unauthenticated").unwrap_or(false);
+    let syslog = optional_port(&settings, "syslog_port").map(|port| {
+        if identified && !syslog_open {
+            fatal("syslog_port cannot be combined with authentication or client certificates unless syslog_unauthenticated = true: syslog carries no identity");
+        }
+        let ip = settings.get_string("syslog_ip").unwrap_or_else(|_| log_ip.clone());
+        let addr = format!("{}:{}", ip, port);
+        let socket = UdpSocket::bind(&addr).unwrap_or_else(|e| fatal(format!("cannot listen for syslog on udp {}: {}", addr, e)));
+        let listener = TcpListener::bind(&addr).unwrap_or_else(|e| fatal(format!("cannot listen for syslog on tcp {}: {}", addr, e)));
+        (socket, listener)
+    });
+    let subscribe_open = settings.get_bool("subscribe_unauthenticated").unwrap_or(false);
+    let subscribe = optional_port(&settings, "subscribe_port").map(|port| {
+        let ip = settings.get_string("subscribe_ip").unwrap_or_else(|_| log_ip.clone());
+        if (tls.is_some() || secrets.is_some()) && !loopback(&ip) && !subscribe_open {
+            fatal("subscribe_port has no TLS or authentication, so with either configured it must be on a loopback subscribe_ip unless subscribe_unauthenticated = true");
+        }
+        TcpListener::bind(format!("{}:{}", ip, port))
+            .unwrap_or_else(|e| fatal(format!("cannot listen for subscribers on {}:{}: {}", ip, port, e)))
+    });
+    let ring_open = settings.get_bool("ring_unauthenticated").unwrap_or(false);
+    let metrics_listener = optional_port(&settings, "metrics_port").map(|port| {
+        let ip = settings.get_string("metrics_ip").unwrap_or_else(|_| log_ip.clone());
+        if !rings.is_empty() && (tls.is_some() || secrets.is_some()) && !loopback(&ip) && !ring_open {
+            fatal("ring sinks are served on metrics_port without TLS or authentication, so with either configured it must be on a loopback metrics_ip unless ring_unauthenticated = true");
+        }
+        TcpListener::bind(format!("{}:{}", ip, port))
+            .unwrap_or_else(|e| fatal(format!("cannot listen for metrics on {}:{}: {}", ip, port, e)))
+    });
+    //Bound last: once TCP accepts, every configured listener is up
+    let listener = TcpListener::bind(format!("{}:{}", log_ip, log_port))
+        .unwrap_or_else(|e| fatal(format!("cannot listen on {}:{}: {}", log_ip, log_port, e)));
 
-    loop {
-        // Accept new connections and add them to our list of connections
-        if let Ok((stream, _)) = listener.accept() {
-            println!("New connection from {}", stream.peer_addr().unwrap());
-            stream.set_nonblocking(true).expect("Error setting non-blocking mode");
-            connections.push(stream);
+    let (records, received) = mpsc::channel();
+    let subscribers = Arc::new(Subscribers::default());
+    let metrics = Arc::new(Metrics::new(log_file.size()));
+    let writer = Writer {
+        log_file,
+        format: file.format,
+        fsync: file.fsync,
+        subscribers: subscribers.clone(),
+        relay,
+        router,
+        metrics: metrics.clone(),
+    };
+    //With a reorder window, records reach the writer through Reorder
+    let received = match reorder_window {
+        0 => received,
+        ms => {
+            let (ordered, writer_jobs) = mpsc::channel();
+            let reorder = Reorder::new(Duration::from_millis(ms));
+            thread::spawn(move || reorder.run(received, ordered));
+            writer_jobs
         }
+    };
+    thread::spawn(move || writer.run(received));
+
+    let clients = Arc::new(Clients::default());
+    {
+        let (limits, records) = (limits.clone(), records.clone());
+        thread::spawn(move || limits.report(records));
+    }
+    let service = Service {
+        secrets,
+        limits: limits.clone(),
+        codecs,
+        metrics: metrics.clone(),
+        clients: clients.clone(),
+        records: records.clone(),
+    };
+    if let Some((listener, path)) = unix {
+        let service = service.clone();
+        thread::spawn(move || listen::unix(listener, path, service));
+    }
+    if let Some(socket) = udp {
+        let (limits, records) = (limits.clone(), records.clone());
+        thread::spawn(move || listen::udp(socket, limits, records));
+    }
+    if let Some((socket, listener)) = syslog {
+        let (udp_limits, udp_records) = (limits.clone(), records.clone());
+        thread::spawn(move || syslog::udp(socket, udp_limits, udp_records));
+        let (tcp_limits, tcp_clients, tcp_records) = (limits.clone(), clients.clone(), records.clone());
+        thread::spawn(move || syslog::tcp(listener, tcp_limits, tcp_clients, tcp_records));
+    }
+    if let Some(listener) = subscribe {
+        let records = records.clone();
+        thread::spawn(move || subscribe::listen(listener, subscribers, records));
+    }
+    if let Some(listener) = metrics_listener {
+        let (metrics, limits) = (metrics.clone(), limits.clone());
+        thread::spawn(move || metrics::listen(listener, metrics, limits, rings));
+    }
+    thread::spawn(move || listen::tcp(listener, tls, service));
 
-        // Read data from all connected clients
-        for mut stream in connections.iter_mut() {
-            let mut buffer = [0; 1024];
-            match stream.read(&mut buffer) {
-                Ok(n) if n > 0 => {
-                    let message = std::str::from_utf8(&buffer[..n]).expect("Invalid UTF-8");
-                    println!("Received message: {}", message);
-                    log_file.write_all(message.as_bytes()).expect("Error writing to log file");
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                Err(e) => {
-                    println!("Error reading from stream: {}", e);
-                    panic!("Error reading from stream");
-                }
-                _ => {}
+    for signal in signals.forever() {
+        match signal {
+            SIGHUP => {
+                let config = config_path.display().to_string();
+                let entry = match reload(&config_path, &limits, &records) {
+                    Ok(()) => server_entry(Severity::Info, "configuration reloaded", &[("config", &config)]),
+                    Err(e) => server_entry(Severity::Error, "configuration not reloaded", &[("config", &config), ("reason", &e)]),
+                };
+                let _ = records.send(entry.into());
             }
+            SIGINT => shutdown("SIGINT", &clients, shutdown_timeout, &records),
+            _ => shutdown("SIGTERM", &clients, shutdown_timeout, &records),
         }
-
-        // Sleep for a short period to avoid busy waiting
-        thread::sleep(Duration::from_millis(100));
     }
 }
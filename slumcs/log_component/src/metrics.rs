@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::limit::Limits;
+use crate::sink::Ring;
+
+/* metrics - counters for Prometheus and a health check, over HTTP
+ *
+ * With metrics_port set, log_component answers HTTP GET requests on
+ * metrics_ip (default log_ip):metrics_port:
+ *
+ *  /metrics  everything below, in the Prometheus text format
+ *  /health   200 "ok", or 503 with the error while the last write or
+ *            fsync of log_file has failed and none has succeeded since
+ *  /ring/<name>  the lines held by a ring sink, oldest first (see sink.rs)
+ *
+ * There is no authentication here, so when clients have to use TLS or
+ * authenticate, log_component refuses to start with ring sinks on a
+ * metrics_ip other than loopback unless ring_unauthenticated = true.
+ *
+ * Rates are left to Prometheus: records and bytes per second are the
+ * rate() of the _total counters.
+ *
+ *  log_component_uptime_seconds            since the server started
+ *  log_component_connections               stream clients connected now
+ *  log_component_connections_total         stream clients ever connected
+ *  log_component_received_bytes_total      read from stream clients
+ *  log_component_records_written_total     records written to log_file
+ *  log_component_bytes_written_total       bytes written to log_file
+ *  log_component_client_records_total      the same, per sender
+ *  log_component_client_bytes_total        the same, per sender
+ *  log_component_records_dropped_total     per sender, by the rate limits
+ *                                          (see limit.rs)
+ *  log_component_write_errors_total        failed writes and fsyncs
+ *  log_component_write_seconds             histogram of how long writing
+ *                                          one line to log_file took
+ *  log_component_log_file_bytes            size of the current log_file
+ *
+ * Senders are named by the clients themselves, so only the first
+ * MAX_SENDERS seen get series of their own; records from any others are
+ * counted under sender="other".
+ *
+ * The counters are kept whether or not metrics_port is set; they are
+ * only atomics and a map updated by the writer thread.
+ * */
+
+//How long a scraper has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+//Requests are a line and some headers; anything longer is not a scraper
+const MAX_REQUEST: usize = 8192;
+
+//Senders counted on their own, in each per-sender map
+pub const MAX_SENDERS: usize = 1000;
+
+//The key `sender` is counted under in `map`: its own until the map holds
+//MAX_SENDERS, "other" for any new one after that
+pub fn sender_key<'a, V>(map: &BTreeMap<String, V>, sender: &'a str) -> &'a str {
+    if map.len() < MAX_SENDERS || map.contains_key(sender) {
+        sender
+    } else {
+        "other"
+    }
+}
+
+//Upper bounds of the write_seconds buckets
+const WRITE_BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Default)]
+struct Histogram {
+    //Observations per bucket, not yet cumulative; the last is +Inf
+    counts: [u64; WRITE_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = WRITE_BUCKETS.iter().position(|&le| value <= le).unwrap_or(WRITE_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+}
+
+//What the writer has written, by sender
+#[derive(Default)]
+struct Written {
+    //Records and bytes per sender
+    clients: BTreeMap<String, (u64, u64)>,
+    latency: Histogram,
+    errors: u64,
+    //The last error, until a write succeeds again
+    failing: Option<String>,
+}
+
+pub struct Metrics {
+    started: Instant,
+    connections: AtomicU64,
+    connections_total: AtomicU64,
+    received_bytes: AtomicU64,
+    file_size: AtomicU64,
+    written: Mutex<Written>,
+}
+
+//Counts a stream client as connected until dropped
+pub struct Connected<'a>(&'a Metrics);
+
+impl Drop for Connected<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new(file_size: u64) -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            connections_total: AtomicU64::new(0),
+            received_bytes: AtomicU64::new(0),
+            file_size: AtomicU64::new(file_size),
+            written: Mutex::new(Written::default()),
+        }
+    }
+
+    pub fn connected(&self) -> Connected<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        Connected(self)
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.received_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    //A line of `bytes` from `sender` reached log_file in `took`, leaving
+    //it `file_size` long
+    pub fn wrote(&self, sender: &str, bytes: usize, took: Duration, file_size: u64) {
+        self.file_size.store(file_size, Ordering::Relaxed);
+        let mut written = self.written.lock().unwrap();
+        let sender = sender_key(&written.clients, sender);
+        match written.clients.get_mut(sender) {
+            Some((records, total)) => {
+                *records += 1;
+                *total += bytes as u64;
+            }
+            None => {
+                written.clients.insert(sender.to_string(), (1, bytes as u64));
+            }
+        }
+        written.latency.observe(took.as_secs_f64());
+        written.failing = None;
+    }
+
+    pub fn reopened(&self, file_size: u64) {
+        self.file_size.store(file_size, Ordering::Relaxed);
+    }
+
+    pub fn failed(&self, error: String) {
+        let mut written = self.written.lock().unwrap();
+        written.errors += 1;
+        written.failing = Some(error);
+    }
+
+    //None while healthy, else the error that makes it unhealthy
+    pub fn failing(&self) -> Option<String> {
+        self.written.lock().unwrap().failing.clone()
+    }
+
+    //Everything in the Prometheus text format
+    pub fn render(&self, limits: &Limits) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        metric("log_component_uptime_seconds", "gauge", "Seconds since the server started.", self.started.elapsed().as_secs_f64().to_string());
+        metric("log_component_connections", "gauge", "Stream clients connected now.", load(&self.connections));
+        metric("log_component_connections_total", "counter", "Stream clients ever connected.", load(&self.connections_total));
+        metric("log_component_received_bytes_total", "counter", "Bytes read from stream clients.", load(&self.received_bytes));
+        metric("log_component_log_file_bytes", "gauge", "Size of the current log file.", load(&self.file_size));
+
+        let written = self.written.lock().unwrap();
+        let (records, bytes) = written.clients.values().fold((0, 0), |(r, b), (records, bytes)| (r + records, b + bytes));
+        metric("log_component_records_written_total", "counter", "Records written to the log file.", records.to_string());
+        metric("log_component_bytes_written_total", "counter", "Bytes written to the log file.", bytes.to_string());
+        metric("log_component_write_errors_total", "counter", "Failed writes and fsyncs of the log file.", written.errors.to_string());
+
+        let per_sender = |out: &mut String, name: &str, help: &str, values: Vec<(&str, u64)>| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} counter\n");
+            for (sender, value) in values {
+                let _ = writeln!(out, "{name}{{sender=\"{}\"}} {value}", escape_label(sender));
+            }
+        };
+        let clients: Vec<_> = written.clients.iter().map(|(s, (r, _))| (s.as_str(), *r)).collect();
+        per_sender(&mut out, "log_component_client_records_total", "Records written to the log file per sender.", clients);
+        let clients: Vec<_> = written.clients.iter().map(|(s, (_, b))| (s.as_str(), *b)).collect();
+        per_sender(&mut out, "log_component_client_bytes_total", "Bytes written to the log file per sender.", clients);
+        let dropped = limits.dropped_totals();
+        let dropped = dropped.iter().map(|(s, n)| (s.as_str(), *n)).collect();
+        per_sender(&mut out, "log_component_records_dropped_total", "Records dropped by the rate limits per sender.", dropped);
+
+        let name = "log_component_write_seconds";
+        let latency = &written.latency;
+        let _ = write!(out, "# HELP {name} Time taken to write one line to the log file.\n# TYPE {name} histogram\n");
+        let mut cumulative = 0;
+        for (le, count) in WRITE_BUCKETS.iter().map(f64::to_string).chain(["+Inf".to_string()]).zip(latency.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let _ = write!(out, "{name}_sum {}\n{name}_count {cumulative}\n", latency.sum);
+        out
+    }
+}
+
+//Label values are double-quoted with \\, \" and \n escapes
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub fn listen(listener: TcpListener, metrics: Arc<Metrics>, limits: Arc<Limits>, rings: Arc<HashMap<String, Arc<Ring>>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let metrics = metrics.clone();
+                let limits = limits.clone();
+                let rings = rings.clone();
+                thread::spawn(move || answer(stream, &metrics, &limits, &rings));
+            }
+            Err(e) => eprintln!("Error accepting metrics request: {}", e),
+        }
+    }
+}
+
+//Answers one request and closes the connection
+fn answer(mut stream: TcpStream, metrics: &Metrics, limits: &Limits, rings: &HashMap<String, Arc<Ring>>) {
+    let Some(request) = read_request(&mut stream) else { return };
+    let mut words = request.split(' ');
+    let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+    let path = path.split('?').next().unwrap_or("");
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", metrics.render(limits)),
+        ("GET", "/health") => match metrics.failing() {
+            None => ("200 OK", "ok\n".to_string()),
+            Some(error) => ("503 Service Unavailable", format!("error: {error}\n")),
+        },
+        ("GET", ring) if ring.starts_with("/ring/") => match rings.get(&ring["/ring/".len()..]) {
+            Some(ring) => ("200 OK", ring.contents()),
+            None => ("404 Not Found", "no such ring sink\n".to_string()),
+        },
+        (_, "/metrics" | "/health") => ("405 Method Not Allowed", "only GET is supported\n".to_string()),
+        _ => ("404 Not Found", "try /metrics or /health\n".to_string()),
+    };
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let _ = stream.write_all(head.as_bytes()).and_then(|()| stream.write_all(body.as_bytes()));
+}
+
+//The request line, once the headers after it have been read
+fn read_request(stream: &mut TcpStream) -> Option<String> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT)).ok()?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).ok().filter(|&n| n > 0)?;
+        request.extend_from_slice(&buffer[..n]);
+        if request.len() > MAX_REQUEST {
+            return None;
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    request.lines().next().map(str::to_string)
+}
@@ -0,0 +1,197 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use send_log::protocol::Record;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/* output - turns received records into lines of the log file
+ *
+ * The encoder is picked with output_format in config.toml:
+ *
+ *  plain   (default) 2024-01-01T12:00:00.000Z INFO  sender: message key=value
+ *  json    one JSON object per line
+ *  logfmt  time=... received=... level=info sender=... msg="..." key=value
+ *
+ * json and logfmt also carry the time the server received the record and
+ * the address of the client that sent it. Records from authenticated
+ * clients carry the application name they authenticated as, in every
+ * format. Every encoder escapes newlines, so one record is always
+ * exactly one line.
+ * */
+
+const TIMESTAMP_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z");
+
+//A record together with what the server knows about its arrival
+pub struct Entry {
+    pub record: Record,
+    //Milliseconds since the Unix epoch when log_component received it
+    pub received: u64,
+    //Client address, or None for records log_component produced itself
+    pub peer: Option<String>,
+    //Application the client authenticated as, if authentication is on
+    pub identity: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Plain,
+    Json,
+    Logfmt,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "plain" => Ok(OutputFormat::Plain),
+            "json" => Ok(OutputFormat::Json),
+            "logfmt" => Ok(OutputFormat::Logfmt),
+            _ => Err(format!("unknown output_format \"{s}\", expected plain, json or logfmt")),
+        }
+    }
+}
+
+impl OutputFormat {
+    //Encodes one entry as a complete line, trailing newline included
+    pub fn encode(&self, entry: &Entry) -> String {
+        let mut line = match self {
+            OutputFormat::Plain => plain(entry),
+            OutputFormat::Json => json(entry),
+            OutputFormat::Logfmt => logfmt(entry),
+        };
+        line.push('\n');
+        line
+    }
+}
+
+pub fn format_timestamp(millis: u64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000)
+        .ok()
+        .and_then(|t| t.format(TIMESTAMP_FORMAT).ok())
+        .unwrap_or_else(|| millis.to_string())
+}
+
+//Messages commonly end in a newline that carries no meaning on its own line
+fn message(record: &Record) -> &str {
+    record.payload.strip_suffix('\n').unwrap_or(&record.payload)
+}
+
+//Escapes line breaks so that text from a client cannot start a new line
+fn one_line(text: &str) -> String {
+    text.replace('\r', "\\r").replace('\n', "\\n")
+}
+
+fn plain(entry: &Entry) -> String {
+    let record = &entry.record;
+    let mut line = format!(
+        "{} {:<5} {}: {}",
+        format_timestamp(record.timestamp),
+        record.severity,
+        one_line(&record.sender),
+        one_line(message(record))
+    );
+    if let Some(identity) = &entry.identity {
+        let _ = write!(line, " identity={}", quote_if_needed(identity));
+    }
+    for (key, value) in &record.fields {
+        let _ = write!(line, " {}={}", one_line(key), quote_if_needed(value));
+    }
+    line
+}
+
+fn json(entry: &Entry) -> String {
+    let record = &entry.record;
+    let mut line = format!(
+        "{{\"time\":{},\"received\":{},\"severity\":{},\"sender\":{},\"peer\":{},\"identity\":{},\"message\":{},\"fields\":{{",
+        json_string(&format_timestamp(record.timestamp)),
+        json_string(&format_timestamp(entry.received)),
+        json_string(record.severity.as_str()),
+        json_string(&record.sender),
+        entry.peer.as_deref().map_or("null".to_string(), json_string),
+        entry.identity.as_deref().map_or("null".to_string(), json_string),
+        json_string(message(record)),
+    );
+    for (i, (key, value)) in record.fields.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        let _ = write!(line, "{}:{}", json_string(key), json_string(value));
+    }
+    line.push_str("}}");
+    line
+}
+
+fn logfmt(entry: &Entry) -> String {
+    let record = &entry.record;
+    let mut line = format!(
+        "time={} received={} level={} sender={}",
+        format_timestamp(record.timestamp),
+        format_timestamp(entry.received),
+        record.severity.as_str().to_ascii_lowercase(),
+        quote_if_needed(&record.sender),
+    );
+    if let Some(peer) = &entry.peer {
+        let _ = write!(line, " peer={}", quote_if_needed(peer));
+    }
+    if let Some(identity) = &entry.identity {
+        let _ = write!(line, " identity={}", quote_if_needed(identity));
+    }
+    let _ = write!(line, " msg={}", quote_if_needed(message(record)));
+    for (key, value) in &record.fields {
+        let _ = write!(line, " {}={}", logfmt_key(key), quote_if_needed(value));
+    }
+    line
+}
+
+//logfmt keys cannot contain spaces, quotes or '='
+fn logfmt_key(key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| if c.is_alphanumeric() || "_-./".contains(c) { c } else { '_' })
+        .collect();
+    if key.is_empty() { "_".to_string() } else { key }
+}
+
+//Wraps a value in quotes, escaping as needed, when it would otherwise be
+//ambiguous as part of a key=value pair
+pub fn quote_if_needed(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value.chars().any(|c| c.is_whitespace() || c == '"' || c == '=' || c.is_control());
+    if !needs_quotes {
+        return value.to_string();
+    }
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    escape_into(&mut out, value);
+    out.push('"');
+    out
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    escape_into(&mut out, value);
+    out.push('"');
+    out
+}
+
+//Backslash escapes valid in both JSON strings and quoted logfmt values
+fn escape_into(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
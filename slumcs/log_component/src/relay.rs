@@ -0,0 +1,75 @@
+use config::{Config, Map, Value};
+use send_log::{log_forward, try_log_connect_with, Log};
+
+use crate::output::Entry;
+
+/* relay - forwards what this log_component receives to another one
+ *
+ * With a [relay] table in config.toml, every record received from a
+ * client (over any listener, syslog included) is also sent upstream
+ * through a send_log client once it has been written locally. The table
+ * takes the same keys as a send_log client's config.toml:
+ *
+ *  [relay]
+ *  log_ip = "10.0.0.1"
+ *  log_port = 23456
+ *  buffer_file = "relay.spool"
+ *
+ * so TLS, authentication and the offline buffer work as they do for any
+ * other client. Unlike an application, the relay starts even while the
+ * upstream is down (connect_required defaults to false) and sends from a
+ * background thread (log_async defaults to true), so the local log never
+ * waits on it. Records the upstream cannot take yet are held in the
+ * offline buffer and sent in order once it is back; with buffer_file set
+ * they also survive a restart of the relay.
+ *
+ * Forwarded records keep their sender, timestamp, severity and fields.
+ * Where a record came from is added as origin_peer and origin_identity
+ * fields, unless a relay further down the chain already added them. The
+ * server's own records about its clients stay local.
+ *
+ * A forward sink (see sink.rs) is a Relay too, configured by its own
+ * table, which only gets the records routed to it.
+ * */
+
+pub struct Relay {
+    log: Log,
+}
+
+impl Relay {
+    //None without a [relay] table
+    pub fn from_config(settings: &Config) -> Result<Option<Relay>, String> {
+        let Ok(table) = settings.get_table("relay") else {
+            return Ok(None);
+        };
+        Relay::from_table(table, "[relay]").map(Some)
+    }
+
+    //A relay configured like a send_log client by `table`; `name` says
+    //where the table is, for errors
+    pub fn from_table(table: Map<String, Value>, name: &str) -> Result<Relay, String> {
+        let mut builder = Config::builder()
+            .set_default("log_async", true)
+            .and_then(|b| b.set_default("connect_required", false))
+            .map_err(|e| e.to_string())?;
+        for (key, value) in table {
+            builder = builder.set_override(key, value).map_err(|e| e.to_string())?;
+        }
+        let upstream = builder.build().map_err(|e| format!("invalid {name}: {e}"))?;
+        let log = try_log_connect_with(&upstream).map_err(|e| format!("cannot relay {name}: {e}"))?;
+        Ok(Relay { log })
+    }
+
+    pub fn forward(&mut self, entry: &Entry) {
+        let mut record = entry.record.clone();
+        if let (None, Some(peer)) = (record.field("origin_peer"), &entry.peer) {
+            record.fields.push(("origin_peer".to_string(), peer.clone()));
+            if let Some(identity) = &entry.identity {
+                record.fields.push(("origin_identity".to_string(), identity.clone()));
+            }
+        }
+        if let Err(e) = log_forward(&mut self.log, record) {
+            eprintln!("Error relaying record: {}", e);
+        }
+    }
+}
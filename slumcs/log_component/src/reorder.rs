@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use send_log::clock::Clock;
+
+use crate::output::Entry;
+use crate::writer::Job;
+
+/* reorder - writes records in causal order rather than arrival order
+ *
+ * Clients with logical_clock set (see send_log's clock.rs) stamp every
+ * record with a Lamport counter. With reorder_window_ms set in
+ * config.toml, records pass through here on their way to the writer and
+ * are held for up to that long. Whenever the oldest held record has
+ * waited the full window, the held record with the lowest counter is
+ * written, so records arriving within the window of each other come out
+ * in an order consistent with causality. Equal counters keep their
+ * arrival order.
+ *
+ * A record that arrives later than the window allows is still written,
+ * just out of order. Records without a clock (from other clients,
+ * syslog, or log_component itself) are slotted in at the highest counter
+ * seen so far, which keeps them close to where they arrived.
+ *
+ * A sync from a client releases everything held first, so an ack still
+ * means every record before it is on disk. So does a reload or a stop,
+ * which are meant for what came before them too.
+ * */
+
+pub struct Reorder {
+    window: Duration,
+    //Held entries by (Lamport counter, arrival number)
+    held: BTreeMap<(u64, u64), Entry>,
+    //When each held entry arrived, by arrival number
+    arrived: BTreeMap<u64, Instant>,
+    next: u64,
+    highest: u64,
+}
+
+impl Reorder {
+    pub fn new(window: Duration) -> Reorder {
+        Reorder { window, held: BTreeMap::new(), arrived: BTreeMap::new(), next: 0, highest: 0 }
+    }
+
+    //Passes `jobs` on to `writer` in order, until either side goes away
+    pub fn run(mut self, jobs: Receiver<Job>, writer: Sender<Job>) {
+        loop {
+            let job = match self.arrived.first_key_value() {
+                Some((_, &at)) => jobs.recv_timeout((at + self.window).saturating_duration_since(Instant::now())),
+                None => jobs.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let released = match job {
+                Ok(Job::Write(entry)) => {
+                    self.hold(entry);
+                    self.release(Instant::now())
+                }
+                Ok(job) => {
+                    let mut released = self.drain();
+                    released.push(job);
+                    released
+                }
+                Err(RecvTimeoutError::Timeout) => self.release(Instant::now()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    for job in self.drain() {
+                        let _ = writer.send(job);
+                    }
+                    return;
+                }
+            };
+            for job in released {
+                if writer.send(job).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn hold(&mut self, entry: Entry) {
+        let lamport = match Clock::of(&entry.record) {
+            Some(clock) => clock.lamport,
+            None => self.highest,
+        };
+        self.highest = self.highest.max(lamport);
+        self.held.insert((lamport, self.next), entry);
+        self.arrived.insert(self.next, Instant::now());
+        self.next += 1;
+    }
+
+    //Takes out the lowest counters for as long as some entry has waited
+    //out the window
+    fn release(&mut self, now: Instant) -> Vec<Job> {
+        let mut released = Vec::new();
+        while self.arrived.first_key_value().is_some_and(|(_, &at)| now >= at + self.window) {
+            let Some(((_, number), entry)) = self.held.pop_first() else { break };
+            self.arrived.remove(&number);
+            released.push(Job::Write(entry));
+        }
+        released
+    }
+
+    fn drain(&mut self) -> Vec<Job> {
+        self.arrived.clear();
+        std::mem::take(&mut self.held).into_values().map(Job::Write).collect()
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use send_log::protocol::now_millis;
+use send_log::rotation;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/* rotate - the log file, rotated by size and/or time
+ *
+ * When the file would grow past rotate_max_bytes, or the hour/day it was
+ * started in has passed, it is renamed to <log_file>.<UTC timestamp> and
+ * a fresh file is opened in its place. Rotated files are optionally
+ * gzipped and only the newest rotate_keep are kept; both happen one file
+ * at a time on a background thread, so pruning never races a compression.
+ * The naming is shared with log_query through send_log's rotation.rs.
+ *
+ * reopen() closes and reopens log_file without rotating. Both rotating
+ * and reopening fsync the old file before letting go of it. After an
+ * external tool such as logrotate has moved the file away, SIGHUP makes
+ * the writer open it afresh (see writer.rs).
+ * */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Interval {
+    //Identifies the hour or day a timestamp falls in
+    fn period(&self, millis: u64) -> u64 {
+        match self {
+            Interval::Never => 0,
+            Interval::Hourly => millis / 3_600_000,
+            Interval::Daily => millis / 86_400_000,
+        }
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Interval, String> {
+        match s {
+            "never" => Ok(Interval::Never),
+            "hourly" => Ok(Interval::Hourly),
+            "daily" => Ok(Interval::Daily),
+            _ => Err(format!("unknown rotate_interval \"{s}\", expected never, hourly or daily")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RotatePolicy {
+    //0 disables size-based rotation
+    pub max_bytes: u64,
+    pub interval: Interval,
+    pub compress: bool,
+    //Number of rotated files to keep; 0 keeps them all
+    pub keep: usize,
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    period: u64,
+    policy: RotatePolicy,
+    //Rotated files waiting to be compressed and pruned
+    archive: Sender<PathBuf>,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, policy: RotatePolicy) -> io::Result<RotatingFile> {
+        let (file, size, modified) = open_append(path)?;
+        let period = policy.interval.period(modified);
+        let (archive, rotated) = mpsc::channel::<PathBuf>();
+        let base = path.to_path_buf();
+        let archive_policy = policy.clone();
+        //Compressing a large file takes a while; keep it off the write path
+        thread::spawn(move || {
+            for file in rotated {
+                archive_one(&base, &file, &archive_policy);
+            }
+        });
+        Ok(RotatingFile { path: path.to_path_buf(), file, size, period, policy, archive })
+    }
+
+    //Appends one complete line, rotating first if it is due
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let now = now_millis();
+        let too_big = self.policy.max_bytes > 0 && self.size > 0 && self.size + line.len() as u64 > self.policy.max_bytes;
+        let new_period = self.policy.interval.period(now) != self.period;
+        if too_big || new_period {
+            self.rotate()?;
+            self.period = self.policy.interval.period(now);
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    //Makes everything written so far durable
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    pub fn reopen(&mut self) -> io::Result<()> {
+        //The old file may already have been moved away; what was written
+        //to it still has to reach the disk
+        self.file.sync_data()?;
+        let (file, size, modified) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.period = self.policy.interval.period(modified);
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = self.rotated_name();
+        fs::rename(&self.path, &rotated)?;
+        self.reopen()?;
+        let _ = self.archive.send(rotated);
+        Ok(())
+    }
+
+    fn rotated_name(&self) -> PathBuf {
+        let stamp = OffsetDateTime::now_utc()
+            .format(format_description!("[year][month][day]T[hour][minute][second][subsecond digits:3]Z"))
+            .unwrap_or_else(|_| now_millis().to_string());
+        rotation::rotated_name(&self.path, &stamp)
+    }
+}
+
+fn archive_one(base: &Path, rotated: &Path, policy: &RotatePolicy) {
+    if policy.compress {
+        if let Err(e) = gzip(rotated) {
+            eprintln!("Error compressing {}: {}", rotated.display(), e);
+        }
+    }
+    if policy.keep > 0 {
+        if let Err(e) = prune(base, policy.keep) {
+            eprintln!("Error removing old log files: {}", e);
+        }
+    }
+}
+
+//Returns the file, its current length and its last modification time
+fn open_append(path: &Path) -> io::Result<(File, u64, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let meta = file.metadata()?;
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(now_millis, |d| d.as_millis() as u64);
+    Ok((file, meta.len(), modified))
+}
+
+fn gzip(path: &Path) -> io::Result<()> {
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let mut encoder = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+fn prune(base: &Path, keep: usize) -> io::Result<()> {
+    let files = rotation::rotated_files(base)?;
+    if files.len() > keep {
+        for old in &files[..files.len() - keep] {
+            fs::remove_file(old)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,287 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use config::{Config, Map, Value};
+use send_log::protocol::Severity;
+
+use crate::output::{Entry, OutputFormat};
+use crate::relay::Relay;
+use crate::rotate::RotatingFile;
+use crate::writer::FileSettings;
+
+/* sink - where records go besides log_file, and which ones
+ *
+ * Without routing every record is written to log_file. [sinks.<name>]
+ * tables add other destinations, each of a type:
+ *
+ *  file     path, plus output_format and the rotate_* keys as for log_file
+ *  stdout   output_format
+ *  forward  the keys of a send_log client's config.toml, as for [relay]
+ *  ring     the newest max_bytes (1048576) of lines, kept in memory, in
+ *           output_format; served on metrics_port at /ring/<name>
+ *
+ * output_format is plain unless set. [[routes]] then pick the records
+ * each sink gets:
+ *
+ *  [[routes]]
+ *  sender = "web"               only records from this sender; "web*"
+ *                               for every sender starting with "web",
+ *                               such as the default "web[4242]"
+ *  app = "billing"              only records from clients authenticated
+ *                               as this application (see auth.rs)
+ *  level = "warn"               only records of this severity or worse
+ *  fields = { region = "eu" }   only records with these field values
+ *  sinks = ["web", "log_file"]
+ *
+ * Every match key is optional, so a route without any takes everything.
+ * A record goes to the sinks of every route it matches, each sink once,
+ * and to log_file only if a matching route names it or no route matches
+ * at all.
+ *
+ * Routing only decides where records are stored: subscribers (see
+ * subscribe.rs) see every record, and [relay] gets every client record.
+ * File sinks are fsynced whenever log_file is. Sinks and routes are read
+ * at startup only; SIGHUP leaves them as they are.
+ * */
+
+//The sink name routes use for log_file itself
+pub const LOG_FILE: &str = "log_file";
+
+const RING_BYTES: usize = 1024 * 1024;
+
+const ROUTE_KEYS: [&str; 5] = ["sender", "app", "level", "fields", "sinks"];
+
+//The newest lines written to a ring sink, up to max_bytes of them
+pub struct Ring {
+    max_bytes: usize,
+    //The lines, oldest first, and their total length
+    lines: Mutex<(VecDeque<String>, usize)>,
+}
+
+impl Ring {
+    fn push(&self, line: String) {
+        let mut held = self.lines.lock().unwrap();
+        held.1 += line.len();
+        held.0.push_back(line);
+        while held.1 > self.max_bytes {
+            let Some(oldest) = held.0.pop_front() else { break };
+            held.1 -= oldest.len();
+        }
+    }
+
+    //Everything held, oldest first
+    pub fn contents(&self) -> String {
+        self.lines.lock().unwrap().0.iter().map(String::as_str).collect()
+    }
+}
+
+enum Kind {
+    File(RotatingFile),
+    Stdout,
+    Forward(Relay),
+    Ring(Arc<Ring>),
+}
+
+struct Sink {
+    name: String,
+    format: OutputFormat,
+    kind: Kind,
+}
+
+impl Sink {
+    fn from_table(name: String, mut table: Map<String, Value>) -> Result<Sink, String> {
+        let what = format!("[sinks.{name}]");
+        let kind = match table.remove("type").map(Value::into_string) {
+            Some(Ok(kind)) => kind,
+            Some(Err(e)) => return Err(format!("{what} type: {e}")),
+            None => return Err(format!("{what} has no type")),
+        };
+        let allowed: &[&str] = match kind.as_str() {
+            "file" => &["path", "output_format", "rotate_max_bytes", "rotate_interval", "rotate_compress", "rotate_keep"],
+            "stdout" => &["output_format"],
+            "ring" => &["max_bytes", "output_format"],
+            "forward" => {
+                let relay = Relay::from_table(table, &what)?;
+                return Ok(Sink { name, format: OutputFormat::Plain, kind: Kind::Forward(relay) });
+            }
+            _ => return Err(format!("{what} has unknown type \"{kind}\", expected file, stdout, forward or ring")),
+        };
+        if let Some(key) = table.keys().find(|k| !allowed.contains(&k.as_str())) {
+            return Err(format!("unknown key {key} in {what}"));
+        }
+        let mut builder = Config::builder();
+        for (key, value) in table {
+            builder = builder.set_override(key, value).map_err(|e| e.to_string())?;
+        }
+        let settings = builder.build().map_err(|e| format!("invalid {what}: {e}"))?;
+        if kind == "file" {
+            let path = settings.get_string("path").map_err(|_| format!("{what} has no path"))?;
+            let file = FileSettings::from_config(&settings, path).map_err(|e| format!("{what}: {e}"))?;
+            let opened = RotatingFile::open(&file.path, file.rotate)
+                .map_err(|e| format!("cannot open {what} path {}: {e}", file.path.display()))?;
+            return Ok(Sink { name, format: file.format, kind: Kind::File(opened) });
+        }
+        let format = match settings.get_string("output_format") {
+            Ok(x) => x.parse().map_err(|e| format!("{what}: {e}"))?,
+            Err(_) => OutputFormat::Plain,
+        };
+        let kind = match kind.as_str() {
+            "ring" => {
+                let max_bytes = settings.get_int("max_bytes").map_or(RING_BYTES, |x| x.max(1) as usize);
+                Kind::Ring(Arc::new(Ring { max_bytes, lines: Mutex::new((VecDeque::new(), 0)) }))
+            }
+            _ => Kind::Stdout,
+        };
+        Ok(Sink { name, format, kind })
+    }
+}
+
+struct Route {
+    //Matches a sender exactly, or by prefix when it ends in *
+    sender: Option<String>,
+    app: Option<String>,
+    level: Option<Severity>,
+    fields: Vec<(String, String)>,
+    log_file: bool,
+    //Indexes into Router::sinks
+    sinks: Vec<usize>,
+}
+
+impl Route {
+    fn matches(&self, entry: &Entry) -> bool {
+        let record = &entry.record;
+        self.sender.as_ref().is_none_or(|s| match s.strip_suffix('*') {
+            Some(prefix) => record.sender.starts_with(prefix),
+            None => *s == record.sender,
+        }) && self.app.as_ref().is_none_or(|app| entry.identity.as_ref() == Some(app))
+            && self.level.is_none_or(|l| record.severity >= l)
+            && self.fields.iter().all(|(key, value)| record.field(key) == Some(value.as_str()))
+    }
+
+    fn from_table(n: usize, table: Map<String, Value>, sinks: &[Sink]) -> Result<Route, String> {
+        let what = format!("routes[{n}]");
+        if let Some(key) = table.keys().find(|k| !ROUTE_KEYS.contains(&k.as_str())) {
+            return Err(format!("unknown key {key} in {what}"));
+        }
+        let string = |value: Value, key: &str| value.into_string().map_err(|e| format!("{what} {key}: {e}"));
+        let mut route = Route { sender: None, app: None, level: None, fields: Vec::new(), log_file: false, sinks: Vec::new() };
+        for (key, value) in table {
+            match key.as_str() {
+                "sender" => route.sender = Some(string(value, &key)?),
+                "app" => route.app = Some(string(value, &key)?),
+                "level" => route.level = Some(string(value, &key)?.parse().map_err(|e| format!("{what} level: {e}"))?),
+                "fields" => {
+                    let fields = value.into_table().map_err(|e| format!("{what} fields: {e}"))?;
+                    for (field, value) in fields {
+                        let value = string(value, &format!("fields.{field}"))?;
+                        route.fields.push((field, value));
+                    }
+                }
+                _ => {
+                    for name in value.into_array().map_err(|e| format!("{what} sinks: {e}"))? {
+                        let name = string(name, "sinks")?;
+                        match sinks.iter().position(|s| s.name == name) {
+                            Some(i) => route.sinks.push(i),
+                            None if name == LOG_FILE => route.log_file = true,
+                            None => return Err(format!("{what} names unknown sink \"{name}\"")),
+                        }
+                    }
+                }
+            }
+        }
+        if !route.log_file && route.sinks.is_empty() {
+            return Err(format!("{what} has no sinks"));
+        }
+        Ok(route)
+    }
+}
+
+//The sinks and the routes to them
+#[derive(Default)]
+pub struct Router {
+    sinks: Vec<Sink>,
+    routes: Vec<Route>,
+}
+
+impl Router {
+    //Without [sinks] or [[routes]] everything goes to log_file
+    pub fn from_config(settings: &Config) -> Result<Router, String> {
+        let mut router = Router::default();
+        let mut sinks: Vec<_> = settings.get_table("sinks").unwrap_or_default().into_iter().collect();
+        sinks.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, table) in sinks {
+            if name == LOG_FILE {
+                return Err(format!("[sinks.{LOG_FILE}] is taken by log_file itself"));
+            }
+            let table = table.into_table().map_err(|e| format!("[sinks.{name}]: {e}"))?;
+            router.sinks.push(Sink::from_table(name, table)?);
+        }
+        for (n, route) in settings.get_array("routes").unwrap_or_default().into_iter().enumerate() {
+            let table = route.into_table().map_err(|e| format!("routes[{n}]: {e}"))?;
+            let route = Route::from_table(n, table, &router.sinks)?;
+            router.routes.push(route);
+        }
+        Ok(router)
+    }
+
+    //The ring sinks by name, for metrics.rs to serve
+    pub fn rings(&self) -> HashMap<String, Arc<Ring>> {
+        let rings = self.sinks.iter().filter_map(|sink| match &sink.kind {
+            Kind::Ring(ring) => Some((sink.name.clone(), ring.clone())),
+            _ => None,
+        });
+        rings.collect()
+    }
+
+    //Whether `entry` goes to log_file, and which other sinks it goes to
+    pub fn route(&self, entry: &Entry) -> (bool, Vec<usize>) {
+        let mut matched = false;
+        let mut log_file = false;
+        let mut sinks = Vec::new();
+        for route in self.routes.iter().filter(|r| r.matches(entry)) {
+            matched = true;
+            log_file |= route.log_file;
+            for &sink in &route.sinks {
+                if !sinks.contains(&sink) {
+                    sinks.push(sink);
+                }
+            }
+        }
+        (log_file || !matched, sinks)
+    }
+
+    pub fn write(&mut self, sink: usize, entry: &Entry) -> Result<(), String> {
+        let sink = &mut self.sinks[sink];
+        let line = sink.format.encode(entry);
+        let written = match &mut sink.kind {
+            Kind::File(file) => file.write_line(line.as_bytes()),
+            Kind::Stdout => io::stdout().write_all(line.as_bytes()),
+            Kind::Forward(relay) => {
+                relay.forward(entry);
+                Ok(())
+            }
+            Kind::Ring(ring) => {
+                ring.push(line);
+                Ok(())
+            }
+        };
+        written.map_err(|e| format!("writing sink {}: {e}", sink.name))
+    }
+
+    //Makes what the file sinks hold durable, reporting the first error
+    pub fn sync(&mut self) -> Result<(), String> {
+        let mut result = Ok(());
+        for sink in &mut self.sinks {
+            let synced = match &mut sink.kind {
+                Kind::File(file) => file.sync(),
+                Kind::Stdout => io::stdout().flush(),
+                _ => Ok(()),
+            };
+            if let (Err(e), Ok(())) = (synced, &result) {
+                result = Err(format!("syncing sink {}: {e}", sink.name));
+            }
+        }
+        result
+    }
+}
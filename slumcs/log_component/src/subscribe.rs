@@ -0,0 +1,226 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use send_log::protocol::Severity;
+
+use crate::connection::server_entry;
+use crate::output::Entry;
+use crate::writer::Job;
+
+/* subscribe - the live stream of written lines, for log_query tail
+ *
+ * With subscribe_port set, log_component accepts connections on
+ * subscribe_ip (default log_ip):subscribe_port. A subscriber starts by
+ * sending one line, its filter, and is answered with a line of its own:
+ *
+ *   subscriber  sender=web level=warn contains="slow query"
+ *   server      ok
+ *           or  error: unknown filter key "x"
+ *
+ * Every key is optional and an empty line subscribes to everything:
+ *
+ *   sender    only records from this sender
+ *   level     only records of this severity or worse
+ *   contains  only records whose message contains this text
+ *
+ * Values containing spaces or quotes are double-quoted, with \" and \\
+ * escapes. After "ok" the subscriber is sent every matching line written
+ * to log_file, exactly as written, so it sees whichever output_format the
+ * file uses. It sends nothing more; closing the connection unsubscribes.
+ *
+ * Filters are applied by the writer, so a subscriber is never sent, nor
+ * queues up, what it did not ask for. The writer never waits on a
+ * subscriber: each has a queue of QUEUE_LINES lines, and one that falls
+ * that far behind is disconnected rather than allowed to hold up the log.
+ *
+ * Subscribers connect over plain TCP without credentials, so when clients
+ * use TLS or authenticate, log_component refuses to offer the port beyond
+ * a loopback subscribe_ip unless subscribe_unauthenticated = true.
+ * */
+
+const QUEUE_LINES: usize = 1024;
+
+//How long a new subscriber has to send its filter
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+//Filter lines are a few conditions; anything longer is refused
+const MAX_REQUEST: usize = 4096;
+
+#[derive(Default)]
+struct Filter {
+    sender: Option<String>,
+    level: Option<Severity>,
+    contains: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        let record = &entry.record;
+        self.sender.as_ref().is_none_or(|s| *s == record.sender)
+            && self.level.is_none_or(|l| record.severity >= l)
+            && self.contains.as_ref().is_none_or(|c| record.payload.contains(c.as_str()))
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(request: &str) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        for (key, value) in request_pairs(request)? {
+            match key.as_str() {
+                "sender" => filter.sender = Some(value),
+                "level" => filter.level = Some(value.parse()?),
+                "contains" => filter.contains = Some(value),
+                _ => return Err(format!("unknown filter key \"{key}\"")),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+//Splits `key=value key="quoted value"` into pairs
+fn request_pairs(request: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut chars = request.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() || key.contains(' ') {
+            return Err(format!("expected key=value, found \"{}\"", key.trim()));
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(c) => value.push(c),
+                    None => return Err(format!("unterminated quote in the value of \"{key}\"")),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ' ') {
+                value.push(c);
+            }
+        }
+        pairs.push((key, value));
+        while chars.next_if_eq(&' ').is_some() {}
+    }
+    Ok(pairs)
+}
+
+struct Subscriber {
+    filter: Filter,
+    lines: SyncSender<Arc<str>>,
+}
+
+//Everyone currently subscribed, shared by the accept loop and the writer
+#[derive(Default)]
+pub struct Subscribers {
+    list: Mutex<Vec<Subscriber>>,
+}
+
+impl Subscribers {
+    //Hands a written line to every subscriber whose filter it passes,
+    //forgetting those that have gone away or fallen behind
+    pub fn publish(&self, entry: &Entry, line: &str) {
+        let mut list = self.list.lock().unwrap();
+        let mut shared: Option<Arc<str>> = None;
+        list.retain(|s| {
+            !s.filter.matches(entry) || s.lines.try_send(shared.get_or_insert_with(|| Arc::from(line)).clone()).is_ok()
+        });
+    }
+
+    fn add(&self, filter: Filter) -> Receiver<Arc<str>> {
+        let (lines, receiver) = mpsc::sync_channel(QUEUE_LINES);
+        self.list.lock().unwrap().push(Subscriber { filter, lines });
+        receiver
+    }
+}
+
+pub fn listen(listener: TcpListener, subscribers: Arc<Subscribers>, entries: Sender<Job>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let subscribers = subscribers.clone();
+                let entries = entries.clone();
+                thread::spawn(move || subscribe(stream, subscribers, entries));
+            }
+            Err(e) => eprintln!("Error accepting subscriber: {}", e),
+        }
+    }
+}
+
+//Reads the subscriber's filter, then leaves the writing to a feed thread
+//while this one waits for the subscriber to hang up
+fn subscribe(stream: TcpStream, subscribers: Arc<Subscribers>, entries: Sender<Job>) {
+    let peer = stream.peer_addr().map_or_else(|_| "unknown".to_string(), |a| a.to_string());
+    let (Ok(mut writer), Ok(())) = (stream.try_clone(), stream.set_read_timeout(Some(REQUEST_TIMEOUT))) else {
+        return;
+    };
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    let filter = match reader.by_ref().take(MAX_REQUEST as u64 + 1).read_line(&mut request) {
+        Ok(0) => Err("closed before sending a filter".to_string()),
+        Ok(n) if n > MAX_REQUEST && !request.ends_with('\n') => Err(format!("filter longer than {MAX_REQUEST} bytes")),
+        Ok(_) => request.parse::<Filter>(),
+        Err(e) => Err(format!("read error: {e}")),
+    };
+    let filter = match filter {
+        Ok(filter) => filter,
+        Err(reason) => {
+            let _ = writer.write_all(format!("error: {reason}\n").as_bytes());
+            let fields = [("peer", peer.as_str()), ("reason", reason.as_str())];
+            let _ = entries.send(server_entry(Severity::Warn, "bad subscription request", &fields).into());
+            return;
+        }
+    };
+    if writer.write_all(b"ok\n").is_err() {
+        return;
+    }
+
+    let request = request.trim();
+    let mut fields = vec![("peer", peer.as_str())];
+    if !request.is_empty() {
+        fields.push(("filter", request));
+    }
+    let _ = entries.send(server_entry(Severity::Info, "subscriber connected", &fields).into());
+    let lines = subscribers.add(filter);
+    let feed_peer = peer.clone();
+    thread::spawn(move || feed(writer, feed_peer, lines, entries));
+
+    //A subscriber sends nothing more, so the first write after it hangs up
+    //would still succeed. Waiting for its end of the stream and shutting
+    //the socket down makes that write fail instead.
+    let mut stream = reader.into_inner();
+    let _ = stream.set_read_timeout(None);
+    let mut buffer = [0; 256];
+    while matches!(stream.read(&mut buffer), Ok(n) if n > 0) {}
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+//Writes queued lines to one subscriber until either side gives up. The
+//queue only closes when publish dropped the subscriber for falling behind,
+//and shutting the socket down then also ends the wait in subscribe.
+fn feed(mut stream: TcpStream, peer: String, lines: Receiver<Arc<str>>, entries: Sender<Job>) {
+    let mut sent = 0u64;
+    let reason = loop {
+        let line = match lines.recv() {
+            Ok(line) => line,
+            Err(_) => break "fell behind".to_string(),
+        };
+        if let Err(e) = stream.write_all(line.as_bytes()) {
+            break format!("write error: {e}");
+        }
+        sent += 1;
+    };
+    let _ = stream.shutdown(Shutdown::Both);
+    let sent = sent.to_string();
+    let fields = [("peer", peer.as_str()), ("lines", sent.as_str()), ("reason", reason.as_str())];
+    let _ = entries.send(server_entry(Severity::Info, "subscriber disconnected", &fields).into());
+}
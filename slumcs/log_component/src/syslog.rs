@@ -0,0 +1,330 @@
+use std::io::{BufRead, BufReader, Read};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+
+use send_log::protocol::{now_millis, Record, Severity};
+use time::format_description::well_known::Rfc3339;
+use time::{Date, Month, OffsetDateTime, Time};
+
+use crate::connection::server_entry;
+use crate::limit::{Limiter, Limits};
+use crate::listen::{Clients, Served, Socket};
+use crate::output::Entry;
+use crate::writer::Job;
+
+/* syslog - takes standard syslog traffic alongside send_log clients
+ *
+ * With syslog_port set, log_component also listens on that port (on
+ * syslog_ip, default log_ip) for UDP datagrams and TCP connections.
+ * TCP accepts both RFC 6587 framings: octet counting ("57 <34>1 ...")
+ * and one message per line. Syslog carries no credentials, so where
+ * send_log clients must authenticate or present a certificate the
+ * operator has to allow it with syslog_unauthenticated = true.
+ *
+ * RFC 5424 and legacy RFC 3164 (BSD) messages become ordinary records:
+ *
+ *  sender     APP-NAME (or the 3164 tag), else HOSTNAME
+ *  severity   emerg..err -> ERROR, warning -> WARN, notice and
+ *             info -> INFO, debug -> DEBUG
+ *  timestamp  the message's own, else the time it arrived
+ *  fields     facility, syslog_severity, hostname, procid and msgid
+ *             when present, then every structured data parameter as
+ *             <SD-ID>.<name>
+ *
+ * Messages that cannot be parsed are reported as a warning naming the
+ * peer, and skipped. Syslog senders count against the [rate_limit] like
+ * any other client (see limit.rs), and so do those warnings.
+ * */
+
+const MAX_MESSAGE: usize = 64 * 1024;
+
+//Structured data parameters as <SD-ID>.<name> fields
+type Params = Vec<(String, String)>;
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp", "ntp",
+    "security", "console", "solaris-cron", "local0", "local1", "local2", "local3", "local4", "local5", "local6",
+    "local7",
+];
+const SEVERITIES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+pub fn udp(socket: UdpSocket, limits: Arc<Limits>, entries: Sender<Job>) {
+    let mut limiter = Limiter::datagram(&limits);
+    let mut buffer = vec![0; MAX_MESSAGE];
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Error receiving syslog datagram: {}", e);
+                continue;
+            }
+        };
+        if !submit(&buffer[..n], &peer.to_string(), &mut limiter, &entries) {
+            return;
+        }
+    }
+}
+
+pub fn tcp(listener: TcpListener, limits: Arc<Limits>, clients: Arc<Clients>, entries: Sender<Job>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let Some(served) = clients.add(stream.try_clone().map(Socket::Tcp)) else {
+                    continue;
+                };
+                let entries = entries.clone();
+                let limiter = Limiter::stream(&limits);
+                thread::spawn(move || serve(stream, limiter, entries, served));
+            }
+            Err(e) => eprintln!("Error accepting syslog connection: {}", e),
+        }
+    }
+}
+
+//One sender's stream of messages, until it hangs up or breaks framing;
+//it stays registered in Clients until then
+fn serve(stream: TcpStream, mut limiter: Limiter, entries: Sender<Job>, _served: Served) {
+    let peer = stream.peer_addr().map_or_else(|_| "unknown".to_string(), |p| p.to_string());
+    let mut reader = BufReader::new(stream);
+    loop {
+        let message = match next_message(&mut reader) {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(reason) => {
+                let _ = entries.send(server_entry(Severity::Warn, "syslog connection dropped", &[("peer", &peer), ("reason", &reason)]).into());
+                return;
+            }
+        };
+        if !message.is_empty() && !submit(&message, &peer, &mut limiter, &entries) {
+            return;
+        }
+    }
+}
+
+//Reads one message in either RFC 6587 framing. A message starting with a
+//digit is octet-counted; anything else runs to the end of the line. Either
+//way no more than MAX_MESSAGE bytes are buffered.
+fn next_message<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, String> {
+    let first = match reader.fill_buf() {
+        Ok([]) => return Ok(None),
+        Ok(buf) => buf[0],
+        Err(e) => return Err(format!("read error: {e}")),
+    };
+    let mut message = Vec::new();
+    if first.is_ascii_digit() {
+        let mut count = Vec::new();
+        let digits = MAX_MESSAGE.to_string().len() as u64;
+        reader.by_ref().take(digits + 1).read_until(b' ', &mut count).map_err(|e| format!("read error: {e}"))?;
+        let len: usize = std::str::from_utf8(&count)
+            .ok()
+            .and_then(|c| c.trim_end().parse().ok())
+            .filter(|&len| len <= MAX_MESSAGE)
+            .ok_or_else(|| "bad octet count".to_string())?;
+        message.resize(len, 0);
+        reader.read_exact(&mut message).map_err(|e| format!("read error: {e}"))?;
+    } else {
+        reader.by_ref().take(MAX_MESSAGE as u64 + 1).read_until(b'\n', &mut message).map_err(|e| format!("read error: {e}"))?;
+        if message.len() > MAX_MESSAGE && message.last() != Some(&b'\n') {
+            return Err(format!("message longer than {MAX_MESSAGE} bytes"));
+        }
+        while message.last().is_some_and(|&b| b == b'\n' || b == b'\r' || b == 0) {
+            message.pop();
+        }
+    }
+    Ok(Some(message))
+}
+
+//Parses and forwards one message unless it is over the rate limit; false
+//once the writer has gone away
+fn submit(message: &[u8], peer: &str, limiter: &mut Limiter, entries: &Sender<Job>) -> bool {
+    let received = now_millis();
+    let text = String::from_utf8_lossy(message);
+    let entry = match parse(text.trim_end_matches(['\n', '\r', '\0']), received) {
+        Ok(record) => {
+            let entry = Entry { record, received, peer: Some(peer.to_string()), identity: None };
+            if !limiter.admit(&entry) {
+                return true;
+            }
+            entry
+        }
+        Err(reason) => {
+            let warning = server_entry(Severity::Warn, "bad syslog message", &[("peer", peer), ("reason", &reason)]);
+            if !limiter.admit_warning(&warning, peer) {
+                return true;
+            }
+            warning
+        }
+    };
+    entries.send(entry.into()).is_ok()
+}
+
+//Turns one RFC 5424 or RFC 3164 message into a record
+fn parse(message: &str, received: u64) -> Result<Record, String> {
+    let rest = message.strip_prefix('<').ok_or("missing <PRI>")?;
+    let (pri, rest) = rest.split_once('>').ok_or("missing <PRI>")?;
+    let pri: usize = pri.parse().ok().filter(|&p| p < 192).ok_or_else(|| format!("bad PRI \"{pri}\""))?;
+    let (facility, severity) = (pri / 8, pri % 8);
+
+    let mut record = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_5424(rest, received)?,
+        None => parse_3164(rest, received),
+    };
+    record.severity = match severity {
+        0..=3 => Severity::Error,
+        4 => Severity::Warn,
+        5 | 6 => Severity::Info,
+        _ => Severity::Debug,
+    };
+    record.fields.splice(
+        0..0,
+        [
+            ("facility".to_string(), FACILITIES[facility].to_string()),
+            ("syslog_severity".to_string(), SEVERITIES[severity].to_string()),
+        ],
+    );
+    Ok(record)
+}
+
+//TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]
+fn parse_5424(rest: &str, received: u64) -> Result<Record, String> {
+    let mut parts = rest.splitn(6, ' ');
+    let mut header = || parts.next().filter(|p| !p.is_empty()).ok_or("truncated header");
+    let (timestamp, hostname, app, procid, msgid) = (header()?, header()?, header()?, header()?, header()?);
+    let (data, msg) = split_structured_data(parts.next().unwrap_or("-"))?;
+
+    let timestamp = match timestamp {
+        "-" => received,
+        t => OffsetDateTime::parse(t, &Rfc3339).map_err(|e| format!("bad TIMESTAMP \"{t}\": {e}")).map(millis)?,
+    };
+    let nil = |v: &str| if v == "-" { None } else { Some(v.to_string()) };
+    let mut fields = Vec::new();
+    for (key, value) in [("hostname", hostname), ("procid", procid), ("msgid", msgid)] {
+        if let Some(value) = nil(value) {
+            fields.push((key.to_string(), value));
+        }
+    }
+    fields.extend(data);
+
+    let sender = nil(app).or_else(|| nil(hostname)).unwrap_or_else(|| "syslog".to_string());
+    //A UTF-8 MSG may start with a byte order mark
+    let payload = msg.trim_start_matches('\u{feff}').to_string();
+    Ok(Record { sender, timestamp, severity: Severity::Info, payload, fields })
+}
+
+//Splits "[id k="v"][id2 ...] msg" into parameters and the message
+fn split_structured_data(text: &str) -> Result<(Params, &str), String> {
+    if let Some(msg) = text.strip_prefix('-') {
+        return Ok((Vec::new(), msg.strip_prefix(' ').unwrap_or(msg)));
+    }
+    let mut fields = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, '[')) = chars.peek() {
+        chars.next();
+        let mut id = String::new();
+        while let Some((_, c)) = chars.next_if(|&(_, c)| c != ' ' && c != ']') {
+            id.push(c);
+        }
+        loop {
+            match chars.next() {
+                Some((_, ']')) => break,
+                Some((_, ' ')) => {
+                    let mut name = String::new();
+                    while let Some((_, c)) = chars.next_if(|&(_, c)| c != '=') {
+                        name.push(c);
+                    }
+                    if chars.next().map(|(_, c)| c) != Some('=') || chars.next().map(|(_, c)| c) != Some('"') {
+                        return Err(format!("bad structured data parameter in [{id}]"));
+                    }
+                    let mut value = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '\\')) => {
+                                //Only ", \ and ] are escaped; keep other backslashes
+                                match chars.next_if(|&(_, c)| matches!(c, '"' | '\\' | ']')) {
+                                    Some((_, c)) => value.push(c),
+                                    None => value.push('\\'),
+                                }
+                            }
+                            Some((_, '"')) => break,
+                            Some((_, c)) => value.push(c),
+                            None => return Err("unterminated structured data".to_string()),
+                        }
+                    }
+                    fields.push((format!("{id}.{name}"), value));
+                }
+                _ => return Err("unterminated structured data".to_string()),
+            }
+        }
+    }
+    let msg = match chars.next() {
+        Some((i, ' ')) => &text[i + 1..],
+        Some(_) => return Err("structured data must be followed by a space".to_string()),
+        None => "",
+    };
+    Ok((fields, msg))
+}
+
+//Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG. Old senders are loose about
+//this, so whatever does not fit is kept as the message rather than
+//rejected.
+fn parse_3164(rest: &str, received: u64) -> Record {
+    let mut record = Record {
+        sender: "syslog".to_string(),
+        timestamp: received,
+        severity: Severity::Info,
+        payload: rest.to_string(),
+        fields: Vec::new(),
+    };
+    let Some((timestamp, rest)) = rest.get(..15).zip(rest.get(15..)) else { return record };
+    let Some(timestamp) = bsd_timestamp(timestamp, received) else { return record };
+    record.timestamp = timestamp;
+    let rest = rest.trim_start();
+    record.payload = rest.to_string();
+
+    let Some((hostname, rest)) = rest.split_once(' ') else { return record };
+    record.fields.push(("hostname".to_string(), hostname.to_string()));
+    record.sender = hostname.to_string();
+    record.payload = rest.to_string();
+
+    //TAG[PID]: or TAG: in front of the message names the program
+    let tag_len = rest.find(|c: char| !(c.is_alphanumeric() || "-_./".contains(c))).unwrap_or(rest.len());
+    let (tag, after) = rest.split_at(tag_len);
+    let (procid, after) = match after.strip_prefix('[').and_then(|r| r.split_once(']')) {
+        Some((procid, after)) => (Some(procid), after),
+        None => (None, after),
+    };
+    if let Some(msg) = after.strip_prefix(':').filter(|_| tag_len > 0) {
+        record.sender = tag.to_string();
+        if let Some(procid) = procid {
+            record.fields.push(("procid".to_string(), procid.to_string()));
+        }
+        record.payload = msg.trim_start().to_string();
+    }
+    record
+}
+
+//"Oct 11 22:14:15" carries no year or zone: take it as UTC in the year
+//that puts it closest to when it arrived
+fn bsd_timestamp(text: &str, received: u64) -> Option<u64> {
+    let month = MONTHS.iter().position(|m| text.starts_with(m))? as u8 + 1;
+    let day: u8 = text.get(4..6)?.trim_start().parse().ok()?;
+    let hms: Vec<u8> = text.get(7..15)?.split(':').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    let [hour, minute, second] = hms[..] else { return None };
+    let time = Time::from_hms(hour, minute, second).ok()?;
+
+    let now = OffsetDateTime::from_unix_timestamp((received / 1000) as i64).ok()?;
+    let month = Month::try_from(month).ok()?;
+    let candidates = [now.year() - 1, now.year(), now.year() + 1];
+    candidates
+        .iter()
+        .filter_map(|&year| Date::from_calendar_date(year, month, day).ok())
+        .map(|date| millis(date.with_time(time).assume_utc()))
+        .min_by_key(|&t| t.abs_diff(received))
+}
+
+fn millis(t: OffsetDateTime) -> u64 {
+    (t.unix_timestamp_nanos() / 1_000_000).max(0) as u64
+}
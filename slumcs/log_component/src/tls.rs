@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use config::Config;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::ServerConfig;
+use send_log::tls::{load_certs, load_private_key, load_roots};
+
+/* tls - the server side of TLS between send_log and log_component
+ *
+ * With tls = true in config.toml every client must complete a TLS
+ * handshake before its records are read:
+ *
+ *  tls_cert_file       PEM certificate chain the server presents
+ *  tls_key_file        PEM private key for tls_cert_file
+ *  tls_client_ca_file  when set, clients must present a certificate
+ *                      signed by a CA in this PEM file (mutual TLS)
+ *
+ * A client that fails the handshake is disconnected like any other bad
+ * client, with the TLS error as the reason.
+ * */
+
+//Reads the tls_* keys; None when tls is off
+pub fn server_config(settings: &Config) -> Result<Option<Arc<ServerConfig>>, String> {
+    if !settings.get_bool("tls").unwrap_or(false) {
+        return Ok(None);
+    }
+    let get = |key: &str| settings.get_string(key).map_err(|_| format!("tls = true but {key} is not set"));
+
+    let cert_file = get("tls_cert_file")?;
+    let key_file = get("tls_key_file")?;
+    let certs = load_certs(&cert_file).map_err(|e| format!("Error reading tls_cert_file {cert_file}: {e}"))?;
+    let key = load_private_key(&key_file).map_err(|e| format!("Error reading tls_key_file {key_file}: {e}"))?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match settings.get_string("tls_client_ca_file") {
+        Ok(ca_file) => {
+            let roots = load_roots(&ca_file).map_err(|e| format!("Error reading tls_client_ca_file {ca_file}: {e}"))?;
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        Err(_) => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("tls_key_file does not match tls_cert_file: {e}"))?;
+    Ok(Some(Arc::new(config)))
+}
@@ -0,0 +1,267 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use config::Config;
+use send_log::protocol::Severity;
+
+use crate::connection::server_entry;
+use crate::metrics::Metrics;
+use crate::output::{Entry, OutputFormat};
+use crate::relay::Relay;
+use crate::rotate::{Interval, RotatePolicy, RotatingFile};
+use crate::sink::Router;
+use crate::subscribe::Subscribers;
+
+/* writer - the thread that owns the log file
+ *
+ * Every listener hands its work to this one thread over a channel, so
+ * records are written in the order they arrive. Besides records, a
+ * connection can send a sync on behalf of a client that asked for one
+ * (see send_log's log_sync); it is answered once every record queued
+ * before it is on disk.
+ *
+ * How often the file is fsynced is set with fsync in config.toml:
+ *
+ *  never         (default) only when a client asks for a sync
+ *  every_record  after every record
+ *  interval      every fsync_interval_ms (1000) while there are
+ *                unsynced records, answering waiting syncs together
+ *
+ * A write or fsync error fails the next sync answered after it, whichever
+ * client's record it was: the writer cannot tell whose records the lost
+ * bytes belonged to.
+ *
+ * On SIGHUP the writer is handed the log file settings afresh (see
+ * FileSettings) and opens log_file again, so an external logrotate can
+ * move it away and signal the server afterwards, and a changed path,
+ * format, rotation or fsync policy takes effect without a restart. If the
+ * new file cannot be opened, the old one stays in use. On SIGINT or
+ * SIGTERM it is told to stop: it fsyncs, answers every waiting sync,
+ * flushes the relay and closes log_file.
+ *
+ * With [[routes]] in config.toml a record may go to other sinks instead
+ * of, or as well as, log_file (see sink.rs). A sync covers those too.
+ * */
+
+//Work for the writer thread
+pub enum Job {
+    Write(Entry),
+    //Answered with None once everything before it is on disk, or with
+    //the error that may have kept it off the disk
+    Sync(Sender<Option<String>>),
+    //Switches to new settings once everything before it is written
+    Reload(Box<FileSettings>),
+    //Answered like a sync, but once log_file is closed and the writer has
+    //stopped
+    Stop(Sender<Option<String>>),
+}
+
+//What SIGHUP can change about the log file
+pub struct FileSettings {
+    pub path: PathBuf,
+    pub rotate: RotatePolicy,
+    pub format: OutputFormat,
+    pub fsync: FsyncPolicy,
+}
+
+impl FileSettings {
+    //log_file is `path`; the other keys are optional
+    pub fn from_config(settings: &Config, path: String) -> Result<FileSettings, String> {
+        let format: OutputFormat = match settings.get_string("output_format") {
+            Ok(x) => x.parse()?,
+            Err(_) => OutputFormat::Plain,
+        };
+        let rotate = RotatePolicy {
+            max_bytes: settings.get_int("rotate_max_bytes").map_or(0, |x| x.max(0) as u64),
+            interval: match settings.get_string("rotate_interval") {
+                Ok(x) => x.parse::<Interval>()?,
+                Err(_) => Interval::Never,
+            },
+            compress: settings.get_bool("rotate_compress").unwrap_or(false),
+            keep: settings.get_int("rotate_keep").map_or(0, |x| x.max(0) as usize),
+        };
+        let fsync_interval = Duration::from_millis(settings.get_int("fsync_interval_ms").map_or(1000, |x| x.max(1) as u64));
+        let fsync = match settings.get_string("fsync") {
+            Ok(x) => FsyncPolicy::parse(&x, fsync_interval)?,
+            Err(_) => FsyncPolicy::Never,
+        };
+        Ok(FileSettings { path: PathBuf::from(path), rotate, format, fsync })
+    }
+}
+
+impl From<Entry> for Job {
+    fn from(entry: Entry) -> Job {
+        Job::Write(entry)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    Never,
+    EveryRecord,
+    Interval(Duration),
+}
+
+impl FsyncPolicy {
+    //Parses fsync; `interval` is fsync_interval_ms, only used by interval
+    pub fn parse(fsync: &str, interval: Duration) -> Result<FsyncPolicy, String> {
+        match fsync {
+            "never" => Ok(FsyncPolicy::Never),
+            "every_record" => Ok(FsyncPolicy::EveryRecord),
+            "interval" => Ok(FsyncPolicy::Interval(interval)),
+            _ => Err(format!("unknown fsync \"{fsync}\", expected never, every_record or interval")),
+        }
+    }
+}
+
+pub struct Writer {
+    pub log_file: RotatingFile,
+    pub format: OutputFormat,
+    pub fsync: FsyncPolicy,
+    pub subscribers: Arc<Subscribers>,
+    pub relay: Option<Relay>,
+    pub router: Router,
+    pub metrics: Arc<Metrics>,
+}
+
+//What the writer still owes the disk
+struct Pending {
+    //Records written since the last fsync
+    dirty: bool,
+    //First write or fsync error since the last answered sync
+    error: Option<String>,
+    //Syncs waiting for the next interval fsync
+    waiting: Vec<Sender<Option<String>>>,
+    last_sync: Instant,
+}
+
+impl Pending {
+    //Answers the syncs waiting for an interval fsync that just happened
+    fn answer_waiting(&mut self) {
+        if self.waiting.is_empty() {
+            return;
+        }
+        let result = self.error.take();
+        for ack in self.waiting.drain(..) {
+            let _ = ack.send(result.clone());
+        }
+    }
+}
+
+impl Writer {
+    pub fn run(mut self, jobs: Receiver<Job>) {
+        let mut pending = Pending { dirty: false, error: None, waiting: Vec::new(), last_sync: Instant::now() };
+        let stopped = loop {
+            let job = match self.fsync {
+                FsyncPolicy::Interval(every) if pending.dirty || !pending.waiting.is_empty() => {
+                    jobs.recv_timeout((pending.last_sync + every).saturating_duration_since(Instant::now()))
+                }
+                _ => jobs.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match job {
+                Ok(Job::Write(entry)) => {
+                    self.write(&entry, &mut pending);
+                    if self.fsync == FsyncPolicy::EveryRecord {
+                        self.sync(&mut pending);
+                    }
+                }
+                //Nothing new to fsync means nothing to wait for
+                Ok(Job::Sync(ack)) if matches!(self.fsync, FsyncPolicy::Interval(_)) && pending.dirty => {
+                    pending.waiting.push(ack)
+                }
+                Ok(Job::Sync(ack)) => {
+                    self.sync(&mut pending);
+                    let _ = ack.send(pending.error.take());
+                }
+                Ok(Job::Reload(settings)) => self.reload(*settings, &mut pending),
+                Ok(Job::Stop(ack)) => break Some(ack),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break None,
+            }
+            if let FsyncPolicy::Interval(every) = self.fsync {
+                if pending.last_sync.elapsed() >= every && (pending.dirty || !pending.waiting.is_empty()) {
+                    self.sync(&mut pending);
+                    pending.answer_waiting();
+                }
+            }
+        };
+
+        self.sync(&mut pending);
+        pending.answer_waiting();
+        //Dropping the relay waits for it to hand over what it holds
+        drop(self);
+        if let Some(ack) = stopped {
+            let _ = ack.send(pending.error.take());
+        }
+    }
+
+    //Finishes with the old file before opening the new one
+    fn reload(&mut self, settings: FileSettings, pending: &mut Pending) {
+        self.sync(pending);
+        pending.answer_waiting();
+        match RotatingFile::open(&settings.path, settings.rotate) {
+            Ok(file) => {
+                self.log_file = file;
+                self.metrics.reopened(self.log_file.size());
+            }
+            Err(e) => {
+                eprintln!("Error opening log file: {}", e);
+                self.metrics.failed(format!("opening the log file: {e}"));
+                let (path, reason) = (settings.path.display().to_string(), e.to_string());
+                let fields = [("log_file", path.as_str()), ("reason", reason.as_str())];
+                let entry = server_entry(Severity::Error, "cannot open log_file, still writing the old one", &fields);
+                self.write(&entry, pending);
+            }
+        }
+        self.format = settings.format;
+        self.fsync = settings.fsync;
+    }
+
+    fn write(&mut self, entry: &Entry, pending: &mut Pending) {
+        let (to_log_file, sinks) = self.router.route(entry);
+        let line = self.format.encode(entry);
+        if to_log_file {
+            let start = Instant::now();
+            match self.log_file.write_line(line.as_bytes()) {
+                Ok(()) => self.metrics.wrote(&entry.record.sender, line.len(), start.elapsed(), self.log_file.size()),
+                Err(e) => {
+                    eprintln!("Error writing to log file: {}", e);
+                    self.metrics.failed(format!("writing the log file: {e}"));
+                    pending.error.get_or_insert_with(|| format!("writing the log file: {e}"));
+                }
+            }
+        }
+        for sink in sinks {
+            if let Err(e) = self.router.write(sink, entry) {
+                eprintln!("Error {}", e);
+                self.metrics.failed(e.clone());
+                pending.error.get_or_insert(e);
+            }
+        }
+        pending.dirty = true;
+        self.subscribers.publish(entry, &line);
+        if let (Some(relay), Some(_)) = (&mut self.relay, &entry.peer) {
+            relay.forward(entry);
+        }
+    }
+
+    //Fsyncs if anything was written since the last time
+    fn sync(&mut self, pending: &mut Pending) {
+        pending.last_sync = Instant::now();
+        if pending.dirty {
+            pending.dirty = false;
+            if let Err(e) = self.log_file.sync() {
+                eprintln!("Error syncing log file: {}", e);
+                self.metrics.failed(format!("syncing the log file: {e}"));
+                pending.error.get_or_insert_with(|| format!("syncing the log file: {e}"));
+            }
+            if let Err(e) = self.router.sync() {
+                eprintln!("Error {}", e);
+                self.metrics.failed(e.clone());
+                pending.error.get_or_insert(e);
+            }
+        }
+    }
+}
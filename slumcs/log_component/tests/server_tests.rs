@@ -0,0 +1,1288 @@
+use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use send_log::clock::Clock;
+use send_log::protocol::{encode_frame, Frame, Record};
+use send_log::{log_clock, log_clock_merge, log_flush, log_forward, log_send_sync, log_send_with, log_sync, try_log_connect, try_log_disconnect, try_log_send, Log, LogError, Severity};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+//A log_component process running against its own config and log file
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    port: u16,
+}
+
+impl Server {
+    fn start(name: &str, extra_config: &str) -> Server {
+        Server::start_on(name, free_port(), extra_config)
+    }
+
+    //Like start, but on a port picked by the test
+    fn start_on(name: &str, port: u16, extra_config: &str) -> Server {
+        Server::launch(name, port, extra_config, |command, config_path| {
+            command.arg("--config").arg(config_path);
+        })
+    }
+
+    //Like start, but leaves it to `setup` to point the server at its config
+    fn start_with(name: &str, extra_config: &str, setup: impl FnOnce(&mut Command, &Path)) -> Server {
+        Server::launch(name, free_port(), extra_config, setup)
+    }
+
+    fn launch(name: &str, port: u16, extra_config: &str, setup: impl FnOnce(&mut Command, &Path)) -> Server {
+        let dir = test_dir(name);
+        let config = format!(
+            "log_ip = \"127.0.0.1\"\nlog_port = {port}\nlog_file = {:?}\n{extra_config}",
+            dir.join("systemlog.txt").to_str().unwrap()
+        );
+        std::fs::write(dir.join("config.toml"), config).unwrap();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_log_component"));
+        //Diagnostics go to stderr; keep them out of the test output but
+        //at hand when a test fails
+        let stderr = std::fs::File::create(dir.join("stderr.txt")).unwrap();
+        command.current_dir(&dir).stdout(Stdio::null()).stderr(stderr);
+        setup(&mut command, &dir.join("config.toml"));
+        let child = command.spawn().unwrap();
+        let server = Server { child, dir, port };
+        server.wait_until_listening();
+        server
+    }
+
+    fn wait_until_listening(&self) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(("127.0.0.1", self.port)).is_err() {
+            assert!(Instant::now() < deadline, "log_component did not start listening");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn config_path(&self) -> String {
+        self.dir.join("config.toml").to_str().unwrap().to_string()
+    }
+
+    //A config file for clients that need settings of their own
+    fn client_config(&self, file: &str, extra_config: &str) -> String {
+        let path = self.dir.join(file);
+        std::fs::write(&path, format!("log_ip = \"127.0.0.1\"\nlog_port = {}\n{extra_config}", self.port)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    //Sends a signal such as "HUP" to the server
+    fn signal(&self, signal: &str) {
+        let status = Command::new("kill").args([&format!("-{signal}"), &self.child.id().to_string()]).status().unwrap();
+        assert!(status.success());
+    }
+
+    fn connect(&self) -> Log {
+        try_log_connect(&self.config_path()).unwrap()
+    }
+
+    fn lines(&self) -> Vec<String> {
+        std::fs::read_to_string(self.dir.join("systemlog.txt"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    //Polls the log file until `done` accepts its lines
+    fn wait_until(&self, what: &str, done: impl Fn(&[String]) -> bool) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let lines = self.lines();
+            if done(&lines) {
+                return lines;
+            }
+            assert!(Instant::now() < deadline, "expected {what}, log has {lines:?}");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    //Waits for `count` lines sent by clients, leaving out the server's own
+    //connect/disconnect records
+    fn wait_for_lines(&self, count: usize) -> Vec<String> {
+        let client_lines = |lines: &[String]| -> Vec<String> {
+            lines.iter().filter(|l| !l.contains(" log_component: ")).cloned().collect()
+        };
+        client_lines(&self.wait_until(&format!("{count} lines"), |lines| client_lines(lines).len() >= count))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+//An empty directory for one test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("log_component_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+//Runs log_component with a config it should refuse, returning what it
+//wrote to stderr
+fn start_error(name: &str, extra_config: &str) -> String {
+    let dir = test_dir(name);
+    let config = format!("log_ip = \"127.0.0.1\"\nlog_port = {}\nlog_file = \"systemlog.txt\"\n{extra_config}", free_port());
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_log_component"))
+        .current_dir(&dir)
+        .arg("--config")
+        .arg(dir.join("config.toml"))
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(!output.status.success());
+    String::from_utf8(output.stderr).unwrap()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+//A throwaway CA with a server certificate for 127.0.0.1 and a client
+//certificate, plus an unrelated CA, as PEM files in their own directory
+struct Certs {
+    dir: PathBuf,
+}
+
+impl Certs {
+    fn generate(name: &str) -> Certs {
+        let dir = std::env::temp_dir().join(format!("log_component_certs_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        let leaves = [
+            ("server", vec!["127.0.0.1".to_string()], ExtendedKeyUsagePurpose::ServerAuth),
+            ("client", Vec::new(), ExtendedKeyUsagePurpose::ClientAuth),
+        ];
+        for (leaf, names, usage) in leaves {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(names).unwrap();
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            std::fs::write(dir.join(format!("{leaf}.pem")), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{leaf}.key")), key.serialize_pem()).unwrap();
+        }
+
+        let other_key = KeyPair::generate().unwrap();
+        let mut other_params = CertificateParams::new(Vec::new()).unwrap();
+        other_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        std::fs::write(dir.join("other_ca.pem"), other_params.self_signed(&other_key).unwrap().pem()).unwrap();
+        Certs { dir }
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.dir.join(file).to_str().unwrap().to_string()
+    }
+
+    //Keys for both ends, since server and clients share config.toml
+    fn config(&self, client_certs: bool) -> String {
+        let mut config = format!(
+            "tls = true\ntls_cert_file = {:?}\ntls_key_file = {:?}\ntls_ca_file = {:?}\n",
+            self.path("server.pem"),
+            self.path("server.key"),
+            self.path("ca.pem")
+        );
+        if client_certs {
+            config += &format!(
+                "tls_client_ca_file = {:?}\ntls_client_cert_file = {:?}\ntls_client_key_file = {:?}\n",
+                self.path("ca.pem"),
+                self.path("client.pem"),
+                self.path("client.key")
+            );
+        }
+        config
+    }
+}
+
+impl Drop for Certs {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+//A connected client's records are written without waiting for another
+//client to connect
+#[test]
+fn serves_existing_clients_immediately() {
+    let server = Server::start("immediate", "");
+    let mut first = server.connect();
+    let mut second = server.connect();
+
+    try_log_send(&mut first, "from first").unwrap();
+    assert!(server.wait_for_lines(1)[0].ends_with("from first"));
+
+    try_log_send(&mut second, "from second").unwrap();
+    try_log_send(&mut first, "first again").unwrap();
+    let lines = server.wait_for_lines(3);
+    assert!(lines.iter().any(|l| l.ends_with("from second")));
+    assert!(lines.iter().any(|l| l.ends_with("first again")));
+}
+
+//Each send becomes one line, even when many clients send at once
+#[test]
+fn one_line_per_record() {
+    let server = Server::start("records", "");
+    let config_path = server.config_path();
+
+    let senders: Vec<_> = (0..4)
+        .map(|id| {
+            let config_path = config_path.clone();
+            thread::spawn(move || {
+                let mut log = try_log_connect(&config_path).unwrap();
+                for i in 0..50 {
+                    let msg = format!("Message {i} from sender {id}\n{}", "x".repeat(i * 100 + 1));
+                    log_send_with(&mut log, Severity::Warn, &msg, &[("sender_id", &id.to_string())]).unwrap();
+                }
+                log_flush(&mut log).unwrap();
+            })
+        })
+        .collect();
+    for sender in senders {
+        sender.join().unwrap();
+    }
+
+    let lines = server.wait_for_lines(200);
+    assert_eq!(lines.len(), 200);
+    for id in 0..4 {
+        let mine: Vec<_> = lines.iter().filter(|l| l.ends_with(&format!("sender_id={id}"))).collect();
+        assert_eq!(mine.len(), 50);
+        for (i, line) in mine.iter().enumerate() {
+            assert!(line.contains(" WARN "));
+            assert!(line.contains(&format!("Message {i} from sender {id}\\n")));
+        }
+    }
+}
+
+//Disconnects are noticed and logged with what the client sent
+#[test]
+fn logs_connect_and_disconnect() {
+    let server = Server::start("disconnect", "");
+    let mut log = server.connect();
+    for i in 0..3 {
+        try_log_send(&mut log, &format!("Message {i}")).unwrap();
+    }
+    try_log_disconnect(&mut log).unwrap();
+
+    let lines = server.wait_until("a disconnect record", |lines| {
+        lines.iter().any(|l| l.contains("client disconnected") && l.contains("records=3"))
+    });
+    let connected = lines.iter().position(|l| l.contains("INFO  log_component: client connected peer=127.0.0.1:"));
+    let disconnected = lines.iter().position(|l| l.contains("records=3"));
+    assert!(connected.unwrap() < disconnected.unwrap());
+    assert!(lines[disconnected.unwrap()].ends_with("reason=closed"));
+}
+
+//A client sending garbage is dropped without disturbing anyone else
+#[test]
+fn bad_client_does_not_stop_server() {
+    let server = Server::start("garbage", "");
+    let mut good = server.connect();
+
+    let mut bad = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    bad.write_all(&[0, 0, 0, 3, 99, 1, 0]).unwrap();
+    server.wait_until("a protocol error", |lines| {
+        lines.iter().any(|l| l.contains("WARN  log_component: client disconnected") && l.contains("protocol error"))
+    });
+
+    let mut late = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    late.write_all(&[0, 0, 0, 9, 2, 1]).unwrap();
+    drop(late);
+    server.wait_until("a mid-frame close", |lines| lines.iter().any(|l| l.contains("closed mid-frame")));
+
+    try_log_send(&mut good, "still here").unwrap();
+    assert!(server.wait_for_lines(1)[0].ends_with("still here"));
+    let mut fresh = server.connect();
+    try_log_send(&mut fresh, "new client").unwrap();
+    server.wait_for_lines(2);
+}
+
+//output_format = "json" writes one object per record, with the peer
+//address and the fields the client attached
+#[test]
+fn json_output() {
+    let server = Server::start("json", "output_format = \"json\"\nlog_sender = \"json-test\"\n");
+    let mut log = server.connect();
+    log_send_with(&mut log, Severity::Error, "quote \" and\nnewline", &[("user", "bob smith")]).unwrap();
+
+    let lines = server.wait_until("a json record", |lines| lines.iter().any(|l| l.contains("\"sender\":\"json-test\"")));
+    let line = lines.iter().find(|l| l.contains("\"sender\":\"json-test\"")).unwrap();
+    assert!(line.starts_with("{\"time\":\""));
+    assert!(line.contains("\"severity\":\"ERROR\""));
+    assert!(line.contains("\"peer\":\"127.0.0.1:"));
+    assert!(line.contains("\"message\":\"quote \\\" and\\nnewline\""));
+    assert!(line.ends_with("\"fields\":{\"user\":\"bob smith\"}}"));
+}
+
+//output_format = "logfmt" writes key=value pairs, quoting where needed
+#[test]
+fn logfmt_output() {
+    let server = Server::start("logfmt", "output_format = \"logfmt\"\nlog_sender = \"logfmt-test\"\n");
+    let mut log = server.connect();
+    log_send_with(&mut log, Severity::Warn, "disk full", &[("mount point", "/var")]).unwrap();
+
+    let lines = server.wait_until("a logfmt record", |lines| lines.iter().any(|l| l.contains("sender=logfmt-test")));
+    let line = lines.iter().find(|l| l.contains("sender=logfmt-test")).unwrap();
+    assert!(line.starts_with("time="));
+    assert!(line.contains(" received="));
+    assert!(line.contains(" level=warn sender=logfmt-test peer=127.0.0.1:"));
+    assert!(line.ends_with(" msg=\"disk full\" mount_point=/var"));
+}
+
+//Peers are quoted like any other logfmt value; a Unix socket path may
+//hold spaces
+#[test]
+fn logfmt_quotes_peers() {
+    let socket = test_dir("logfmt unix").join("log.sock");
+    let config = format!("output_format = \"logfmt\"\nlog_socket = {:?}\n", socket.to_str().unwrap());
+    let server = Server::start("logfmt_peer", &config);
+    let path = server.client_config(
+        "unix.toml",
+        &format!("log_transport = \"unix\"\nlog_socket = {:?}\nlog_sender = \"local\"\n", socket.to_str().unwrap()),
+    );
+    let mut log = try_log_connect(&path).unwrap();
+    try_log_send(&mut log, "over a unix socket").unwrap();
+
+    let lines = server.wait_until("the record", |lines| lines.iter().any(|l| l.contains("sender=local")));
+    let line = lines.iter().find(|l| l.contains("sender=local")).unwrap();
+    assert!(line.contains(&format!(" sender=local peer=\"unix:{}\" ", socket.display())), "{line}");
+    let _ = std::fs::remove_dir_all(socket.parent().unwrap());
+}
+
+//A line break in a sender or field key cannot forge a second line
+#[test]
+fn plain_output_escapes_senders_and_keys() {
+    let syslog_port = free_port();
+    let server = Server::start("plain-escapes", &format!("syslog_port = {syslog_port}\n"));
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.send_to(b"<34>Oct 11 22:14:15 evil\nFORGED not a tag", ("127.0.0.1", syslog_port)).unwrap();
+    let mut log = server.connect();
+    log_send_with(&mut log, Severity::Info, "keyed", &[("bad\r\nkey", "v")]).unwrap();
+
+    let lines = server.wait_until("both records", |lines| {
+        lines.iter().any(|l| l.contains("not a tag")) && lines.iter().any(|l| l.contains("keyed"))
+    });
+    assert!(lines.iter().any(|l| l.contains(" ERROR evil\\nFORGED: not a tag ") && l.ends_with("hostname=\"evil\\nFORGED\"")), "{lines:?}");
+    assert!(lines.iter().any(|l| l.ends_with("keyed bad\\r\\nkey=v")), "{lines:?}");
+    assert!(!lines.iter().any(|l| l.starts_with("FORGED") || l.starts_with("key=")), "{lines:?}");
+}
+
+//Size-based rotation keeps only the newest rotate_keep files, gzipped
+#[test]
+fn rotates_by_size() {
+    let server = Server::start(
+        "rotate",
+        "rotate_max_bytes = 400\nrotate_compress = true\nrotate_keep = 2\nlog_sender = \"rotate-test\"\n",
+    );
+    let mut log = server.connect();
+    for i in 0..30 {
+        try_log_send(&mut log, &format!("Message {i} {}", "x".repeat(60))).unwrap();
+    }
+    log_flush(&mut log).unwrap();
+
+    server.wait_until("the last record", |lines| lines.iter().any(|l| l.contains("Message 29 ")));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let mut names: Vec<String> = std::fs::read_dir(&server.dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|n| n.starts_with("systemlog.txt."))
+            .collect();
+        names.sort();
+        if names.len() == 2 && names.iter().all(|n| n.ends_with(".gz")) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "rotated files: {names:?}");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(std::fs::metadata(server.dir.join("systemlog.txt")).unwrap().len() <= 400);
+}
+
+//SIGHUP makes the server reopen log_file after it was moved away
+#[test]
+fn reopens_on_sighup() {
+    let server = Server::start("sighup", "");
+    let mut log = server.connect();
+    try_log_send(&mut log, "before").unwrap();
+    server.wait_for_lines(1);
+
+    std::fs::rename(server.dir.join("systemlog.txt"), server.dir.join("moved.txt")).unwrap();
+    server.signal("HUP");
+    thread::sleep(Duration::from_millis(200));
+
+    try_log_send(&mut log, "after").unwrap();
+    let lines = server.wait_for_lines(1);
+    assert!(lines[0].ends_with("after"));
+    let moved = std::fs::read_to_string(server.dir.join("moved.txt")).unwrap();
+    assert!(moved.contains("before") && !moved.contains("after"));
+}
+
+//SIGHUP puts a changed log_file, output_format and rate limit into
+//effect without dropping clients, and keeps the old configuration when
+//the new one does not load
+#[test]
+fn reloads_config_on_sighup() {
+    let server = Server::start("reload", "");
+    let mut log = server.connect();
+    try_log_send(&mut log, "before").unwrap();
+    server.wait_for_lines(1);
+
+    let config = std::fs::read_to_string(server.config_path()).unwrap();
+    let config = config.replace("systemlog.txt", "reloaded.txt");
+    std::fs::write(server.config_path(), format!("{config}output_format = \"json\"\n[rate_limit]\nclient_records_per_sec = 1\n")).unwrap();
+    server.signal("HUP");
+    let reloaded = server.dir.join("reloaded.txt");
+    let read = || std::fs::read_to_string(&reloaded).unwrap_or_default();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !read().contains("configuration reloaded") {
+        assert!(Instant::now() < deadline, "no reload in {:?}", server.lines());
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    for i in 0..3 {
+        try_log_send(&mut log, &format!("after {i}")).unwrap();
+    }
+    log_sync(&mut log).unwrap();
+    let lines: Vec<String> = read().lines().filter(|l| l.contains("\"after ")).map(str::to_string).collect();
+    assert_eq!(lines.len(), 1, "{lines:?}");
+    assert!(lines[0].starts_with('{') && lines[0].contains("\"message\":\"after 0\""), "{lines:?}");
+    assert!(server.lines().iter().any(|l| l.ends_with("before")) && !server.lines().iter().any(|l| l.contains("after")));
+
+    std::fs::write(server.config_path(), format!("{config}fsync = \"sometimes\"\n")).unwrap();
+    server.signal("HUP");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !read().contains("configuration not reloaded") {
+        assert!(Instant::now() < deadline, "no reload error in {}", read());
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(read().contains("unknown fsync"), "{}", read());
+}
+
+//SIGTERM writes what connected clients had already sent, then exits
+//cleanly
+#[test]
+fn drains_clients_on_sigterm() {
+    let mut server = Server::start("sigterm", "");
+    let mut log = server.connect();
+    for i in 0..500 {
+        try_log_send(&mut log, &format!("record {i}")).unwrap();
+    }
+    server.signal("TERM");
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = server.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "log_component did not exit");
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert!(status.success(), "{status}");
+    let lines = server.lines();
+    assert!(lines.iter().any(|l| l.contains("shutting down signal=SIGTERM")), "{lines:?}");
+    let records: Vec<_> = lines.iter().filter(|l| l.contains(": record ")).collect();
+    assert_eq!(records.len(), 500);
+    assert!(records.last().unwrap().ends_with("record 499"));
+    assert!(TcpStream::connect(("127.0.0.1", server.port)).is_err());
+}
+
+//With tls = true records travel encrypted, and a client speaking plain
+//TCP is turned away
+#[test]
+fn tls_transport() {
+    let certs = Certs::generate("tls");
+    let server = Server::start("tls", &certs.config(false));
+    let mut log = server.connect();
+    try_log_send(&mut log, "over tls").unwrap();
+    assert!(server.wait_for_lines(1)[0].ends_with("over tls"));
+
+    let mut plain = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    plain.write_all(&[0, 0, 0, 3, 2, 1, 0]).unwrap();
+    server.wait_until("a failed handshake", |lines| {
+        lines.iter().any(|l| l.contains("client disconnected") && l.contains("reason=\"read error:"))
+    });
+
+    try_log_send(&mut log, "still over tls").unwrap();
+    assert_eq!(server.wait_for_lines(2).len(), 2);
+    try_log_disconnect(&mut log).unwrap();
+    server.wait_until("a clean disconnect", |lines| {
+        lines.iter().any(|l| l.contains("records=2") && l.ends_with("reason=closed"))
+    });
+}
+
+//A client rejects a server whose certificate it cannot verify
+#[test]
+fn tls_rejects_untrusted_server() {
+    let certs = Certs::generate("untrusted");
+    let server = Server::start("untrusted", &certs.config(false));
+    let path = server.client_config("untrusted.toml", &format!("tls = true\ntls_ca_file = {:?}\n", certs.path("other_ca.pem")));
+    assert!(matches!(try_log_connect(&path), Err(LogError::ConnectRefused(..))));
+
+    let path = server.client_config("wrong_name.toml", &format!("tls = true\ntls_ca_file = {:?}\ntls_server_name = \"logs.example\"\n", certs.path("ca.pem")));
+    assert!(matches!(try_log_connect(&path), Err(LogError::ConnectRefused(..))));
+}
+
+//With tls_client_ca_file set, only clients presenting a certificate from
+//that CA get their records written
+#[test]
+fn mutual_tls() {
+    let certs = Certs::generate("mtls");
+    let server = Server::start("mtls", &certs.config(true));
+    let mut trusted = server.connect();
+    try_log_send(&mut trusted, "with certificate").unwrap();
+    assert!(server.wait_for_lines(1)[0].ends_with("with certificate"));
+
+    let path = server.client_config("anonymous.toml", &format!("tls = true\ntls_ca_file = {:?}\n", certs.path("ca.pem")));
+    //TLS 1.3 finishes the client's side of the handshake before the server
+    //has checked its certificate, so the rejection may only show later
+    if let Ok(mut anonymous) = try_log_connect(&path) {
+        let _ = try_log_send(&mut anonymous, "without certificate");
+    }
+    server.wait_until("a rejected client", |lines| {
+        lines.iter().any(|l| l.contains("client disconnected") && l.contains("reason=\"read error:"))
+    });
+
+    try_log_send(&mut trusted, "still trusted").unwrap();
+    let lines = server.wait_for_lines(2);
+    assert!(lines[1].ends_with("still trusted"));
+    assert!(!server.lines().iter().any(|l| l.contains("without certificate")));
+}
+
+//Datagrams cannot present a client certificate, so with one required
+//log_udp is a configuration error
+#[test]
+fn mutual_tls_refuses_udp() {
+    let certs = Certs::generate("mtls-udp");
+    let stderr = start_error("mtls-udp", &format!("{}log_udp = true\n", certs.config(true)));
+    assert!(stderr.contains("log_udp cannot be combined with authentication or client certificates"), "{stderr}");
+}
+
+//Syslog carries no credentials either, so with authentication it needs
+//an explicit opt-in; its port must be a port number
+#[test]
+fn syslog_needs_opt_in_with_authentication() {
+    let syslog_port = free_port();
+    let stderr = start_error("syslog-auth", &format!("auth_secret = \"s3cret\"\nsyslog_port = {syslog_port}\n"));
+    assert!(stderr.contains("syslog_port cannot be combined with authentication"), "{stderr}");
+    let stderr = start_error("syslog-port", "syslog_port = 70000\n");
+    assert!(stderr.contains("\"syslog_port\" must be a port number between 0 and 65535, not 70000"), "{stderr}");
+
+    let config = format!("auth_secret = \"s3cret\"\nsyslog_port = {syslog_port}\nsyslog_unauthenticated = true\n");
+    let server = Server::start("syslog-open", &config);
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.send_to(b"<14>1 - host app - - - allowed", ("127.0.0.1", syslog_port)).unwrap();
+    server.wait_until("the syslog record", |lines| lines.iter().any(|l| l.contains(" app: allowed ")));
+}
+
+//With auth_secret set, clients authenticate and their records carry the
+//application name they proved
+#[test]
+fn authenticated_clients_are_stamped() {
+    let server = Server::start("auth", "auth_secret = \"s3cret\"\nauth_app = \"billing\"\n");
+    let mut log = server.connect();
+    try_log_send(&mut log, "invoice paid").unwrap();
+
+    assert!(server.wait_for_lines(1)[0].ends_with("invoice paid identity=billing"));
+    assert!(server.lines().iter().any(|l| l.contains("client authenticated") && l.ends_with("app=billing")));
+}
+
+//Wrong secrets, unknown applications and clients skipping the handshake
+//never get a record into the log
+#[test]
+fn rejects_unauthenticated_clients() {
+    let server = Server::start("noauth", "[auth_clients]\nbilling = \"right\"\n");
+
+    let wrong = server.client_config("wrong.toml", "auth_app = \"billing\"\nauth_secret = \"wrong\"\n");
+    assert!(matches!(try_log_connect(&wrong), Err(LogError::AuthRejected(_))));
+    let unknown = server.client_config("unknown.toml", "auth_app = \"payroll\"\nauth_secret = \"right\"\n");
+    assert!(matches!(try_log_connect(&unknown), Err(LogError::AuthRejected(_))));
+
+    //A client without a secret finds out when it connects
+    let anonymous = server.client_config("anonymous.toml", "");
+    match try_log_connect(&anonymous) {
+        Err(LogError::AuthRejected(reason)) => assert!(reason.contains("auth_secret is not set"), "{reason}"),
+        other => panic!("expected AuthRejected, got {:?}", other.err()),
+    }
+    //and one that skips the handshake is turned away at its first record
+    let mut raw = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    raw.write_all(&encode_frame(&Frame::Record(Record::new("raw", Severity::Info, "sneaking in")))).unwrap();
+    server.wait_until("a rejected record", |lines| {
+        lines.iter().any(|l| l.contains("client disconnected") && l.ends_with("reason=\"record before authentication\""))
+    });
+
+    let right = server.client_config("right.toml", "auth_app = \"billing\"\nauth_secret = \"right\"\n");
+    let mut log = try_log_connect(&right).unwrap();
+    try_log_send(&mut log, "let in").unwrap();
+    let lines = server.wait_for_lines(1);
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].ends_with("let in identity=billing"));
+    server.wait_until("two failed handshakes", |lines| {
+        lines.iter().filter(|l| l.contains("authentication failed")).count() == 2
+    });
+}
+
+//Without --config the server reads SLUMCS_CONFIG, or ./config.toml, and
+//SLUMCS_* variables override single keys
+#[test]
+fn finds_config_without_flag() {
+    let server = Server::start_with("cwd_config", "", |_, _| {});
+    let mut log = server.connect();
+    try_log_send(&mut log, "found ./config.toml").unwrap();
+    server.wait_for_lines(1);
+
+    let from_env = Server::start_with("env_config", "", |command, config_path| {
+        command
+            .current_dir(std::env::temp_dir())
+            .env("SLUMCS_CONFIG", config_path)
+            .env("SLUMCS_OUTPUT_FORMAT", "json");
+    });
+    let mut log = from_env.connect();
+    try_log_send(&mut log, "format from the environment").unwrap();
+    from_env.wait_until("a json record", |lines| {
+        lines.iter().any(|l| l.contains("\"message\":\"format from the environment\""))
+    });
+}
+
+//A bad or missing configuration stops the server with every problem named
+#[test]
+fn reports_configuration_errors() {
+    let dir = test_dir("bad_config");
+    std::fs::write(dir.join("bad.toml"), "log_ip = \"127.0.0.1\"\nlog_port = \"http\"\n").unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_log_component"))
+            .args(args)
+            .current_dir(&dir)
+            .env("HOME", &dir)
+            .env("XDG_CONFIG_HOME", &dir)
+            .env_remove("SLUMCS_CONFIG")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    let stderr = run(&["--config", dir.join("bad.toml").to_str().unwrap()]);
+    assert!(stderr.contains("\"log_file\" is missing"), "{stderr}");
+    assert!(stderr.contains("\"log_port\" must be a port number"), "{stderr}");
+
+    std::fs::write(dir.join("fsync.toml"), "log_ip = \"127.0.0.1\"\nlog_port = 1\nlog_file = \"x\"\nfsync = \"sometimes\"\n").unwrap();
+    let stderr = run(&["--config", dir.join("fsync.toml").to_str().unwrap()]);
+    assert!(stderr.contains("unknown fsync \"sometimes\""), "{stderr}");
+
+    std::fs::write(dir.join("limit.toml"), "log_ip = \"127.0.0.1\"\nlog_port = 1\nlog_file = \"x\"\n[rate_limit]\npolicy = \"shout\"\n").unwrap();
+    let stderr = run(&["--config", dir.join("limit.toml").to_str().unwrap()]);
+    assert!(stderr.contains("unknown rate_limit.policy \"shout\""), "{stderr}");
+
+    std::fs::write(dir.join("route.toml"), "log_ip = \"127.0.0.1\"\nlog_port = 1\nlog_file = \"x\"\n[[routes]]\nsinks = [\"nowhere\"]\n").unwrap();
+    let stderr = run(&["--config", dir.join("route.toml").to_str().unwrap()]);
+    assert!(stderr.contains("routes[0] names unknown sink \"nowhere\""), "{stderr}");
+
+    std::fs::write(dir.join("codec.toml"), "log_ip = \"127.0.0.1\"\nlog_port = 1\nlog_file = \"x\"\ncompression = \"lz4\"\n").unwrap();
+    let stderr = run(&["--config", dir.join("codec.toml").to_str().unwrap()]);
+    assert!(stderr.contains("invalid compression: unknown codec \"lz4\""), "{stderr}");
+
+    let stderr = run(&[]);
+    assert!(stderr.contains("no configuration file given with --config or SLUMCS_CONFIG"), "{stderr}");
+
+    let stderr = run(&["--config", dir.join("missing.toml").to_str().unwrap()]);
+    assert!(stderr.contains("missing.toml does not exist"), "{stderr}");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+//log_socket adds a Unix domain socket listener for clients on this host
+#[test]
+fn unix_socket_transport() {
+    let socket = test_dir("unix_socket").join("log.sock");
+    let server = Server::start("unix", &format!("log_socket = {:?}\n", socket.to_str().unwrap()));
+    let path = server.client_config(
+        "unix.toml",
+        &format!("log_transport = \"unix\"\nlog_socket = {:?}\n", socket.to_str().unwrap()),
+    );
+    let mut log = try_log_connect(&path).unwrap();
+    try_log_send(&mut log, "over a unix socket").unwrap();
+    assert!(server.wait_for_lines(1)[0].ends_with("over a unix socket"));
+
+    try_log_disconnect(&mut log).unwrap();
+    server.wait_until("a unix disconnect", |lines| {
+        lines.iter().any(|l| l.contains("client disconnected peer=unix:") && l.ends_with("reason=closed"))
+    });
+    let _ = std::fs::remove_dir_all(socket.parent().unwrap());
+}
+
+//log_udp = true takes one record per datagram; malformed datagrams are
+//reported and skipped
+#[test]
+fn udp_transport() {
+    let server = Server::start("udp", "log_udp = true\n");
+    let path = server.client_config("udp.toml", "log_transport = \"udp\"\n");
+    let mut log = try_log_connect(&path).unwrap();
+    for i in 0..5 {
+        log_send_with(&mut log, Severity::Info, &format!("datagram {i}"), &[("n", &i.to_string())]).unwrap();
+    }
+    let lines = server.wait_for_lines(5);
+    for i in 0..5 {
+        assert!(lines.iter().any(|l| l.ends_with(&format!("datagram {i} n={i}"))));
+    }
+
+    let bad = UdpSocket::bind("127.0.0.1:0").unwrap();
+    bad.send_to(&[0, 0, 0, 9, 2, 1], ("127.0.0.1", server.port)).unwrap();
+    server.wait_until("a bad datagram", |lines| {
+        lines.iter().any(|l| l.contains("WARN  log_component: bad datagram") && l.ends_with("reason=\"6 trailing bytes\""))
+    });
+}
+
+//syslog_port takes RFC 5424 and RFC 3164 messages over UDP and TCP and
+//writes them like any other record
+#[test]
+fn syslog_ingestion() {
+    let syslog_port = free_port();
+    let server = Server::start("syslog", &format!("syslog_port = {syslog_port}\noutput_format = \"json\"\n"));
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let to = ("127.0.0.1", syslog_port);
+    udp.send_to(
+        b"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+          [exampleSDID@32473 iut=\"3\" eventSource=\"App\"][meta note=\"a \\\"quoted\\\" ]\"] An application event",
+        to,
+    )
+    .unwrap();
+    udp.send_to(b"not syslog at all", to).unwrap();
+
+    let mut tcp = TcpStream::connect(to).unwrap();
+    let bsd = "<34>Oct 11 22:14:15 mymachine su[77]: 'su root' failed for lonvick";
+    write!(tcp, "{} {}", bsd.len(), bsd).unwrap();
+    tcp.write_all(b"<15>1 - host app 123 - - line framed\n").unwrap();
+    drop(tcp);
+
+    let lines = server.wait_until("three syslog records", |lines| {
+        lines.iter().filter(|l| l.contains("\"facility\":")).count() == 3 && lines.iter().any(|l| l.contains("bad syslog message"))
+    });
+    let find = |text: &str| lines.iter().find(|l| l.contains(text)).unwrap().clone();
+
+    let line = find("An application event");
+    assert!(line.starts_with("{\"time\":\"2003-10-11T22:14:15.003Z\""), "{line}");
+    assert!(line.contains("\"severity\":\"INFO\",\"sender\":\"evntslog\""), "{line}");
+    assert!(line.contains(
+        "\"fields\":{\"facility\":\"local4\",\"syslog_severity\":\"notice\",\"hostname\":\"mymachine.example.com\",\
+         \"msgid\":\"ID47\",\"exampleSDID@32473.iut\":\"3\",\"exampleSDID@32473.eventSource\":\"App\",\
+         \"meta.note\":\"a \\\"quoted\\\" ]\"}"
+    ), "{line}");
+
+    let line = find("failed for lonvick");
+    assert!(line.contains("\"severity\":\"ERROR\",\"sender\":\"su\""), "{line}");
+    assert!(line.contains("-10-11T22:14:15.000Z"), "{line}");
+    assert!(line.contains("\"message\":\"'su root' failed for lonvick\""), "{line}");
+    assert!(line.contains("{\"facility\":\"auth\",\"syslog_severity\":\"crit\",\"hostname\":\"mymachine\",\"procid\":\"77\"}"), "{line}");
+
+    let line = find("line framed");
+    assert!(line.contains("\"severity\":\"DEBUG\",\"sender\":\"app\""), "{line}");
+    assert!(line.contains("\"procid\":\"123\""), "{line}");
+
+    assert!(find("bad syslog message").contains("missing <PRI>"));
+}
+
+//A line that never ends is cut off rather than buffered without bound
+#[test]
+fn syslog_rejects_overlong_lines() {
+    let syslog_port = free_port();
+    let server = Server::start("syslog-long", &format!("syslog_port = {syslog_port}\n"));
+    let mut tcp = TcpStream::connect(("127.0.0.1", syslog_port)).unwrap();
+    let _ = tcp.write_all(&vec![b'x'; 100 * 1024]);
+
+    let lines = server.wait_until("a dropped connection", |lines| lines.iter().any(|l| l.contains("syslog connection dropped")));
+    assert!(lines.iter().any(|l| l.ends_with("reason=\"message longer than 65536 bytes\"")), "{lines:?}");
+}
+
+//subscribe_port streams every written line to each subscriber
+#[test]
+fn subscribers_receive_written_lines() {
+    let subscribe_port = free_port();
+    let server = Server::start("subscribe", &format!("subscribe_port = {subscribe_port}\n"));
+    let mut reader = subscribe(subscribe_port, "");
+    server.wait_until("the subscriber", |lines| lines.iter().any(|l| l.contains("subscriber connected")));
+
+    let mut log = server.connect();
+    log_send_with(&mut log, Severity::Warn, "for the subscriber", &[("n", "1")]).unwrap();
+    log_flush(&mut log).unwrap();
+
+    let mut received = Vec::new();
+    while !received.iter().any(|l: &String| l.contains("for the subscriber")) {
+        let mut line = String::new();
+        assert!(reader.read_line(&mut line).unwrap() > 0, "subscription closed after {received:?}");
+        received.push(line.trim_end().to_string());
+    }
+    //Exactly as written to the file
+    let lines = server.wait_for_lines(1);
+    assert_eq!(received.last(), lines.last());
+    assert!(lines[0].ends_with(": for the subscriber n=1"), "{lines:?}");
+
+    //The server only notices once it has seen the hang-up, so a write
+    //racing with it may still go through
+    drop(reader);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !server.lines().iter().any(|l| l.contains("subscriber disconnected") && l.contains("reason=\"write error")) {
+        assert!(Instant::now() < deadline, "the subscriber was not dropped: {:?}", server.lines());
+        try_log_send(&mut log, "after the subscriber left").unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+//A subscriber that stops reading is dropped, and its connection closed,
+//once more lines are queued for it than the socket and its queue hold
+#[test]
+fn slow_subscribers_are_disconnected() {
+    let subscribe_port = free_port();
+    let server = Server::start("slow-subscriber", &format!("subscribe_port = {subscribe_port}\n"));
+    let mut reader = subscribe(subscribe_port, "");
+    server.wait_until("the subscriber", |lines| lines.iter().any(|l| l.contains("subscriber connected")));
+
+    let mut log = server.connect();
+    let padding = "x".repeat(8192);
+    for i in 0..2500 {
+        try_log_send(&mut log, &format!("line {i} {padding}")).unwrap();
+    }
+    log_flush(&mut log).unwrap();
+    server.wait_for_lines(2500);
+
+    //Whatever was already on its way arrives, then the server hangs up
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).expect("the subscription was not closed");
+    assert!(!String::from_utf8_lossy(&rest).contains("line 2499 "));
+    server.wait_until("the drop", |lines| {
+        lines.iter().any(|l| l.contains("subscriber disconnected") && l.ends_with("reason=\"fell behind\""))
+    });
+}
+
+//The subscription port has no credentials, so with authentication it is
+//only offered on loopback unless the operator opts in
+#[test]
+fn subscribe_port_stays_local_with_authentication() {
+    let config = format!("auth_secret = \"s3cret\"\nsubscribe_ip = \"0.0.0.0\"\nsubscribe_port = {}\n", free_port());
+    let stderr = start_error("subscribe-auth", &config);
+    assert!(stderr.contains("subscribe_port has no TLS or authentication"), "{stderr}");
+
+    let subscribe_port = free_port();
+    let server = Server::start("subscribe-local", &format!("auth_secret = \"s3cret\"\nsubscribe_port = {subscribe_port}\n"));
+    subscribe(subscribe_port, "");
+    server.wait_until("the subscriber", |lines| lines.iter().any(|l| l.contains("subscriber connected")));
+}
+
+//Subscribes with the given filter line, returning the stream after "ok"
+fn subscribe(port: u16, filter: &str) -> BufReader<TcpStream> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    writeln!(stream, "{filter}").unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut answer = String::new();
+    reader.read_line(&mut answer).unwrap();
+    assert_eq!(answer, "ok\n");
+    reader
+}
+
+//Each subscriber only gets the records its filter passes
+#[test]
+fn subscription_filters() {
+    let subscribe_port = free_port();
+    let server = Server::start("subscribe_filters", &format!("subscribe_port = {subscribe_port}\n"));
+    let mut by_sender = subscribe(subscribe_port, "sender=web");
+    let mut by_level_and_text = subscribe(subscribe_port, "level=warn contains=\"disk \\\"full\\\"\"");
+
+    //An invalid filter is refused and logged
+    let mut bad = TcpStream::connect(("127.0.0.1", subscribe_port)).unwrap();
+    writeln!(bad, "level=loud").unwrap();
+    let mut answer = String::new();
+    BufReader::new(bad).read_line(&mut answer).unwrap();
+    assert_eq!(answer, "error: unknown severity \"loud\"\n");
+    //and so is one that never ends
+    let mut endless = TcpStream::connect(("127.0.0.1", subscribe_port)).unwrap();
+    endless.write_all(&[b'a'; 5000]).unwrap();
+    let mut answer = String::new();
+    BufReader::new(endless).read_line(&mut answer).unwrap();
+    assert_eq!(answer, "error: filter longer than 4096 bytes\n");
+
+    let mut web = try_log_connect(&server.client_config("web.toml", "log_sender = \"web\"")).unwrap();
+    let mut db = try_log_connect(&server.client_config("db.toml", "log_sender = \"db\"")).unwrap();
+    log_send_with(&mut web, Severity::Info, "page served", &[]).unwrap();
+    log_send_with(&mut db, Severity::Info, "disk \"full\" soon", &[]).unwrap();
+    log_send_with(&mut db, Severity::Error, "disk \"full\"", &[]).unwrap();
+    //The two connections are read on separate threads; wait for db's
+    //records to land so web's error comes after them
+    log_sync(&mut db).unwrap();
+    log_send_with(&mut web, Severity::Error, "upload failed: disk \"full\"", &[]).unwrap();
+    log_flush(&mut web).unwrap();
+
+    let next = |reader: &mut BufReader<TcpStream>| {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    };
+    assert!(next(&mut by_sender).ends_with("web: page served\n"));
+    assert!(next(&mut by_sender).ends_with("web: upload failed: disk \"full\"\n"));
+    assert!(next(&mut by_level_and_text).ends_with("db: disk \"full\"\n"));
+    assert!(next(&mut by_level_and_text).ends_with("web: upload failed: disk \"full\"\n"));
+
+    let lines = server.wait_until("the refusal", |lines| lines.iter().any(|l| l.contains("bad subscription request")));
+    assert!(lines.iter().any(|l| l.contains("subscriber connected") && l.contains("filter=\"level=warn contains=")));
+}
+
+//A [relay] table forwards client records upstream, holding them while the
+//upstream is down
+#[test]
+fn relays_records_upstream() {
+    let upstream_port = free_port();
+    let spool = test_dir("relay_spool").join("relay.spool");
+    let relay = Server::start(
+        "relay",
+        &format!(
+            "[relay]\nlog_ip = \"127.0.0.1\"\nlog_port = {upstream_port}\nlog_sender = \"relay\"\n\
+             reconnect_initial_ms = 10\nreconnect_max_ms = 50\nbuffer_file = {:?}\n",
+            spool.to_str().unwrap()
+        ),
+    );
+
+    //Sent while there is no upstream yet
+    let mut log = relay.connect();
+    log_send_with(&mut log, Severity::Error, "before upstream", &[("n", "1")]).unwrap();
+    log_flush(&mut log).unwrap();
+    relay.wait_for_lines(1);
+
+    let upstream = Server::start_on("relay_upstream", upstream_port, "");
+    try_log_send(&mut log, "after upstream").unwrap();
+    let lines = upstream.wait_for_lines(2);
+    assert!(lines[0].contains(" ERROR server_tests") && lines[0].contains(": before upstream n=1 origin_peer=127.0.0.1:"), "{lines:?}");
+    assert!(lines[1].contains(": after upstream origin_peer=127.0.0.1:"), "{lines:?}");
+    //Only client records are relayed; the relay's own stay local
+    assert_eq!(upstream.lines().iter().filter(|l| l.contains("origin_peer=")).count(), 2);
+    let _ = std::fs::remove_dir_all(spool.parent().unwrap());
+}
+
+//A synced record is in the log file by the time log_send_sync returns,
+//whatever the fsync policy
+#[test]
+fn acknowledges_persisted_records() {
+    for fsync in ["never", "every_record", "interval"] {
+        let server = Server::start(&format!("fsync_{fsync}"), &format!("fsync = \"{fsync}\"\nfsync_interval_ms = 50\n"));
+        let mut log = server.connect();
+        for i in 0..3 {
+            try_log_send(&mut log, &format!("record {i}")).unwrap();
+        }
+        log_send_sync(&mut log, "synced").unwrap();
+        let lines = server.lines();
+        let client: Vec<&String> = lines.iter().filter(|l| !l.contains(" log_component: ")).collect();
+        assert_eq!(client.len(), 4, "{fsync}: {lines:?}");
+        assert!(client[3].ends_with(": synced"), "{fsync}: {lines:?}");
+        //Nothing new to persist is acked straight away
+        log_sync(&mut log).unwrap();
+    }
+}
+
+//Records over the client limit are dropped or sampled, and the drops are
+//reported per client
+#[test]
+fn rate_limits_drop_and_sample() {
+    for (policy, kept) in [("drop", 2..=3), ("sample", 6..=7)] {
+        let server = Server::start(
+            &format!("limit_{policy}"),
+            &format!(
+                "[rate_limit]\nclient_records_per_sec = 1\nburst_secs = 2\npolicy = \"{policy}\"\n\
+                 sample_every = 5\nsummary_interval_ms = 100\n"
+            ),
+        );
+        let mut log = server.connect();
+        for i in 0..22 {
+            try_log_send(&mut log, &format!("record {i}")).unwrap();
+        }
+        log_sync(&mut log).unwrap();
+        let written = server.wait_for_lines(0).len();
+        assert!(kept.contains(&written), "{policy}: {:?}", server.lines());
+
+        let lines = server.wait_until("a drop summary", |lines| lines.iter().any(|l| l.contains("records dropped by rate limit")));
+        let summary = lines.iter().find(|l| l.contains("records dropped by rate limit")).unwrap();
+        assert!(summary.contains(&format!("dropped={}", 22 - written)), "{summary}");
+        assert!(summary.contains("peer=127.0.0.1:"), "{summary}");
+    }
+}
+
+//Warnings about malformed datagrams are limited like the datagrams
+//themselves would have been
+#[test]
+fn rate_limits_bad_datagram_warnings() {
+    let server = Server::start(
+        "limit_garbage",
+        "log_udp = true\n[rate_limit]\nglobal_records_per_sec = 1\nburst_secs = 2\nsummary_interval_ms = 100\n",
+    );
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for _ in 0..20 {
+        udp.send_to(b"garbage", ("127.0.0.1", server.port)).unwrap();
+    }
+    let lines = server.wait_until("a drop summary", |lines| lines.iter().any(|l| l.contains("records dropped by rate limit")));
+    let warnings = lines.iter().filter(|l| l.contains("bad datagram")).count();
+    assert!((1..=3).contains(&warnings), "{lines:?}");
+}
+
+//The dropped totals are capped at 1000 senders like the other metrics
+#[test]
+fn rate_limit_totals_cap_senders() {
+    let metrics_port = free_port();
+    let server = Server::start(
+        "limit_senders",
+        &format!("metrics_port = {metrics_port}\n[rate_limit]\nclient_records_per_sec = 1\nsummary_interval_ms = 100000\n"),
+    );
+    let mut log = server.connect();
+    for i in 0..1010 {
+        log_forward(&mut log, Record::new(&format!("sender{i}"), Severity::Info, "hello")).unwrap();
+    }
+    log_sync(&mut log).unwrap();
+
+    let body = http_get(metrics_port, "/metrics").1;
+    let series = body.lines().filter(|l| l.starts_with("log_component_records_dropped_total{")).count();
+    assert_eq!(series, 1001, "{body}");
+    assert!(body.contains("\nlog_component_records_dropped_total{sender=\"other\"} "), "{body}");
+}
+
+//push_back keeps every record but slows the client down to the limit
+#[test]
+fn rate_limit_pushes_back() {
+    let server = Server::start("limit_push_back", "[rate_limit]\nclient_records_per_sec = 20\nburst_secs = 0.1\npolicy = \"push_back\"\n");
+    let mut log = server.connect();
+    let start = Instant::now();
+    for i in 0..10 {
+        try_log_send(&mut log, &format!("record {i}")).unwrap();
+    }
+    log_sync(&mut log).unwrap();
+    //Two records fit in the bucket, the other eight wait 50ms each
+    assert!(start.elapsed() >= Duration::from_millis(350), "{:?}", start.elapsed());
+    assert_eq!(server.wait_for_lines(10).len(), 10);
+}
+
+//Within reorder_window_ms, records are written in Lamport order rather
+//than as they arrived
+#[test]
+fn reorders_by_logical_clock() {
+    let server = Server::start("reorder", "reorder_window_ms = 300\n");
+    let mut db = try_log_connect(&server.client_config("db.toml", "log_sender = \"db\"\nlogical_clock = \"lamport\"")).unwrap();
+    let mut web = try_log_connect(&server.client_config("web.toml", "log_sender = \"web\"\nlogical_clock = \"lamport\"")).unwrap();
+
+    //web answers a request from db, so its records come after db's
+    try_log_send(&mut db, "request sent").unwrap();
+    let request: Clock = log_clock(&db).unwrap().to_string().parse().unwrap();
+    log_clock_merge(&mut web, &request).unwrap();
+    try_log_send(&mut web, "request handled").unwrap();
+    log_flush(&mut web).unwrap();
+    //db's second record reaches the server after web's, with a lower clock
+    thread::sleep(Duration::from_millis(50));
+    let mut late = try_log_connect(&server.client_config("late.toml", "log_sender = \"db\"\nlogical_clock = \"lamport\"")).unwrap();
+    try_log_send(&mut late, "unrelated").unwrap();
+    log_flush(&mut late).unwrap();
+    log_flush(&mut db).unwrap();
+
+    let lines = server.wait_for_lines(3);
+    assert!(lines[0].ends_with("db: request sent lamport=1"), "{lines:?}");
+    assert!(lines[1].ends_with("db: unrelated lamport=1"), "{lines:?}");
+    assert!(lines[2].ends_with("web: request handled lamport=2"), "{lines:?}");
+}
+
+//Batched records arrive whole whichever codec is negotiated, and
+//uncompressed when the server allows none
+#[test]
+fn compressed_batches() {
+    for (server_codecs, client_codecs) in [("zstd,deflate", "zstd"), ("deflate", "zstd,deflate"), ("none", "zstd")] {
+        let server = Server::start("compress", &format!("compression = \"{server_codecs}\"\n"));
+        let config = format!("compression = \"{client_codecs}\"\nbatch_max_records = 5\nbatch_delay_ms = 10000");
+        let mut log = try_log_connect(&server.client_config("batch.toml", &config)).unwrap();
+        for i in 0..12 {
+            try_log_send(&mut log, &format!("record {i}")).unwrap();
+        }
+        log_flush(&mut log).unwrap();
+
+        let lines = server.wait_for_lines(12);
+        for (i, line) in lines.iter().enumerate() {
+            assert!(line.ends_with(&format!(": record {i}")), "{server_codecs}/{client_codecs}: {lines:?}");
+        }
+    }
+}
+
+//Sends an HTTP GET for `path`, returning the status line and the body
+fn http_get(port: u16, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+//metrics_port serves counters in the Prometheus text format and a health
+//check
+#[test]
+fn metrics_endpoint() {
+    let metrics_port = free_port();
+    let server = Server::start(
+        "metrics",
+        &format!("metrics_port = {metrics_port}\n[rate_limit]\nclient_records_per_sec = 1\nburst_secs = 3\n"),
+    );
+    let mut log = try_log_connect(&server.client_config("web.toml", "log_sender = \"web\"")).unwrap();
+    for i in 0..5 {
+        try_log_send(&mut log, &format!("record {i}")).unwrap();
+    }
+    log_sync(&mut log).unwrap();
+
+    let (status, body) = http_get(metrics_port, "/metrics");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let value = |name: &str| -> f64 {
+        let line = body.lines().find(|l| l.split(' ').next() == Some(name)).unwrap_or_else(|| panic!("no {name} in {body}"));
+        line.rsplit(' ').next().unwrap().parse().unwrap()
+    };
+    assert_eq!(value("log_component_connections"), 1.0);
+    assert_eq!(value("log_component_client_records_total{sender=\"web\"}"), 3.0);
+    assert_eq!(value("log_component_records_dropped_total{sender=\"web\"}"), 2.0);
+    assert!(value("log_component_received_bytes_total") > 0.0);
+    let file_size = std::fs::metadata(server.dir.join("systemlog.txt")).unwrap().len();
+    assert_eq!(value("log_component_log_file_bytes"), file_size as f64);
+    assert!(value("log_component_write_seconds_count") >= 3.0, "{body}");
+    assert!(body.contains("# TYPE log_component_write_seconds histogram"), "{body}");
+
+    assert_eq!(http_get(metrics_port, "/health"), ("HTTP/1.1 200 OK".to_string(), "ok\n".to_string()));
+    assert_eq!(http_get(metrics_port, "/nothing").0, "HTTP/1.1 404 Not Found");
+
+    try_log_disconnect(&mut log).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !http_get(metrics_port, "/metrics").1.contains("\nlog_component_connections 0\n") {
+        assert!(Instant::now() < deadline, "the disconnect was not counted");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    //metrics_port is checked like every other port
+    for bad in ["70000", "\"http\""] {
+        let stderr = start_error("metrics-port", &format!("metrics_port = {bad}\n"));
+        assert!(stderr.contains("\"metrics_port\" must be a port number between 0 and 65535"), "{stderr}");
+    }
+}
+
+//Only the first 1000 senders get series of their own, whatever clients
+//call themselves
+#[test]
+fn metrics_cap_senders() {
+    let metrics_port = free_port();
+    let server = Server::start("metrics_senders", &format!("metrics_port = {metrics_port}\n"));
+    let mut log = server.connect();
+    for i in 0..1010 {
+        log_forward(&mut log, Record::new(&format!("sender{i}"), Severity::Info, "hello")).unwrap();
+    }
+    log_sync(&mut log).unwrap();
+
+    let body = http_get(metrics_port, "/metrics").1;
+    let series: Vec<&str> = body.lines().filter(|l| l.starts_with("log_component_client_records_total{")).collect();
+    assert_eq!(series.len(), 1001, "{body}");
+    let other = series.iter().find(|l| l.starts_with("log_component_client_records_total{sender=\"other\"} ")).unwrap();
+    assert!(other.rsplit(' ').next().unwrap().parse::<u64>().unwrap() >= 10, "{other}");
+}
+
+//[[routes]] send each record to the sinks of every route it matches, and
+//to log_file when none does
+#[test]
+fn routes_records_to_sinks() {
+    let metrics_port = free_port();
+    let server = Server::start(
+        "routes",
+        &format!(
+            "metrics_port = {metrics_port}\n\
+             [sinks.web]\ntype = \"file\"\npath = \"web.log\"\noutput_format = \"logfmt\"\n\
+             [sinks.recent]\ntype = \"ring\"\nmax_bytes = 300\n\
+             [sinks.console]\ntype = \"stdout\"\n\
+             [[routes]]\nsender = \"web\"\nsinks = [\"web\"]\n\
+             [[routes]]\nlevel = \"error\"\nsinks = [\"recent\", \"log_file\", \"console\"]\n\
+             [[routes]]\nfields = {{ region = \"eu\" }}\nsinks = [\"recent\", \"web\"]\n"
+        ),
+    );
+    let mut web = try_log_connect(&server.client_config("web.toml", "log_sender = \"web\"")).unwrap();
+    let mut db = try_log_connect(&server.client_config("db.toml", "log_sender = \"db\"")).unwrap();
+    log_send_with(&mut web, Severity::Info, "w1", &[]).unwrap();
+    log_send_with(&mut web, Severity::Error, "w2", &[("region", "eu")]).unwrap();
+    log_sync(&mut web).unwrap();
+    log_send_with(&mut db, Severity::Info, "d1", &[]).unwrap();
+    log_send_with(&mut db, Severity::Info, "d2", &[("region", "eu")]).unwrap();
+    log_sync(&mut db).unwrap();
+
+    let messages = |lines: Vec<String>| -> Vec<String> {
+        let names = ["w1", "w2", "d1", "d2"];
+        lines.into_iter().filter_map(|l| names.into_iter().find(|n| l.contains(n)).map(str::to_string)).collect()
+    };
+    assert_eq!(messages(server.lines()), ["w2", "d1"]);
+    let web_log = std::fs::read_to_string(server.dir.join("web.log")).unwrap();
+    assert_eq!(messages(web_log.lines().map(str::to_string).collect()), ["w1", "w2", "d2"]);
+    assert!(web_log.lines().all(|l| l.starts_with("time=")), "{web_log}");
+    let (status, ring) = http_get(metrics_port, "/ring/recent");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(messages(ring.lines().map(str::to_string).collect()), ["w2", "d2"]);
+
+    //The ring keeps only the newest max_bytes
+    for i in 0..20 {
+        log_send_with(&mut db, Severity::Error, &format!("burst {i}"), &[]).unwrap();
+    }
+    log_sync(&mut db).unwrap();
+    let ring = http_get(metrics_port, "/ring/recent").1;
+    assert!(ring.len() <= 300 && ring.ends_with(": burst 19\n"), "{ring}");
+    assert!(!ring.contains("w2"), "{ring}");
+    assert_eq!(http_get(metrics_port, "/ring/nothing").0, "HTTP/1.1 404 Not Found");
+}
+
+//Applications get files of their own by authenticated name, or by the
+//start of their sender when they do not authenticate
+#[test]
+fn routes_applications_to_their_own_files() {
+    let server = Server::start(
+        "routes_apps",
+        "[auth_clients]\nbilling = \"one\"\npayroll = \"two\"\n\
+         [sinks.billing]\ntype = \"file\"\npath = \"billing.log\"\n\
+         [sinks.payroll]\ntype = \"file\"\npath = \"payroll.log\"\n\
+         [[routes]]\napp = \"billing\"\nsinks = [\"billing\"]\n\
+         [[routes]]\nsender = \"payroll*\"\nsinks = [\"payroll\"]\n",
+    );
+    let billing = server.client_config("billing.toml", "auth_app = \"billing\"\nauth_secret = \"one\"\nlog_sender = \"worker[12]\"");
+    let payroll = server.client_config("payroll.toml", "auth_app = \"payroll\"\nauth_secret = \"two\"\nlog_sender = \"payroll[34]\"");
+    let (mut billing, mut payroll) = (try_log_connect(&billing).unwrap(), try_log_connect(&payroll).unwrap());
+    try_log_send(&mut billing, "invoice paid").unwrap();
+    try_log_send(&mut payroll, "salary paid").unwrap();
+    log_sync(&mut billing).unwrap();
+    log_sync(&mut payroll).unwrap();
+
+    let read = |file: &str| std::fs::read_to_string(server.dir.join(file)).unwrap();
+    let (billing_log, payroll_log) = (read("billing.log"), read("payroll.log"));
+    assert!(billing_log.contains("worker[12]: invoice paid") && !billing_log.contains("salary"), "{billing_log}");
+    assert!(payroll_log.contains("payroll[34]: salary paid") && !payroll_log.contains("invoice"), "{payroll_log}");
+    assert!(!server.lines().iter().any(|l| l.contains(" paid")), "{:?}", server.lines());
+}
+
+//Ring sinks hold log lines, so like subscribe_port they stay on loopback
+//when clients must authenticate, unless opted in
+#[test]
+fn ring_sinks_stay_local_with_authentication() {
+    let ring = "[sinks.recent]\ntype = \"ring\"\n[[routes]]\nsinks = [\"recent\"]\n";
+    let config = format!("auth_secret = \"s3cret\"\nmetrics_ip = \"0.0.0.0\"\nmetrics_port = {}\n{ring}", free_port());
+    let stderr = start_error("ring-auth", &config);
+    assert!(stderr.contains("ring sinks are served on metrics_port"), "{stderr}");
+
+    let metrics_port = free_port();
+    let _server = Server::start(
+        "ring-open",
+        &format!("auth_secret = \"s3cret\"\nring_unauthenticated = true\nmetrics_ip = \"0.0.0.0\"\nmetrics_port = {metrics_port}\n{ring}"),
+    );
+    assert_eq!(http_get(metrics_port, "/ring/recent").0, "HTTP/1.1 200 OK");
+}
@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+use send_log::rotation::rotated_files;
+
+/* files - the log file and its rotated predecessors
+ *
+ * log_component renames a full log file to <log_file>.<UTC timestamp>,
+ * optionally gzipping it to <log_file>.<UTC timestamp>.gz; send_log's
+ * rotation.rs finds them for both. Gzipped files are recognised by their
+ * contents rather than their name, so a file compressed by hand reads
+ * just as well.
+ * */
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+//Every file belonging to `base`, oldest first and `base` itself last
+pub fn history(base: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = rotated_files(base)?;
+    if base.exists() {
+        files.push(base.to_path_buf());
+    }
+    Ok(files)
+}
+
+//Opens a log file for reading line by line, decompressing if needed
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+//Calls `each` with every line of `reader`, without its newline, until it
+//returns false. Invalid UTF-8 is replaced rather than ending the search.
+pub fn for_each_line(mut reader: impl BufRead, mut each: impl FnMut(&str) -> bool) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&buffer);
+        if !each(text.trim_end_matches(['\n', '\r'])) {
+            return Ok(());
+        }
+    }
+}
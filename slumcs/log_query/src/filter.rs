@@ -0,0 +1,86 @@
+use regex::Regex;
+use send_log::protocol::Severity;
+
+use crate::parse::{parse_timestamp, Line};
+
+/* filter - which lines log_query prints
+ *
+ * Every condition given must hold. When tailing, log_component applies
+ * the ones it can before sending anything (see subscription_request). A
+ * line that lacks what a condition looks at (say, a line that did not
+ * parse and so has no time) fails it.
+ * */
+
+#[derive(Default)]
+pub struct Filter {
+    //Inclusive bounds, in milliseconds since the Unix epoch
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub sender: Option<String>,
+    //Lowest severity shown
+    pub level: Option<Severity>,
+    //Looked for in the message, as plain text or a regex
+    pub contains: Option<String>,
+    pub pattern: Option<Regex>,
+}
+
+impl Filter {
+    pub fn matches(&self, line: &Line) -> bool {
+        let time_ok = match (self.since, self.until, line.time) {
+            (None, None, _) => true,
+            (_, _, None) => false,
+            (since, until, Some(t)) => since.is_none_or(|s| t >= s) && until.is_none_or(|u| t <= u),
+        };
+        time_ok
+            && self.sender.as_ref().is_none_or(|s| line.sender.as_ref() == Some(s))
+            && self.level.is_none_or(|l| line.severity.is_some_and(|s| s >= l))
+            && self.contains.as_ref().is_none_or(|c| line.message.contains(c.as_str()))
+            && self.pattern.as_ref().is_none_or(|p| p.is_match(&line.message))
+    }
+
+    //The filter line a tail subscription opens with (see log_component's
+    //subscribe.rs), so the server only sends what these conditions could
+    //pass. The time range and --grep are still checked here.
+    pub fn subscription_request(&self) -> String {
+        let mut pairs = Vec::new();
+        if let Some(sender) = &self.sender {
+            pairs.push(format!("sender={}", quote(sender)));
+        }
+        if let Some(level) = self.level {
+            pairs.push(format!("level={}", level.as_str().to_ascii_lowercase()));
+        }
+        //The request is one line, so a multi-line text is left to us
+        if let Some(contains) = self.contains.as_ref().filter(|c| !c.contains('\n')) {
+            pairs.push(format!("contains={}", quote(contains)));
+        }
+        pairs.join(" ")
+    }
+}
+
+fn quote(value: &str) -> String {
+    if !value.is_empty() && !value.contains([' ', '"', '\\']) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+//Parses --since/--until: a timestamp such as 2024-01-01T12:00:00Z, a
+//date (midnight UTC), or a time ago such as 90s, 15m, 2h or 7d
+pub fn parse_time(arg: &str, now: u64) -> Result<u64, String> {
+    let invalid = || format!("invalid time \"{arg}\", expected e.g. 2024-01-01T12:00:00Z, 2024-01-01 or 15m");
+    if let Some(t) = parse_timestamp(arg) {
+        return Ok(t);
+    }
+    if let Some(t) = parse_timestamp(&format!("{arg}T00:00:00Z")) {
+        return Ok(t);
+    }
+    let unit = match arg.chars().last() {
+        Some('s') => 1_000,
+        Some('m') => 60_000,
+        Some('h') => 3_600_000,
+        Some('d') => 86_400_000,
+        _ => return Err(invalid()),
+    };
+    let count: u64 = arg[..arg.len() - 1].parse().map_err(|_| invalid())?;
+    Ok(now.saturating_sub(count.saturating_mul(unit)))
+}
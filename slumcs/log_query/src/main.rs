@@ -0,0 +1,169 @@
+/* log_query - reads the central log
+ *
+ *  log_query [--config <path>] search [filters] [file...]
+ *  log_query [--config <path>] tail [filters]
+ *
+ * search prints the matching lines of the given files, or without any,
+ * of log_file and its rotated predecessors from oldest to newest,
+ * decompressing gzipped ones (see files.rs). tail subscribes at
+ * log_component's subscribe_port, passing on the filters the server can
+ * apply, and prints matching lines as they are written, until interrupted.
+ *
+ * Filters (see filter.rs):
+ *
+ *  --since <time>    records at or after this time
+ *  --until <time>    records at or before this time
+ *  --sender <name>   records from this sender only
+ *  --level <level>   records of this severity or worse
+ *  --contains <text> records whose message contains this text
+ *  --grep <regex>    records whose message matches
+ *
+ * Lines are printed exactly as log_component wrote them, in plain, json
+ * or logfmt (see parse.rs). The configuration is found the same way
+ * log_component finds it, and is only read when log_file or the
+ * subscription address is needed.
+ * */
+
+use std::env;
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use config::Config;
+use regex::Regex;
+use send_log::protocol::now_millis;
+use send_log::settings;
+
+mod files;
+mod filter;
+mod parse;
+
+use filter::Filter;
+
+const USAGE: &str = "Usage: log_query [--config <path>] search [filters] [file...]
+       log_query [--config <path>] tail [filters]
+Filters: --since <time> --until <time> --sender <name> --level <level> --contains <text> --grep <regex>";
+
+fn fatal(msg: impl Display) -> ! {
+    eprintln!("log_query: {}", msg);
+    exit(1);
+}
+
+//Splits the filter options from the remaining arguments
+fn parse_filters(args: Vec<String>) -> Result<(Filter, Vec<String>), String> {
+    let mut filter = Filter::default();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            rest.push(arg);
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+        match arg.as_str() {
+            "--since" => filter.since = Some(filter::parse_time(&value, now_millis())?),
+            "--until" => filter.until = Some(filter::parse_time(&value, now_millis())?),
+            "--sender" => filter.sender = Some(value),
+            "--level" => filter.level = Some(value.parse()?),
+            "--contains" => filter.contains = Some(value),
+            "--grep" => filter.pattern = Some(Regex::new(&value).map_err(|e| format!("invalid --grep: {e}"))?),
+            _ => return Err(format!("unknown option \"{arg}\"\n{USAGE}")),
+        }
+    }
+    Ok((filter, rest))
+}
+
+fn load_config(explicit: Option<&str>) -> Config {
+    let path = settings::locate(explicit).unwrap_or_else(|e| fatal(e));
+    settings::load(&path).unwrap_or_else(|e| fatal(e))
+}
+
+//Prints the line if it passes, returning false once stdout is gone
+fn emit(out: &mut impl Write, filter: &Filter, text: &str) -> bool {
+    if !filter.matches(&parse::parse(text)) {
+        return true;
+    }
+    writeln!(out, "{}", text).is_ok()
+}
+
+fn search(filter: &Filter, paths: Vec<String>, explicit: Option<&str>) {
+    let paths: Vec<PathBuf> = if paths.is_empty() {
+        let log_file = load_config(explicit)
+            .get_string("log_file")
+            .unwrap_or_else(|_| fatal("\"log_file\" is missing from the configuration"));
+        files::history(Path::new(&log_file)).unwrap_or_else(|e| fatal(format!("cannot list {}: {}", log_file, e)))
+    } else {
+        paths.into_iter().map(PathBuf::from).collect()
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for path in paths {
+        let reader = files::open(&path).unwrap_or_else(|e| fatal(format!("cannot read {}: {}", path.display(), e)));
+        let mut open = true;
+        let result = files::for_each_line(reader, |text| {
+            open = emit(&mut out, filter, text);
+            open
+        });
+        if let Err(e) = result {
+            fatal(format!("error reading {}: {}", path.display(), e));
+        }
+        if !open {
+            return;
+        }
+    }
+}
+
+fn tail(filter: &Filter, explicit: Option<&str>) {
+    let settings = load_config(explicit);
+    let port = settings
+        .get_int("subscribe_port")
+        .unwrap_or_else(|_| fatal("\"subscribe_port\" is not configured, so there is nothing to tail"));
+    let ip = settings
+        .get_string("subscribe_ip")
+        .or_else(|_| settings.get_string("log_ip"))
+        .unwrap_or_else(|_| fatal("neither \"subscribe_ip\" nor \"log_ip\" is configured"));
+    let mut stream = TcpStream::connect(format!("{}:{}", ip, port))
+        .unwrap_or_else(|e| fatal(format!("cannot subscribe at {}:{}: {}", ip, port, e)));
+    if let Err(e) = writeln!(stream, "{}", filter.subscription_request()) {
+        fatal(format!("cannot subscribe at {}:{}: {}", ip, port, e));
+    }
+    let mut reader = BufReader::new(stream);
+    let mut answer = String::new();
+    match reader.read_line(&mut answer) {
+        Ok(_) if answer.trim_end() == "ok" => {}
+        Ok(_) => fatal(format!("subscription refused: {}", answer.trim_end().trim_start_matches("error: "))),
+        Err(e) => fatal(format!("subscription failed: {}", e)),
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut open = true;
+    let result = files::for_each_line(reader, |text| {
+        open = emit(&mut out, filter, text) && out.flush().is_ok();
+        open
+    });
+    match result {
+        Ok(()) if !open => {}
+        Err(e) => fatal(format!("subscription failed: {}", e)),
+        Ok(()) => fatal("log_component closed the subscription"),
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let explicit = settings::take_config_arg(&mut args).unwrap_or_else(|e| fatal(e));
+    if args.is_empty() {
+        fatal(USAGE);
+    }
+    let command = args.remove(0);
+    let (filter, rest) = parse_filters(args).unwrap_or_else(|e| fatal(e));
+    match command.as_str() {
+        "search" => search(&filter, rest, explicit.as_deref()),
+        "tail" if rest.is_empty() => tail(&filter, explicit.as_deref()),
+        "tail" => fatal(format!("tail takes no files\n{USAGE}")),
+        _ => fatal(format!("unknown command \"{command}\"\n{USAGE}")),
+    }
+}
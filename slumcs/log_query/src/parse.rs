@@ -0,0 +1,124 @@
+use std::str::FromStr;
+
+use send_log::protocol::Severity;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/* parse - reads lines of the log file back
+ *
+ * log_component writes one of three formats (see its output.rs). Each
+ * line is told apart by how it starts, so files written before and
+ * after a change of output_format can be searched together:
+ *
+ *  {...          json
+ *  time=...      logfmt
+ *  anything else plain
+ *
+ * Only what log_query filters on is taken out. A line that does not
+ * parse keeps just its text as the message, so --grep still finds it.
+ * */
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Line {
+    //Milliseconds since the Unix epoch, from the record's own timestamp
+    pub time: Option<u64>,
+    pub severity: Option<Severity>,
+    pub sender: Option<String>,
+    pub message: String,
+}
+
+pub fn parse(text: &str) -> Line {
+    let parsed = if text.starts_with('{') {
+        json(text)
+    } else if text.starts_with("time=") {
+        logfmt(text)
+    } else {
+        plain(text)
+    };
+    parsed.unwrap_or_else(|| Line { message: text.to_string(), ..Line::default() })
+}
+
+//2024-01-01T12:00:00.000Z, as every format writes it
+pub fn parse_timestamp(text: &str) -> Option<u64> {
+    let time = OffsetDateTime::parse(text, &Rfc3339).ok()?;
+    u64::try_from(time.unix_timestamp_nanos() / 1_000_000).ok()
+}
+
+//2024-01-01T12:00:00.000Z INFO  sender: message key=value
+//
+//Fields and the identity follow the message with nothing to mark where
+//it ends, so they are left as part of it.
+fn plain(text: &str) -> Option<Line> {
+    let (time, rest) = text.split_once(' ')?;
+    let (severity, rest) = rest.trim_start().split_once(' ')?;
+    let (sender, message) = rest.trim_start().split_once(": ")?;
+    Some(Line {
+        time: Some(parse_timestamp(time)?),
+        severity: Some(Severity::from_str(severity).ok()?),
+        sender: Some(sender.to_string()),
+        message: message.to_string(),
+    })
+}
+
+fn json(text: &str) -> Option<Line> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    let get = |key: &str| value.get(key).and_then(|v| v.as_str());
+    Some(Line {
+        time: get("time").and_then(parse_timestamp),
+        severity: get("severity").and_then(|s| s.parse().ok()),
+        sender: get("sender").map(str::to_string),
+        message: get("message").unwrap_or_default().to_string(),
+    })
+}
+
+fn logfmt(text: &str) -> Option<Line> {
+    let mut line = Line::default();
+    for (key, value) in logfmt_pairs(text)? {
+        match key.as_str() {
+            "time" => line.time = parse_timestamp(&value),
+            "level" => line.severity = value.parse().ok(),
+            "sender" => line.sender = Some(value),
+            "msg" => line.message = value,
+            _ => {}
+        }
+    }
+    Some(line)
+}
+
+//Splits key=value and key="quoted \"value\"" pairs, undoing the escapes
+//output.rs applies to quoted values
+fn logfmt_pairs(text: &str) -> Option<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|c| *c == ' ').is_some() {}
+        if chars.peek().is_none() {
+            return Some(pairs);
+        }
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => match chars.next()? {
+                        'n' => value.push('\n'),
+                        'r' => value.push('\r'),
+                        't' => value.push('\t'),
+                        'u' => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                        }
+                        c => value.push(c),
+                    },
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ' ') {
+                value.push(c);
+            }
+        }
+        pairs.push((key, value));
+    }
+}
@@ -0,0 +1,183 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+//A directory holding a log file, its rotated predecessors in every format
+//log_component writes, and a config pointing at it
+struct History {
+    dir: PathBuf,
+}
+
+const OLDEST: &str = "\
+2024-01-01T10:00:00.000Z INFO  billing: invoice 17 sent
+2024-01-01T11:00:00.000Z ERROR billing: invoice 18 failed code=7
+2024-01-01T12:00:00.000Z DEBUG web: GET /index.html
+";
+
+const MIDDLE: &str = "\
+time=2024-01-02T10:00:00.000Z received=2024-01-02T10:00:00.001Z level=warn sender=web peer=127.0.0.1:5000 msg=\"slow \\\"GET\\\" /report\" ms=900
+time=2024-01-02T11:00:00.000Z received=2024-01-02T11:00:00.001Z level=info sender=billing msg=\"invoice 19 sent\"
+";
+
+const NEWEST: &str = "\
+{\"time\":\"2024-01-03T10:00:00.000Z\",\"received\":\"2024-01-03T10:00:00.001Z\",\"severity\":\"ERROR\",\"sender\":\"web\",\"peer\":null,\"identity\":null,\"message\":\"upstream down\",\"fields\":{}}
+{\"time\":\"2024-01-03T11:00:00.000Z\",\"received\":\"2024-01-03T11:00:00.001Z\",\"severity\":\"INFO\",\"sender\":\"billing\",\"peer\":null,\"identity\":\"billing\",\"message\":\"invoice 20 sent\",\"fields\":{\"id\":\"20\"}}
+";
+
+impl History {
+    fn new(name: &str) -> History {
+        let dir = std::env::temp_dir().join(format!("log_query_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(OLDEST.as_bytes()).unwrap();
+        std::fs::write(dir.join("systemlog.txt.20240101T235959000Z.gz"), gz.finish().unwrap()).unwrap();
+        std::fs::write(dir.join("systemlog.txt.20240102T235959000Z"), MIDDLE).unwrap();
+        std::fs::write(dir.join("systemlog.txt"), NEWEST).unwrap();
+        std::fs::write(dir.join("systemlog.txt.bak"), "2024-01-01T00:00:00.000Z INFO  bak: not part of the log\n").unwrap();
+
+        let config = format!("log_ip = \"127.0.0.1\"\nlog_port = 1\nlog_file = {:?}\n", dir.join("systemlog.txt").to_str().unwrap());
+        std::fs::write(dir.join("config.toml"), config).unwrap();
+        History { dir }
+    }
+
+    fn config(&self) -> String {
+        self.dir.join("config.toml").to_str().unwrap().to_string()
+    }
+
+    //Runs a search over the whole history, returning the printed lines
+    fn search(&self, filters: &[&str]) -> Vec<String> {
+        let output = run(&[&["--config", &self.config(), "search"], filters].concat());
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap().lines().map(str::to_string).collect()
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_log_query")).args(args).output().unwrap()
+}
+
+#[test]
+fn searches_rotated_and_compressed_files_in_order() {
+    let history = History::new("order");
+    let all = history.search(&[]);
+    let expected: Vec<&str> = [OLDEST, MIDDLE, NEWEST].iter().flat_map(|f| f.lines()).collect();
+    assert_eq!(all, expected);
+}
+
+#[test]
+fn filters_every_format() {
+    let history = History::new("filters");
+
+    let billing = history.search(&["--sender", "billing"]);
+    assert_eq!(billing.len(), 4, "{billing:?}");
+    assert!(billing[2].contains("invoice 19 sent") && billing[3].contains("invoice 20 sent"));
+
+    let errors = history.search(&["--level", "warn"]);
+    assert_eq!(errors.len(), 3, "{errors:?}");
+    assert!(errors[0].contains("invoice 18 failed"));
+    assert!(errors[1].contains("level=warn"));
+    assert!(errors[2].contains("upstream down"));
+
+    let day_two = history.search(&["--since", "2024-01-02", "--until", "2024-01-02T23:59:59Z"]);
+    assert_eq!(day_two, MIDDLE.lines().collect::<Vec<_>>());
+
+    //The logfmt message is matched unescaped
+    let quoted = history.search(&["--grep", "slow \"GET\""]);
+    assert_eq!(quoted.len(), 1, "{quoted:?}");
+
+    let combined = history.search(&["--sender", "billing", "--grep", r"invoice \d+ sent", "--since", "2024-01-01T12:00:00Z"]);
+    assert_eq!(combined.len(), 2, "{combined:?}");
+}
+
+#[test]
+fn searches_named_files() {
+    let history = History::new("named");
+    let gz = history.dir.join("systemlog.txt.20240101T235959000Z.gz");
+    let output = run(&["search", "--grep", "invoice", gz.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn reports_bad_arguments() {
+    for (args, expected) in [
+        (&["search", "--level", "loud"][..], "unknown severity \"loud\""),
+        (&["search", "--since", "yesterday"], "invalid time \"yesterday\""),
+        (&["search", "--grep", "("], "invalid --grep"),
+        (&["search", "--color", "x"], "unknown option \"--color\""),
+        (&["frobnicate"], "unknown command \"frobnicate\""),
+        (&["search", "/nonexistent/systemlog.txt"], "cannot read /nonexistent/systemlog.txt"),
+    ] {
+        let output = run(args);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.starts_with("log_query: ") && stderr.contains(expected), "{args:?}: {stderr}");
+    }
+}
+
+//tail prints what the subscription sends that passes the filters
+#[test]
+fn tails_subscription() {
+    let history = History::new("tail");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::fs::write(history.dir.join("tail.toml"), format!("log_ip = \"127.0.0.1\"\nsubscribe_port = {port}\n")).unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request).unwrap();
+        assert_eq!(request, "sender=billing level=error contains=\"invoice 1\"\n");
+        stream.write_all(b"ok\n").unwrap();
+        stream.write_all(OLDEST.as_bytes()).unwrap();
+        stream.write_all(NEWEST.as_bytes()).unwrap();
+    });
+    let mut child = Command::new(env!("CARGO_BIN_EXE_log_query"))
+        .args(["--config", history.dir.join("tail.toml").to_str().unwrap(), "tail"])
+        .args(["--sender", "billing", "--level", "error", "--contains", "invoice 1"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    server.join().unwrap();
+
+    let lines: Vec<String> = BufReader::new(child.stdout.take().unwrap()).lines().map(Result::unwrap).collect();
+    //The stand-in server ignores the filter, but log_query applies it too
+    assert_eq!(lines.len(), 1, "{lines:?}");
+    assert!(lines[0].contains("invoice 18 failed"));
+
+    //The server hanging up ends the tail with an error
+    let output = child.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("log_component closed the subscription"));
+}
+
+#[test]
+fn reports_refused_subscription() {
+    let history = History::new("refused");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::fs::write(history.dir.join("tail.toml"), format!("log_ip = \"127.0.0.1\"\nsubscribe_port = {port}\n")).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        BufReader::new(&stream).read_line(&mut String::new()).unwrap();
+        stream.write_all(b"error: unknown filter key \"x\"\n").unwrap();
+    });
+    let output = run(&["--config", history.dir.join("tail.toml").to_str().unwrap(), "tail"]);
+    server.join().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("subscription refused: unknown filter key \"x\""), "{stderr}");
+}
@@ -0,0 +1,41 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/* auth - proving to log_component which application is connecting
+ *
+ * With auth_secret set in config.toml the Log runs a challenge/response
+ * handshake (see protocol.rs) on every connection before sending records:
+ *
+ *  auth_secret  secret shared with the server for this application
+ *  auth_app     application name to authenticate as (program name)
+ *
+ * The server answers hello with a random nonce and the client returns
+ * HMAC-SHA256(secret, nonce || app). The secret itself never crosses the
+ * wire and a recorded handshake cannot be replayed against a new nonce.
+ * sign and verify are public so log_component checks the same mac.
+ * */
+
+pub const NONCE_LEN: usize = 32;
+
+//What the client introduces itself with; without a secret it can only
+//connect to servers that do not check credentials
+pub(crate) struct Credentials {
+    pub(crate) app: String,
+    pub(crate) secret: Option<String>,
+}
+
+fn mac_for(secret: &[u8], nonce: &[u8], app: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(nonce);
+    mac.update(app.as_bytes());
+    mac
+}
+
+pub fn sign(secret: &[u8], nonce: &[u8], app: &str) -> Vec<u8> {
+    mac_for(secret, nonce, app).finalize().into_bytes().to_vec()
+}
+
+//Compares in constant time
+pub fn verify(secret: &[u8], nonce: &[u8], app: &str, tag: &[u8]) -> bool {
+    mac_for(secret, nonce, app).verify(tag).is_ok()
+}
@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/* buffer - holds encoded frames while the logging server is unreachable
+ *
+ * Frames are kept in the order they were sent. By default they live in a
+ * VecDeque capped at a number of records; with a spool file configured
+ * they are appended to that file instead, capped at a number of bytes,
+ * so they also survive a restart of the application. Since every frame
+ * starts with its own length, the spool file needs no extra bookkeeping.
+ * */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    //Discard the oldest queued record to make room for the new one
+    DropOldest,
+    //Keep what is queued and discard the new record
+    DropNewest,
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DropPolicy, String> {
+        match s {
+            "drop_oldest" => Ok(DropPolicy::DropOldest),
+            "drop_newest" => Ok(DropPolicy::DropNewest),
+            _ => Err(format!("expected \"drop_oldest\" or \"drop_newest\", got \"{s}\"")),
+        }
+    }
+}
+
+pub(crate) struct OfflineBuffer {
+    backing: Backing,
+    policy: DropPolicy,
+    dropped: u64,
+}
+
+enum Backing {
+    Memory { frames: VecDeque<Vec<u8>>, max_records: usize },
+    Spool(Spool),
+}
+
+impl OfflineBuffer {
+    pub(crate) fn memory(max_records: usize, policy: DropPolicy) -> OfflineBuffer {
+        OfflineBuffer {
+            backing: Backing::Memory { frames: VecDeque::new(), max_records },
+            policy,
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn spool(path: &Path, max_bytes: u64, policy: DropPolicy) -> io::Result<OfflineBuffer> {
+        Ok(OfflineBuffer {
+            backing: Backing::Spool(Spool::open(path, max_bytes)?),
+            policy,
+            dropped: 0,
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        match &self.backing {
+            Backing::Memory { frames, .. } => frames.is_empty(),
+            Backing::Spool(spool) => spool.head == spool.tail,
+        }
+    }
+
+    //Queues a frame, applying the drop policy if the buffer is full
+    pub(crate) fn push(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        match &mut self.backing {
+            Backing::Memory { frames, max_records } => {
+                if frames.len() >= *max_records {
+                    self.dropped += 1;
+                    match self.policy {
+                        DropPolicy::DropNewest => return Ok(()),
+                        DropPolicy::DropOldest => {
+                            frames.pop_front();
+                        }
+                    }
+                }
+                frames.push_back(frame);
+            }
+            Backing::Spool(spool) => {
+                while spool.tail - spool.head + frame.len() as u64 > spool.max_bytes {
+                    if self.policy == DropPolicy::DropNewest || spool.head == spool.tail {
+                        self.dropped += 1;
+                        return Ok(());
+                    }
+                    spool.skip_front()?;
+                    self.dropped += 1;
+                }
+                spool.append(&frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    //The oldest frames, as many as fit in `max_records` and `max_bytes`
+    //but at least one, without removing them
+    pub(crate) fn front_frames(&mut self, max_records: usize, max_bytes: usize) -> io::Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::new();
+        let mut bytes = 0;
+        let fits = |frames: &Vec<Vec<u8>>, bytes: usize, len: usize| {
+            frames.is_empty() || (frames.len() < max_records && bytes + len <= max_bytes)
+        };
+        match &mut self.backing {
+            Backing::Memory { frames: queued, .. } => {
+                for frame in queued.iter() {
+                    if !fits(&frames, bytes, frame.len()) {
+                        break;
+                    }
+                    bytes += frame.len();
+                    frames.push(frame.clone());
+                }
+            }
+            Backing::Spool(spool) => {
+                let mut offset = spool.head;
+                while let Some(frame) = spool.frame_at(offset)? {
+                    if !fits(&frames, bytes, frame.len()) {
+                        break;
+                    }
+                    offset += frame.len() as u64;
+                    bytes += frame.len();
+                    frames.push(frame);
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    pub(crate) fn pop_front(&mut self) -> io::Result<()> {
+        match &mut self.backing {
+            Backing::Memory { frames, .. } => {
+                frames.pop_front();
+                Ok(())
+            }
+            Backing::Spool(spool) => spool.skip_front(),
+        }
+    }
+
+    pub(crate) fn note_dropped(&mut self, count: u64) {
+        self.dropped += count;
+    }
+
+    //Returns how many records were discarded since the last call
+    pub(crate) fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+//An append-only file of frames. `head` is the offset of the oldest frame
+//not yet sent; once everything has been sent the file is truncated.
+struct Spool {
+    path: PathBuf,
+    file: File,
+    head: u64,
+    tail: u64,
+    max_bytes: u64,
+}
+
+impl Spool {
+    fn open(path: &Path, max_bytes: u64) -> io::Result<Spool> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let tail = file.metadata()?.len();
+        Ok(Spool { path: path.to_path_buf(), file, head: 0, tail, max_bytes })
+    }
+
+    fn append(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.tail))?;
+        self.file.write_all(frame)?;
+        self.tail += frame.len() as u64;
+        Ok(())
+    }
+
+    fn front_len(&mut self) -> io::Result<u64> {
+        self.len_at(self.head)
+    }
+
+    fn len_at(&mut self, offset: u64) -> io::Result<u64> {
+        let mut prefix = [0; 4];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut prefix)?;
+        Ok(4 + u32::from_be_bytes(prefix) as u64)
+    }
+
+    //The frame starting at `offset`, None past the last one
+    fn frame_at(&mut self, offset: u64) -> io::Result<Option<Vec<u8>>> {
+        if offset >= self.tail {
+            return Ok(None);
+        }
+        let len = self.len_at(offset)?;
+        if offset + len > self.tail {
+            //A frame cut short by a crash while it was being appended
+            if offset == self.head {
+                self.reset()?;
+            }
+            return Ok(None);
+        }
+        let mut frame = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut frame)?;
+        Ok(Some(frame))
+    }
+
+    fn skip_front(&mut self) -> io::Result<()> {
+        if self.head == self.tail {
+            return Ok(());
+        }
+        self.head = (self.head + self.front_len()?).min(self.tail);
+        if self.head == self.tail {
+            self.reset()?;
+        } else if self.head > self.max_bytes {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.head = 0;
+        self.tail = 0;
+        Ok(())
+    }
+
+    //Moves the unsent frames to the start of the file so dropped and
+    //already-sent frames do not keep growing it
+    fn compact(&mut self) -> io::Result<()> {
+        let mut rest = vec![];
+        self.file.seek(SeekFrom::Start(self.head))?;
+        (&mut self.file).take(self.tail - self.head).read_to_end(&mut rest)?;
+
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, &rest)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.head = 0;
+        self.tail = rest.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::protocol::Record;
+
+/* clock - logical clocks for ordering records across processes
+ *
+ * Wall clocks on different machines disagree, so records from senders
+ * that talk to each other can land in the log in an order that could not
+ * have happened. With logical_clock set in config.toml, every record a Log
+ * sends carries a logical timestamp as fields:
+ *
+ *  lamport  a Lamport counter: higher than that of every record the
+ *           sender knows happened before this one
+ *  vclock   with logical_clock = "vector" only, a vector clock listing
+ *           the events seen from each node, e.g. "web#1:4,db#1:7"
+ *
+ * A Log is one node. Its id is clock_id from config.toml, or else the
+ * sender followed by # and a number unique to each Log in the process, so
+ * several Logs sharing a config still count separately.
+ *
+ * For the clocks to mean anything, processes pass them along with their
+ * own messages: the sender attaches log_clock (as a string, see Display),
+ * the receiver parses it and hands it to log_clock_merge. Records sent
+ * after the merge are then ordered after everything the sender had logged
+ * before it sent the message.
+ *
+ * log_component can hold records back for a short window and write them
+ * in Lamport order; see its reorder.rs.
+ * */
+
+//Which clocks records carry, picked with logical_clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockKind {
+    Lamport,
+    Vector,
+}
+
+impl FromStr for ClockKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ClockKind, String> {
+        match s {
+            "lamport" => Ok(ClockKind::Lamport),
+            "vector" => Ok(ClockKind::Vector),
+            _ => Err(format!("unknown logical_clock \"{s}\", expected lamport or vector")),
+        }
+    }
+}
+
+//A logical timestamp. Written as the Lamport counter, followed by ; and
+//the vector clock when there is one: "12" or "12;web#1:4,db#1:7".
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Clock {
+    pub lamport: u64,
+    //Events seen per node id; empty with Lamport clocks only
+    pub vector: BTreeMap<String, u64>,
+}
+
+impl Clock {
+    //Takes in what another node knew: the larger of the two counters,
+    //node by node
+    pub fn merge(&mut self, other: &Clock) {
+        self.lamport = self.lamport.max(other.lamport);
+        for (node, &count) in &other.vector {
+            let ours = self.vector.entry(node.clone()).or_insert(0);
+            *ours = (*ours).max(count);
+        }
+    }
+
+    //True if the event stamped `self` happened before the one stamped
+    //`other`. Needs vector clocks: Lamport counters alone cannot tell.
+    pub fn happened_before(&self, other: &Clock) -> bool {
+        self.vector != other.vector
+            && self.vector.iter().all(|(node, &count)| other.vector.get(node).is_some_and(|&c| count <= c))
+    }
+
+    //The clock a record carries, if any
+    pub fn of(record: &Record) -> Option<Clock> {
+        let lamport = record.field("lamport")?.parse().ok()?;
+        let vector = match record.field("vclock") {
+            Some(v) => parse_vector(v).ok()?,
+            None => BTreeMap::new(),
+        };
+        Some(Clock { lamport, vector })
+    }
+
+    fn vector_string(&self) -> String {
+        let nodes: Vec<String> = self.vector.iter().map(|(node, count)| format!("{node}:{count}")).collect();
+        nodes.join(",")
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.lamport)?;
+        if !self.vector.is_empty() {
+            write!(f, ";{}", self.vector_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Clock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Clock, String> {
+        let (lamport, vector) = match s.split_once(';') {
+            Some((lamport, vector)) => (lamport, parse_vector(vector)?),
+            None => (s, BTreeMap::new()),
+        };
+        let lamport = lamport.trim().parse().map_err(|_| format!("invalid clock \"{s}\""))?;
+        Ok(Clock { lamport, vector })
+    }
+}
+
+//"node:count,node:count"; node ids may contain : but not , or ;
+fn parse_vector(s: &str) -> Result<BTreeMap<String, u64>, String> {
+    let mut vector = BTreeMap::new();
+    for part in s.split(',').filter(|p| !p.is_empty()) {
+        let count = part.rsplit_once(':').and_then(|(node, count)| Some((node, count.parse().ok()?)));
+        let Some((node, count)) = count else {
+            return Err(format!("invalid vector clock entry \"{part}\""));
+        };
+        vector.insert(node.to_string(), count);
+    }
+    Ok(vector)
+}
+
+//Numbers the Logs of this process for their default node ids
+static NODES: AtomicU64 = AtomicU64::new(1);
+
+//The clock kept by one Log
+pub(crate) struct LocalClock {
+    kind: ClockKind,
+    id: String,
+    clock: Clock,
+}
+
+impl LocalClock {
+    //`id` is clock_id, if set
+    pub(crate) fn new(kind: ClockKind, id: Option<String>, sender: &str) -> Result<LocalClock, String> {
+        let id = id.unwrap_or_else(|| format!("{sender}#{}", NODES.fetch_add(1, Ordering::Relaxed)));
+        if id.is_empty() || id.contains([',', ';']) {
+            return Err(format!("\"{id}\" cannot be a node id: it must not be empty or contain , or ;"));
+        }
+        Ok(LocalClock { kind, id, clock: Clock::default() })
+    }
+
+    //Counts one more event and returns the fields that stamp it
+    pub(crate) fn tick(&mut self) -> Vec<(String, String)> {
+        self.clock.lamport += 1;
+        let mut fields = vec![("lamport".to_string(), self.clock.lamport.to_string())];
+        if self.kind == ClockKind::Vector {
+            *self.clock.vector.entry(self.id.clone()).or_insert(0) += 1;
+            fields.push(("vclock".to_string(), self.clock.vector_string()));
+        }
+        fields
+    }
+
+    pub(crate) fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub(crate) fn merge(&mut self, other: &Clock) {
+        self.clock.merge(other);
+        //Lamport-only clocks keep no vector, even when merging with one
+        if self.kind == ClockKind::Lamport {
+            self.clock.vector.clear();
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use crate::protocol::MAX_FRAME_LEN;
+
+/* compress - codecs for batches of records
+ *
+ * A client with compression set in config.toml offers its codecs, in
+ * order of preference, right after connecting; log_component answers
+ * with the first one it also allows, or with none. From then on the
+ * client sends its records in batch frames compressed with that codec
+ * (see protocol.rs):
+ *
+ *  zstd     better ratio for the same speed
+ *  deflate  for servers built without zstd in mind
+ *
+ * A batch never unpacks to more than MAX_FRAME_LEN bytes, so a hostile
+ * batch cannot make the server allocate without bound.
+ * */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Deflate,
+}
+
+impl Codec {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+            Codec::Deflate => "deflate",
+        }
+    }
+
+    pub(crate) fn to_wire(self) -> u8 {
+        match self {
+            Codec::Zstd => 1,
+            Codec::Deflate => 2,
+        }
+    }
+
+    pub(crate) fn from_wire(value: u8) -> Option<Codec> {
+        match value {
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Zstd => zstd::stream::encode_all(data, 0),
+            Codec::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let limit = MAX_FRAME_LEN as u64 + 1;
+        match self {
+            Codec::Zstd => zstd::stream::Decoder::new(data)?.take(limit).read_to_end(&mut out)?,
+            Codec::Deflate => DeflateDecoder::new(data).take(limit).read_to_end(&mut out)?,
+        };
+        if out.len() > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "batch unpacks to more than MAX_FRAME_LEN bytes"));
+        }
+        Ok(out)
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Codec, String> {
+        match s.trim() {
+            "zstd" => Ok(Codec::Zstd),
+            "deflate" => Ok(Codec::Deflate),
+            _ => Err(format!("unknown codec \"{s}\", expected zstd or deflate")),
+        }
+    }
+}
+
+//Parses a comma-separated list of codecs; "none" is the empty list
+pub fn parse_codecs(s: &str) -> Result<Vec<Codec>, String> {
+    if s.trim() == "none" {
+        return Ok(Vec::new());
+    }
+    s.split(',').map(str::parse).collect()
+}
@@ -0,0 +1,402 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use rustls::{ClientConnection, StreamOwned};
+
+use crate::auth::{self, Credentials};
+use crate::buffer::OfflineBuffer;
+use crate::compress::Codec;
+use crate::error::LogError;
+use crate::protocol::{encode_frame, Frame, FrameDecoder, LENGTH_PREFIX, MAX_FRAME_LEN};
+use crate::tls::TlsClient;
+use crate::transport::{Stream, Transport, MAX_DATAGRAM};
+
+/* connection - the socket behind a Log, and what happens when it drops
+ *
+ * Every frame goes through the offline buffer. While the server is
+ * reachable the buffer is drained straight away; when a write fails the
+ * socket is discarded, frames pile up in the buffer, and reconnects are
+ * attempted on later sends with exponential backoff. The first send after
+ * a successful reconnect flushes the backlog in order.
+ *
+ * The socket is whichever transport.rs opens. Every (re)connect over a
+ * stream completes the hello handshake, and TLS when configured, before
+ * the socket is used, so a certificate problem, a rejected secret or a
+ * missing one counts as a failed attempt.
+ *
+ * sync() asks the server to confirm that what was written has been
+ * persisted; see the sync and ack frames in protocol.rs.
+ *
+ * With batching configured, frames wait in the buffer until there are
+ * batch_max_records of them, batch_max_bytes worth, or the oldest has
+ * waited batch_delay_ms, and then go out in a single write: compressed
+ * in one batch frame if the server accepted a codec and that makes them
+ * smaller, else back to back.
+ * Without a background thread nothing wakes up when batch_delay_ms
+ * runs out, so a Log in blocking mode sends a late batch with its next
+ * record, or on log_flush. Datagrams are never batched.
+ * */
+
+//When buffered frames are written out
+pub(crate) struct Batching {
+    pub(crate) max_records: usize,
+    pub(crate) max_bytes: usize,
+    pub(crate) delay: Duration,
+}
+
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    pub(crate) fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { initial, max, current: initial, next_attempt: None }
+    }
+
+    fn ready(&self) -> bool {
+        self.next_attempt.is_none_or(|at| Instant::now() >= at)
+    }
+
+    fn failed(&mut self) {
+        self.next_attempt = Some(Instant::now() + self.current);
+        self.current = (self.current * 2).min(self.max);
+    }
+
+    fn succeeded(&mut self) {
+        self.current = self.initial;
+        self.next_attempt = None;
+    }
+}
+
+//Where to connect and how; used for the first connection and every retry
+pub(crate) struct Connector {
+    pub(crate) transport: Transport,
+    pub(crate) timeout: Duration,
+    pub(crate) tls: Option<TlsClient>,
+    pub(crate) auth: Credentials,
+    //How long log_sync waits for the server's acknowledgement
+    pub(crate) ack_timeout: Duration,
+    //Codecs to offer, preferred first; empty to send uncompressed
+    pub(crate) compression: Vec<Codec>,
+}
+
+impl Connector {
+    //Returns the stream and the codec the server accepted, if any
+    pub(crate) fn connect(&self) -> Result<(Stream, Option<Codec>), LogError> {
+        let refused = |e: io::Error| LogError::ConnectRefused(self.transport.to_string(), e);
+        let stream = self.transport.open(self.timeout).map_err(refused)?;
+        stream.set_timeout(Some(self.timeout)).map_err(refused)?;
+
+        let mut stream = match (&self.tls, stream) {
+            (Some(tls), Stream::Tcp(tcp)) => {
+                let conn = ClientConnection::new(tls.config.clone(), tls.server_name.clone())
+                    .map_err(|e| refused(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+                let mut tls = StreamOwned::new(conn, tcp);
+                while tls.conn.is_handshaking() {
+                    tls.conn.complete_io(&mut tls.sock).map_err(refused)?;
+                }
+                Stream::Tls(Box::new(tls))
+            }
+            (_, stream) => stream,
+        };
+        if !stream.is_datagram() {
+            authenticate(&mut stream, &self.auth).map_err(|e| match e {
+                Handshake::Io(e) => refused(e),
+                Handshake::Rejected(reason) => LogError::AuthRejected(reason),
+            })?;
+        }
+        let mut codec = None;
+        if !self.compression.is_empty() && !stream.is_datagram() {
+            codec = negotiate(&mut stream, &self.compression).map_err(refused)?;
+        }
+
+        stream.set_timeout(None).map_err(refused)?;
+        Ok((stream, codec))
+    }
+}
+
+//Offers our codecs and returns the one the server picked
+fn negotiate(stream: &mut Stream, codecs: &[Codec]) -> io::Result<Option<Codec>> {
+    let codecs = codecs.iter().map(|c| c.as_str().to_string()).collect();
+    stream.write_all(&encode_frame(&Frame::Offer { codecs }))?;
+    match read_frame(stream, &mut FrameDecoder::new())? {
+        Frame::Accept { codec } => Ok(codec),
+        other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected accept, got {other:?}"))),
+    }
+}
+
+enum Handshake {
+    Io(io::Error),
+    Rejected(String),
+}
+
+impl From<io::Error> for Handshake {
+    fn from(e: io::Error) -> Handshake {
+        Handshake::Io(e)
+    }
+}
+
+//hello, then sign the server's challenge. A server that does not check
+//credentials welcomes the client right after hello; one that does, to a
+//client without a secret, means the client cannot log there at all.
+fn authenticate(stream: &mut Stream, credentials: &Credentials) -> Result<(), Handshake> {
+    let mut decoder = FrameDecoder::new();
+    stream.write_all(&encode_frame(&Frame::Hello { app: credentials.app.clone() }))?;
+    loop {
+        match read_frame(stream, &mut decoder)? {
+            Frame::Challenge { nonce } => {
+                let Some(secret) = &credentials.secret else {
+                    return Err(Handshake::Rejected("the server requires authentication and auth_secret is not set".to_string()));
+                };
+                let mac = auth::sign(secret.as_bytes(), &nonce, &credentials.app);
+                stream.write_all(&encode_frame(&Frame::Auth { mac }))?;
+            }
+            Frame::Welcome { .. } => return Ok(()),
+            Frame::Reject { reason } => return Err(Handshake::Rejected(reason)),
+            other => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, format!("unexpected {other:?} during handshake"));
+                return Err(Handshake::Io(e));
+            }
+        }
+    }
+}
+
+fn read_frame(stream: &mut Stream, decoder: &mut FrameDecoder) -> io::Result<Frame> {
+    let mut buffer = [0; 512];
+    loop {
+        match decoder.next_frame() {
+            Ok(Some(frame)) => return Ok(frame),
+            Ok(None) => {}
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+        match stream.read(&mut buffer)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => decoder.push(&buffer[..n]),
+        }
+    }
+}
+
+//Sends a sync and waits for the matching ack, returning the server's
+//error if it could not persist the records
+fn request_ack(stream: &mut Stream, seq: u64, timeout: Duration) -> io::Result<Option<String>> {
+    stream.write_all(&encode_frame(&Frame::Sync { seq }))?;
+    stream.set_timeout(Some(timeout))?;
+    let frame = read_frame(stream, &mut FrameDecoder::new())?;
+    stream.set_timeout(None)?;
+    match frame {
+        Frame::Ack { seq: acked, error } if acked == seq => Ok(error),
+        other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected an ack, got {other:?}"))),
+    }
+}
+
+pub(crate) struct Connection {
+    connector: Connector,
+    stream: Option<Stream>,
+    //Codec the server accepted for the current stream
+    codec: Option<Codec>,
+    backoff: Backoff,
+    buffer: OfflineBuffer,
+    batching: Batching,
+    //Frames sent since the buffer was last written out, their size, and
+    //when the first of them was sent
+    unsent: usize,
+    unsent_bytes: usize,
+    unsent_since: Option<Instant>,
+    //Sequence number of the last sync sent
+    seq: u64,
+}
+
+impl Connection {
+    //`link` is None when the server could not be reached at first
+    pub(crate) fn new(
+        connector: Connector,
+        link: Option<(Stream, Option<Codec>)>,
+        backoff: Backoff,
+        buffer: OfflineBuffer,
+        batching: Batching,
+    ) -> Connection {
+        let (stream, codec) = link.map_or((None, None), |(stream, codec)| (Some(stream), codec));
+        Connection {
+            connector,
+            stream,
+            codec,
+            backoff,
+            buffer,
+            batching,
+            unsent: 0,
+            unsent_bytes: 0,
+            unsent_since: None,
+            seq: 0,
+        }
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    //Queues the frame and, once a batch is due, sends as much of the
+    //backlog as the server will take
+    pub(crate) fn send(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        self.unsent += 1;
+        self.unsent_bytes += frame.len();
+        self.unsent_since.get_or_insert_with(Instant::now);
+        self.buffer.push(frame)?;
+        if self.batch_wait().is_some_and(|wait| wait.is_zero()) {
+            self.flush();
+        }
+        Ok(())
+    }
+
+    //How long until the frames sent so far are due to be written out;
+    //None when there are none
+    pub(crate) fn batch_wait(&self) -> Option<Duration> {
+        let since = self.unsent_since?;
+        if self.unsent >= self.batching.max_records || self.unsent_bytes >= self.batching.max_bytes {
+            return Some(Duration::ZERO);
+        }
+        Some(self.batching.delay.saturating_sub(since.elapsed()))
+    }
+
+    //Writes buffered frames in order until the buffer is empty or the
+    //connection fails, reconnecting first if the backoff allows it
+    pub(crate) fn flush(&mut self) {
+        if self.buffer.is_empty() || !self.ensure_connected() {
+            return;
+        }
+        loop {
+            let datagram = self.stream.as_ref().is_some_and(Stream::is_datagram);
+            let max_records = if datagram { 1 } else { self.batching.max_records };
+            let Ok(frames) = self.buffer.front_frames(max_records, self.batching.max_bytes.min(MAX_FRAME_LEN)) else {
+                return;
+            };
+            if frames.is_empty() {
+                break;
+            }
+            if datagram && frames[0].len() > MAX_DATAGRAM {
+                let _ = self.buffer.pop_front();
+                self.buffer.note_dropped(1);
+                continue;
+            }
+            let count = frames.len();
+            let batch = match (self.codec, count) {
+                (None, 1) => frames.into_iter().next().unwrap_or_default(),
+                (None, _) => frames.concat(),
+                //A batch goes out as it is when it will not compress, or
+                //when compressing it gains nothing: an incompressible batch
+                //could otherwise grow past MAX_FRAME_LEN
+                (Some(codec), _) => {
+                    let plain = frames.concat();
+                    match codec.compress(&plain).map(|data| encode_frame(&Frame::Batch { codec, data })) {
+                        Ok(batch) if batch.len() < plain.len() && batch.len() - LENGTH_PREFIX <= MAX_FRAME_LEN => batch,
+                        _ => plain,
+                    }
+                }
+            };
+            if !self.write(&batch) {
+                return;
+            }
+            for _ in 0..count {
+                let _ = self.buffer.pop_front();
+            }
+        }
+        self.unsent = 0;
+        self.unsent_bytes = 0;
+        self.unsent_since = None;
+    }
+
+    //Waits for the server to acknowledge that everything written so far is
+    //on disk. Buffered frames have to reach it first. If no answer comes,
+    //the connection is dropped: a late ack must not be mistaken for the
+    //answer to a later sync.
+    pub(crate) fn sync(&mut self) -> Result<(), LogError> {
+        self.flush();
+        if !self.is_drained() || !self.ensure_connected() {
+            return Err(LogError::Disconnected);
+        }
+        let Some(stream) = self.stream.as_mut() else { return Err(LogError::Disconnected) };
+        if stream.is_datagram() {
+            return Err(LogError::NotAcknowledged("udp has no acknowledgements".to_string()));
+        }
+        self.seq += 1;
+        match request_ack(stream, self.seq, self.connector.ack_timeout) {
+            Ok(None) => Ok(()),
+            Ok(Some(error)) => Err(LogError::NotAcknowledged(error)),
+            Err(e) => {
+                self.stream = None;
+                self.backoff.failed();
+                Err(LogError::NotAcknowledged(format!("no acknowledgement from the server: {e}")))
+            }
+        }
+    }
+
+    //True once everything handed to send has been written to the socket
+    pub(crate) fn is_drained(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    //Records discarded by the drop policy since the last call
+    pub(crate) fn take_dropped(&mut self) -> u64 {
+        self.buffer.take_dropped()
+    }
+
+    //Counts records that were discarded before they reached the buffer
+    pub(crate) fn note_dropped(&mut self, count: u64) {
+        self.buffer.note_dropped(count);
+    }
+
+    pub(crate) fn shutdown(&mut self) -> io::Result<()> {
+        match self.stream.take() {
+            Some(stream) => stream.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    fn ensure_connected(&mut self) -> bool {
+        if let Some(stream) = &mut self.stream {
+            if !stream.peer_closed() {
+                return true;
+            }
+            self.stream = None;
+            self.backoff.failed();
+        }
+        if !self.backoff.ready() {
+            return false;
+        }
+        match self.connector.connect() {
+            Ok((stream, codec)) => {
+                self.backoff.succeeded();
+                self.stream = Some(stream);
+                self.codec = codec;
+                true
+            }
+            Err(_) => {
+                self.backoff.failed();
+                false
+            }
+        }
+    }
+
+    fn write(&mut self, frame: &[u8]) -> bool {
+        let Some(stream) = self.stream.as_mut() else { return false };
+        if stream.write_all(frame).is_ok() {
+            return true;
+        }
+        //The frame stays buffered and is resent whole on the next connection
+        self.stream = None;
+        self.backoff.failed();
+        false
+    }
+}
+
+//A Log in blocking mode dropped without log_disconnect still sends the
+//batch it was collecting, if it is connected
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.stream.is_some() {
+            self.flush();
+        }
+    }
+}
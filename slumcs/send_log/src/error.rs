@@ -0,0 +1,70 @@
+use std::fmt;
+use std::io;
+
+use crate::protocol::MAX_FRAME_LEN;
+
+//Everything that can go wrong talking to the logging server. Returned by
+//the try_* functions and the severity helpers in lib.rs.
+#[derive(Debug)]
+pub enum LogError {
+    //The configuration file could not be found or parsed
+    ConfigMissing(String),
+    //The configuration file has no value for this key
+    ConfigKeyMissing(String),
+    //The value for this key is present but unusable
+    ConfigInvalid(String, String),
+    //Nobody accepted the connection at this address
+    ConnectRefused(String, io::Error),
+    //The server turned down this client's credentials
+    AuthRejected(String),
+    //The server did not confirm that records were persisted, and why
+    NotAcknowledged(String),
+    //The encoded record is this many bytes, more than one frame may hold
+    RecordTooLarge(usize),
+    //A record could not be written to the socket or the offline buffer
+    WriteFailed(io::Error),
+    //The connection was shut down, locally or by the server
+    Disconnected,
+    //A global logger was already installed for the `log` crate
+    LoggerAlreadySet,
+}
+
+impl LogError {
+    //Sorts a socket error into WriteFailed or Disconnected
+    pub(crate) fn from_write(e: io::Error) -> LogError {
+        match e.kind() {
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof => LogError::Disconnected,
+            _ => LogError::WriteFailed(e),
+        }
+    }
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::ConfigMissing(reason) => write!(f, "could not open configuration file: {reason}"),
+            LogError::ConfigKeyMissing(key) => write!(f, "configuration is missing \"{key}\""),
+            LogError::ConfigInvalid(key, reason) => write!(f, "invalid value for \"{key}\": {reason}"),
+            LogError::ConnectRefused(addr, e) => write!(f, "could not connect to logging server at {addr}: {e}"),
+            LogError::AuthRejected(reason) => write!(f, "logging server rejected authentication: {reason}"),
+            LogError::NotAcknowledged(reason) => write!(f, "records not acknowledged as persisted: {reason}"),
+            LogError::RecordTooLarge(len) => write!(f, "record of {len} bytes exceeds the {MAX_FRAME_LEN} byte frame limit"),
+            LogError::WriteFailed(e) => write!(f, "error sending message to logging server: {e}"),
+            LogError::Disconnected => write!(f, "not connected to the logging server"),
+            LogError::LoggerAlreadySet => write!(f, "a global logger has already been installed"),
+        }
+    }
+}
+
+impl std::error::Error for LogError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LogError::ConnectRefused(_, e) | LogError::WriteFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::cell::Cell;
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::LevelFilter;
+
+use crate::{log_flush, log_send_with, settings, try_log_connect, Log, LogError, Severity};
+
+/* facade - forwards the `log` crate's macros to the global logger
+ *
+ * One call installs a send_log connection as the process-wide logger:
+ *
+ *  send_log::facade::init( "config.toml" )?;
+ *  log::info!("listening on {}", port);
+ *
+ * Every record is sent with its target, module path, file and line as
+ * fields. The optional log_level key in config.toml (trace, debug, info,
+ * warn, error or off; default info) sets the most verbose level that is
+ * forwarded. Setting log_async = true as well keeps the macros from ever
+ * blocking on the network.
+ *
+ * With the "tracing" feature enabled, SendLogLayer does the same for
+ * tracing events.
+ *
+ * Sending can reconnect, and rustls logs its TLS handshakes through the
+ * same macros. Records from rustls and from send_log itself are never
+ * forwarded, and anything else logged on a thread that is already
+ * sending is dropped rather than waiting on the Log it holds.
+ * */
+
+thread_local! {
+    //Set while this thread holds a Logger's Log
+    static SENDING: Cell<bool> = const { Cell::new(false) };
+}
+
+//Clears SENDING when the send is over, panicking or not
+struct Sending;
+
+impl Drop for Sending {
+    fn drop(&mut self) {
+        SENDING.with(|s| s.set(false));
+    }
+}
+
+//Whether records from `target` may be sent at all
+fn forwarded(target: &str) -> bool {
+    !["rustls", "send_log"]
+        .iter()
+        .any(|own| target.strip_prefix(own).is_some_and(|rest| rest.is_empty() || rest.starts_with("::")))
+}
+
+pub struct Logger {
+    log: Mutex<Log>,
+    level: LevelFilter,
+}
+
+impl Logger {
+    pub fn new(log: Log, level: LevelFilter) -> Logger {
+        Logger { log: Mutex::new(log), level }
+    }
+
+    //Runs `f` on the Log, unless this thread is already inside a send
+    fn with_log(&self, f: impl FnOnce(&mut Log)) {
+        if SENDING.with(|s| s.replace(true)) {
+            return;
+        }
+        let _sending = Sending;
+        //A poisoned lock only means another thread panicked mid-send
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut log);
+    }
+
+    fn send(&self, severity: Severity, msg: &str, fields: &[(&str, &str)]) {
+        self.with_log(|log| {
+            let _ = log_send_with(log, severity, msg, fields);
+        });
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level && forwarded(metadata.target())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = record.line().map(|l| l.to_string());
+        let mut fields = vec![("target", record.target())];
+        if let Some(module) = record.module_path() {
+            fields.push(("module", module));
+        }
+        if let Some(file) = record.file() {
+            fields.push(("file", file));
+        }
+        if let Some(line) = &line {
+            fields.push(("line", line));
+        }
+        self.send(severity_of(record.level()), &record.args().to_string(), &fields);
+    }
+
+    fn flush(&self) {
+        self.with_log(|log| {
+            let _ = log_flush(log);
+        });
+    }
+}
+
+fn severity_of(level: log::Level) -> Severity {
+    match level {
+        log::Level::Error => Severity::Error,
+        log::Level::Warn => Severity::Warn,
+        log::Level::Info => Severity::Info,
+        log::Level::Debug => Severity::Debug,
+        log::Level::Trace => Severity::Trace,
+    }
+}
+
+//Connects using config_path and installs the result as the `log` crate's logger
+pub fn init( config_path: &str ) -> Result<(), LogError> {
+    let level = level_from_config(config_path)?;
+    let log = try_log_connect(config_path)?;
+    log::set_boxed_logger(Box::new(Logger::new(log, level))).map_err(|_| LogError::LoggerAlreadySet)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn level_from_config( config_path: &str ) -> Result<LevelFilter, LogError> {
+    match settings::load(Path::new(config_path))?.get_string("log_level") {
+        Ok(level) => level
+            .parse()
+            .map_err(|_| LogError::ConfigInvalid("log_level".to_string(), format!("unknown level \"{level}\""))),
+        Err(_) => Ok(LevelFilter::Info),
+    }
+}
+
+#[cfg(feature = "tracing")]
+pub use self::tracing_layer::SendLogLayer;
+
+#[cfg(feature = "tracing")]
+mod tracing_layer {
+    use std::fmt;
+
+    use tracing::field::{Field, Visit};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer};
+
+    use super::{forwarded, level_from_config, severity_of, Logger};
+    use crate::{try_log_connect, LogError};
+
+    //A tracing-subscriber layer that forwards events to the global logger:
+    //
+    //  tracing_subscriber::registry().with(SendLogLayer::new("config.toml")?).init();
+    pub struct SendLogLayer {
+        logger: Logger,
+    }
+
+    impl SendLogLayer {
+        pub fn new(config_path: &str) -> Result<SendLogLayer, LogError> {
+            let level = level_from_config(config_path)?;
+            let log = try_log_connect(config_path)?;
+            Ok(SendLogLayer { logger: Logger::new(log, level) })
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for SendLogLayer {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let meta = event.metadata();
+            let level = match *meta.level() {
+                tracing::Level::ERROR => log::Level::Error,
+                tracing::Level::WARN => log::Level::Warn,
+                tracing::Level::INFO => log::Level::Info,
+                tracing::Level::DEBUG => log::Level::Debug,
+                tracing::Level::TRACE => log::Level::Trace,
+            };
+            if level > self.logger.level || !forwarded(meta.target()) {
+                return;
+            }
+
+            let mut visitor = FieldVisitor::default();
+            event.record(&mut visitor);
+
+            let line = meta.line().map(|l| l.to_string());
+            let mut fields = vec![("target", meta.target())];
+            if let Some(module) = meta.module_path() {
+                fields.push(("module", module));
+            }
+            if let Some(file) = meta.file() {
+                fields.push(("file", file));
+            }
+            if let Some(line) = &line {
+                fields.push(("line", line));
+            }
+            fields.extend(visitor.fields.iter().map(|(k, v)| (*k, v.as_str())));
+            self.logger.send(severity_of(level), &visitor.message, &fields);
+        }
+    }
+
+    #[derive(Default)]
+    struct FieldVisitor {
+        message: String,
+        fields: Vec<(&'static str, String)>,
+    }
+
+    impl Visit for FieldVisitor {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "message" {
+                self.message = value.to_string();
+            } else {
+                self.fields.push((field.name(), value.to_string()));
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                self.message = format!("{value:?}");
+            } else {
+                self.fields.push((field.name(), format!("{value:?}")));
+            }
+        }
+    }
+}
@@ -1,8 +1,32 @@
-use std::net::{TcpStream, Shutdown};
 use config::Config;
 use std::process::exit;
-use std::io::Write;
-use rand::Rng;
+use std::path::Path;
+use std::time::Duration;
+
+pub mod auth;
+mod buffer;
+pub mod clock;
+pub mod compress;
+mod connection;
+mod error;
+pub mod facade;
+pub mod protocol;
+pub mod rotation;
+pub mod settings;
+pub mod tls;
+mod transport;
+mod worker;
+
+use buffer::OfflineBuffer;
+use clock::{Clock, ClockKind, LocalClock};
+use connection::{Backoff, Batching, Connection, Connector};
+use protocol::{encode_frame, Frame, Record, LENGTH_PREFIX, MAX_FRAME_LEN};
+use transport::{Transport, TransportKind};
+use worker::Worker;
+
+pub use buffer::DropPolicy;
+pub use error::LogError;
+pub use protocol::Severity;
 
 /* send_log - writes messages to the global logger
  *
@@ -12,46 +36,437 @@ use rand::Rng;
  *
  * For example, in user code:
  *
- *  let logger = log_connect( "../config.toml" );
+ *  let logger = log_connect( "config.toml" );
  *  log_send( &logger, "This is a log message to write to the global log." );
  *  log_send( &logger, "And another message to write to the global log." );
  *  log_disconnect( &logger );
+ *
+ * log_send logs at Info. Use log_trace/log_debug/log_info/log_warn/log_error
+ * to pick a severity, or log_send_with to also attach key/value fields:
+ *
+ *  log_send_with( &mut logger, Severity::Warn, "Disk almost full",
+ *                 &[("mount", "/var"), ("used_pct", "93")] );
+ *
+ * log_connect, log_send and log_disconnect print the problem and exit the
+ * process if anything goes wrong. Applications that would rather carry on
+ * without the global log use try_log_connect, try_log_send and
+ * try_log_disconnect, which return a LogError instead:
+ *
+ *  match try_log_connect( "config.toml" ) {
+ *      Ok(logger) => ...,
+ *      Err(e) => eprintln!("logging disabled: {e}"),
+ *  }
+ *
+ * The severity helpers and log_send_with always return a Result.
+ * log_forward passes on a Record received from elsewhere unchanged.
+ *
+ * settings.rs finds config.toml the way the other programs do (--config,
+ * SLUMCS_CONFIG, standard locations) and applies SLUMCS_* overrides.
+ * try_log_connect_with takes settings built that way or by hand, and
+ * try_log_connect_to( "127.0.0.1:23456" ) needs no configuration at all.
+ *
+ * If the server goes away after log_connect, records are buffered and the
+ * connection is retried with exponential backoff on later sends; the
+ * backlog is flushed in order once the server is back. Optional keys in
+ * config.toml tune this:
+ *
+ *  reconnect_initial_ms  first retry delay, doubled per failure (100)
+ *  reconnect_max_ms      upper bound on the retry delay (30000)
+ *  connect_timeout_ms    how long one connection attempt may take (1000)
+ *  connect_required      fail log_connect if the server cannot be reached
+ *                        (true); with false the Log starts out buffering
+ *  buffer_max_records    records held in memory while offline (10000)
+ *  buffer_policy         "drop_oldest" or "drop_newest" when full
+ *  buffer_file           spool to this file instead of memory
+ *  buffer_max_bytes      size cap for buffer_file (64 MiB)
+ *
+ * Records dropped because the buffer was full are reported to the server
+ * in a warning once the connection is restored.
+ *
+ * By default log_send writes to the socket on the calling thread. With
+ * log_async = true in config.toml, or when connecting with
+ * log_connect_async, the Log instead owns a background thread and
+ * log_send only queues the record for it:
+ *
+ *  async_queue_records   records waiting for the thread before new ones
+ *                        are dropped (10000)
+ *  drain_timeout_ms      how long disconnecting or dropping the Log keeps
+ *                        retrying to deliver what is queued (5000)
+ *
+ * At high volume, records can be sent in batches rather than one write
+ * per record; see connection.rs:
+ *
+ *  batch_max_records     send once this many records are waiting (1, so
+ *                        batching is off)
+ *  batch_max_bytes       or once they take up this many bytes (64 KiB)
+ *  batch_delay_ms        or once the oldest has waited this long (100)
+ *  compression           codecs to offer the server for batches, e.g.
+ *                        "zstd,deflate" (none); see compress.rs
+ *
+ * log_flush blocks until everything sent so far has reached the server,
+ * in either mode. log_sync goes further and waits until the server has
+ * written it to disk and fsynced it; log_send_sync sends one record that
+ * way. Both fail with NotAcknowledged if the server could not persist
+ * the records or did not answer within ack_timeout_ms (5000), and are
+ * not available over UDP.
+ *
+ * Records go over TCP unless log_transport picks a Unix domain socket or
+ * UDP datagrams instead; see transport.rs.
+ *
+ * Set tls = true to encrypt the connection; the certificate keys are
+ * described in tls.rs. A server that only accepts known applications
+ * needs auth_secret (and usually auth_app) set; see auth.rs.
+ *
+ * With logical_clock = "lamport" or "vector", records also carry a
+ * logical timestamp so log_component can order records from different
+ * processes causally. Pass log_clock along with the application's own
+ * messages and hand what arrives to log_clock_merge; see clock.rs.
+ *
+ * Code written against the `log` crate can send its log::info! etc.
+ * through a Log as well; see facade.rs.
+ *
+ * Each log_send call is sent as one length-prefixed record frame (see
+ * protocol.rs) tagged with this process' sender id and a timestamp, so
+ * log_component writes exactly one entry per call.
  * */
 
 //This should be considered an opaque type- the user should never
 //interact with the internals of struct Log. See the usage idiom
 //up above.
 pub struct Log {
-    stream: TcpStream,
+    mode: Mode,
+    sender: String,
+    connected: bool,
+    //Only with logical_clock set
+    clock: Option<LocalClock>,
 }
 
-pub fn log_connect( config_path: &str ) -> Log {
-    let settings = match Config::builder()
-        .add_source(config::File::with_name(config_path))
-        .build() {
-            Ok(x) => x,
-            Err(x) => {println!("Could not open configuration file: {x}"); exit(-1)},
-        }; 
+enum Mode {
+    Blocking(Box<Connection>),
+    Background(Worker),
+}
+
+pub fn try_log_connect( config_path: &str ) -> Result<Log, LogError> {
+    connect_with(&settings::load(Path::new(config_path))?, false)
+}
+
+//Like try_log_connect, but always sends from a background thread
+pub fn try_log_connect_async( config_path: &str ) -> Result<Log, LogError> {
+    connect_with(&settings::load(Path::new(config_path))?, true)
+}
 
-    let log_ip = settings.get_string("log_ip").unwrap();
-    let log_port = settings.get_string("log_port").unwrap();
+//Connects with settings the caller has already built, with settings::load
+//or its own config::Config::builder()
+pub fn try_log_connect_with( settings: &Config ) -> Result<Log, LogError> {
+    connect_with(settings, false)
+}
+
+//Connects to "ip:port" with every optional key at its default
+pub fn try_log_connect_to( addr: &str ) -> Result<Log, LogError> {
+    let Some((ip, port)) = addr.rsplit_once(':') else {
+        return Err(LogError::ConfigInvalid("log_port".to_string(), format!("no port in \"{addr}\"")));
+    };
+    let settings = Config::builder()
+        .set_override("log_ip", ip)
+        .and_then(|b| b.set_override("log_port", port))
+        .and_then(|b| b.build())
+        .map_err(|e| LogError::ConfigInvalid("log_ip".to_string(), e.to_string()))?;
+    connect_with(&settings, false)
+}
+
+fn connect_with( settings: &Config, force_async: bool ) -> Result<Log, LogError> {
+    let get = |key: &str| settings.get_string(key).map_err(|_| LogError::ConfigKeyMissing(key.to_string()));
+    let kind = match get("log_transport") {
+        Ok(x) => x.parse().map_err(|e| LogError::ConfigInvalid("log_transport".to_string(), e))?,
+        Err(_) => TransportKind::Tcp,
+    };
+    let (transport, tls) = match kind {
+        #[cfg(unix)]
+        TransportKind::Unix => (Transport::Unix(get("log_socket")?.into()), None),
+        #[cfg(not(unix))]
+        TransportKind::Unix => {
+            let reason = "Unix domain sockets are not available on this platform".to_string();
+            return Err(LogError::ConfigInvalid("log_transport".to_string(), reason));
+        }
+        TransportKind::Tcp | TransportKind::Udp => {
+            let log_ip = get("log_ip")?;
+            let log_port: u16 = get("log_port")?.parse().map_err(|_| {
+                LogError::ConfigInvalid("log_port".to_string(), "expected a port number between 0 and 65535".to_string())
+            })?;
+            let addr = format!("{}:{}", log_ip, log_port);
+            if kind == TransportKind::Udp {
+                (Transport::Udp(addr), None)
+            } else {
+                (Transport::Tcp(addr), tls::client_from_config(settings, &log_ip)?)
+            }
+        }
+    };
+    if kind != TransportKind::Tcp && settings.get_bool("tls").unwrap_or(false) {
+        return Err(LogError::ConfigInvalid("tls".to_string(), "TLS needs log_transport = \"tcp\"".to_string()));
+    }
+
+    //Optional keys from here on
+    let get_u64 = |key: &str, default: u64| match settings.get_int(key) {
+        Ok(x) if x >= 0 => Ok(x as u64),
+        Ok(x) => Err(LogError::ConfigInvalid(key.to_string(), format!("{x} is negative"))),
+        Err(config::ConfigError::NotFound(_)) => Ok(default),
+        Err(e) => Err(LogError::ConfigInvalid(key.to_string(), e.to_string())),
+    };
+    let backoff = Backoff::new(
+        Duration::from_millis(get_u64("reconnect_initial_ms", 100)?),
+        Duration::from_millis(get_u64("reconnect_max_ms", 30_000)?),
+    );
+    let connect_timeout = Duration::from_millis(get_u64("connect_timeout_ms", 1000)?.max(1));
+    let policy = match get("buffer_policy") {
+        Ok(x) => x.parse().map_err(|e| LogError::ConfigInvalid("buffer_policy".to_string(), e))?,
+        Err(_) => DropPolicy::DropOldest,
+    };
+    let buffer = match get("buffer_file") {
+        Ok(path) => OfflineBuffer::spool(Path::new(&path), get_u64("buffer_max_bytes", 64 << 20)?, policy)
+            .map_err(|e| LogError::ConfigInvalid("buffer_file".to_string(), e.to_string()))?,
+        Err(_) => OfflineBuffer::memory(get_u64("buffer_max_records", 10_000)? as usize, policy),
+    };
+
+    //By default records are tagged with the program name and pid
+    let sender = get("log_sender").unwrap_or_else(|_| default_sender());
+
+    let clock = match get("logical_clock") {
+        Ok(kind) => {
+            let kind: ClockKind = kind.parse().map_err(|e| LogError::ConfigInvalid("logical_clock".to_string(), e))?;
+            let clock = LocalClock::new(kind, get("clock_id").ok(), &sender);
+            Some(clock.map_err(|e| LogError::ConfigInvalid("clock_id".to_string(), e))?)
+        }
+        Err(_) => None,
+    };
+
+    let background = force_async || settings.get_bool("log_async").unwrap_or(false);
+
+    let auth = auth::Credentials {
+        app: get("auth_app").unwrap_or_else(|_| program_name()),
+        secret: get("auth_secret").ok(),
+    };
+    if kind == TransportKind::Udp && auth.secret.is_some() {
+        let reason = "udp cannot authenticate; use log_transport = \"tcp\" or \"unix\"".to_string();
+        return Err(LogError::ConfigInvalid("auth_secret".to_string(), reason));
+    }
+
+    let compression = match get("compression") {
+        Ok(x) => compress::parse_codecs(&x).map_err(|e| LogError::ConfigInvalid("compression".to_string(), e))?,
+        Err(_) => Vec::new(),
+    };
+    if kind == TransportKind::Udp && !compression.is_empty() {
+        let reason = "udp cannot negotiate compression; use log_transport = \"tcp\" or \"unix\"".to_string();
+        return Err(LogError::ConfigInvalid("compression".to_string(), reason));
+    }
+    let batching = Batching {
+        max_records: get_u64("batch_max_records", 1)?.max(1) as usize,
+        max_bytes: get_u64("batch_max_bytes", 64 << 10)? as usize,
+        delay: Duration::from_millis(get_u64("batch_delay_ms", 100)?),
+    };
 
-    let stream = match TcpStream::connect(format!("{}:{}", log_ip, log_port)) {
-        Ok(s) => s,
-        Err(e) => { println!("Error connecting to logging server: {}", e); exit(-1) }
+    let ack_timeout = Duration::from_millis(get_u64("ack_timeout_ms", 5000)?.max(1));
+    let connector = Connector { transport, timeout: connect_timeout, tls, auth, ack_timeout, compression };
+    let link = match connector.connect() {
+        Ok(link) => Some(link),
+        //A rejected handshake is a configuration problem, not an outage
+        Err(LogError::ConnectRefused(..)) if !settings.get_bool("connect_required").unwrap_or(true) => None,
+        Err(e) => return Err(e),
     };
+    let mut conn = Connection::new(connector, link, backoff, buffer, batching);
+
+    //Anything left in a spool file by an earlier run goes out first
+    conn.flush();
+
+    let mode = if background {
+        let queue = get_u64("async_queue_records", 10_000)? as usize;
+        let drain_timeout = Duration::from_millis(get_u64("drain_timeout_ms", 5000)?);
+        Mode::Background(Worker::spawn(conn, sender.clone(), queue, drain_timeout))
+    } else {
+        Mode::Blocking(Box::new(conn))
+    };
+    Ok(Log { mode, sender, connected: true, clock })
+}
+
+fn program_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn default_sender() -> String {
+    format!("{}[{}]", program_name(), std::process::id())
+}
+
+pub fn try_log_disconnect( log: &mut Log ) -> Result<(), LogError> {
+    if !log.connected {
+        return Err(LogError::Disconnected);
+    }
+    log.connected = false;
+    match &mut log.mode {
+        Mode::Blocking(conn) => {
+            conn.flush();
+            conn.shutdown().map_err(LogError::from_write)
+        }
+        Mode::Background(worker) => {
+            worker.stop();
+            Ok(())
+        }
+    }
+}
+
+//Waits until every record sent so far has been written to the server.
+//Fails with Disconnected if some are still buffered because the server
+//cannot be reached.
+pub fn log_flush( log: &mut Log ) -> Result<(), LogError> {
+    if !log.connected {
+        return Err(LogError::Disconnected);
+    }
+    match &mut log.mode {
+        Mode::Blocking(conn) => {
+            conn.flush();
+            if conn.is_drained() { Ok(()) } else { Err(LogError::Disconnected) }
+        }
+        Mode::Background(worker) => worker.flush(),
+    }
+}
+
+//Waits until the server has persisted every record sent so far
+pub fn log_sync( log: &mut Log ) -> Result<(), LogError> {
+    if !log.connected {
+        return Err(LogError::Disconnected);
+    }
+    match &mut log.mode {
+        Mode::Blocking(conn) => conn.sync(),
+        Mode::Background(worker) => worker.sync(),
+    }
+}
+
+//Sends a record at Info and waits until the server has persisted it
+pub fn log_send_sync( log: &mut Log, msg: &str ) -> Result<(), LogError> {
+    try_log_send(log, msg)?;
+    log_sync(log)
+}
+
+pub fn try_log_send( log: &mut Log, msg: &str ) -> Result<(), LogError> {
+    log_send_with(log, Severity::Info, msg, &[])
+}
+
+pub fn log_trace( log: &mut Log, msg: &str ) -> Result<(), LogError> {
+    log_send_with(log, Severity::Trace, msg, &[])
+}
+
+pub fn log_debug( log: &mut Log, msg: &str ) -> Result<(), LogError> {
+    log_send_with(log, Severity::Debug, msg, &[])
+}
+
+pub fn log_info( log: &mut Log, msg: &str ) -> Result<(), LogError> {
+    log_send_with(log, Severity::Info, msg, &[])
+}
+
+pub fn log_warn( log: &mut Log, msg: &str ) -> Result<(), LogError> {
+    log_send_with(log, Severity::Warn, msg, &[])
+}
+
+pub fn log_error( log: &mut Log, msg: &str ) -> Result<(), LogError> {
+    log_send_with(log, Severity::Error, msg, &[])
+}
+
+//Sends one record with an explicit severity and a list of key/value fields,
+//which log_component renders alongside the message.
+pub fn log_send_with( log: &mut Log, severity: Severity, msg: &str, fields: &[(&str, &str)] ) -> Result<(), LogError> {
+    if !log.connected {
+        return Err(LogError::Disconnected);
+    }
+    let mut record = Record::new(&log.sender, severity, msg).with_fields(fields);
+    if let Some(clock) = &mut log.clock {
+        record.fields.extend(clock.tick());
+    }
+    let frame = record_frame(record)?;
+    match &mut log.mode {
+        Mode::Blocking(conn) => deliver(conn, &log.sender, frame),
+        Mode::Background(worker) => worker.send(frame),
+    }
+}
+
+//The Log's logical clock, to send along with a message to another
+//process. None unless logical_clock is set.
+pub fn log_clock( log: &Log ) -> Option<Clock> {
+    log.clock.as_ref().map(|c| c.clock().clone())
+}
+
+//Merges the clock that came with a message from another process, so
+//records sent from now on are ordered after what it had logged
+pub fn log_clock_merge( log: &mut Log, received: &Clock ) -> Result<(), LogError> {
+    match &mut log.clock {
+        Some(clock) => {
+            clock.merge(received);
+            Ok(())
+        }
+        None => Err(LogError::ConfigKeyMissing("logical_clock".to_string())),
+    }
+}
+
+//Sends a record that was built elsewhere exactly as it is, keeping its
+//sender and timestamp. log_component uses this to relay what its own
+//clients sent on to another server.
+pub fn log_forward( log: &mut Log, record: Record ) -> Result<(), LogError> {
+    if !log.connected {
+        return Err(LogError::Disconnected);
+    }
+    let frame = record_frame(record)?;
+    match &mut log.mode {
+        Mode::Blocking(conn) => deliver(conn, &log.sender, frame),
+        Mode::Background(worker) => worker.send(frame),
+    }
+}
+
+//Encodes a record, refusing one the server would reject as too large
+fn record_frame( record: Record ) -> Result<Vec<u8>, LogError> {
+    let frame = encode_frame(&Frame::Record(record));
+    let len = frame.len() - LENGTH_PREFIX;
+    if len > MAX_FRAME_LEN {
+        return Err(LogError::RecordTooLarge(len));
+    }
+    Ok(frame)
+}
+
+//Hands a frame to the connection, then tells the server about any records
+//the offline buffer had to drop once it is reachable again
+pub(crate) fn deliver( conn: &mut Connection, sender: &str, frame: Vec<u8> ) -> Result<(), LogError> {
+    conn.send(frame).map_err(LogError::WriteFailed)?;
+
+    if conn.is_connected() {
+        let dropped = conn.take_dropped();
+        if dropped > 0 {
+            let notice = Record::new(sender, Severity::Warn, "send_log dropped records while the server was unreachable")
+                .with_fields(&[("dropped", &dropped.to_string())]);
+            conn.send(encode_frame(&Frame::Record(notice))).map_err(LogError::WriteFailed)?;
+        }
+    }
+    Ok(())
+}
+
+//The original interface. These keep their old behaviour of reporting the
+//error and exiting; see the try_* functions above for the fallible versions.
 
-    Log { stream }
+pub fn log_connect( config_path: &str ) -> Log {
+    try_log_connect(config_path).unwrap_or_else(|e| exit_with(e))
+}
+
+pub fn log_connect_async( config_path: &str ) -> Log {
+    try_log_connect_async(config_path).unwrap_or_else(|e| exit_with(e))
 }
 
 pub fn log_disconnect( log: &mut Log ){
-    log.stream.shutdown(Shutdown::Both).unwrap();
+    try_log_disconnect(log).unwrap_or_else(|e| exit_with(e))
 }
 
 pub fn log_send( log: &mut Log, msg: &str ){
-    if let Err(e) = log.stream.write_all(msg.as_bytes()) {
-        println!("Error sending message to logging server: {}", e);
-        exit(-1);
-    }
+    try_log_send(log, msg).unwrap_or_else(|e| exit_with(e))
 }
 
+fn exit_with( e: LogError ) -> ! {
+    println!("{e}");
+    exit(-1)
+}
@@ -0,0 +1,485 @@
+/* protocol - wire format shared by send_log and log_component
+ *
+ * Every message on the wire is a length-prefixed frame:
+ *
+ *   +-------------+-------------+----------+---------------------+
+ *   | length: u32 | version: u8 | kind: u8 | body: length - 2 B  |
+ *   +-------------+-------------+----------+---------------------+
+ *
+ * All integers are big-endian and `length` counts every byte after the
+ * length prefix itself. A record body (kind 1) is laid out as:
+ *
+ *   sender     u16 length + UTF-8 bytes
+ *   timestamp  u64 milliseconds since the Unix epoch
+ *   severity   u8 (see Severity)
+ *   payload    u32 length + UTF-8 bytes
+ *   fields     u16 count, then per field a u16-length key and a
+ *              u32-length value (version 2 and later)
+ *
+ * Because the receiver only ever hands out whole frames, one log_send
+ * call always turns into exactly one record on the server, no matter
+ * how TCP splits or coalesces the bytes in between.
+ *
+ * A stream client opens the connection with a handshake before its first
+ * record (see auth.rs):
+ *
+ *   client  hello      (kind 2)  app: u16 length + UTF-8 bytes
+ *   server  challenge  (kind 3)  nonce: u16 length + bytes
+ *   client  auth       (kind 4)  mac: u16 length + bytes
+ *   server  welcome    (kind 5)  identity: u16 length + UTF-8 bytes
+ *        or reject     (kind 6)  reason: u16 length + UTF-8 bytes
+ *
+ * A server that does not require authentication answers hello with
+ * welcome straight away, so a client without a secret learns at connect
+ * time whether it may log there. Version 3 added the handshake, sync and
+ * batch frames; clients older than that send records without one.
+ *
+ * A client that needs to know its records are on disk follows them with
+ * a sync, which the server answers once every record the connection sent
+ * before it has been written and fsynced (see log_component's writer.rs):
+ *
+ *   client  sync  (kind 7)  seq: u64
+ *   server  ack   (kind 8)  seq: u64, then error: u16 length + UTF-8
+ *                           bytes, empty when the records were persisted
+ *
+ * A client that compresses (see compress.rs) offers its codecs after the
+ * handshake and then sends records in batches:
+ *
+ *   client  offer   (kind 9)   codecs: u16 length + UTF-8 names, comma
+ *                              separated, most preferred first
+ *   server  accept  (kind 10)  codec: u16 length + UTF-8 name, empty if
+ *                              none of them is allowed
+ *   client  batch   (kind 11)  codec: u8 (1 zstd, 2 deflate), then the
+ *                              rest of the body is complete record frames,
+ *                              back to back, compressed with that codec
+ * */
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::compress::Codec;
+
+pub const PROTOCOL_VERSION: u8 = 3;
+
+//Oldest version the decoder still understands; version 1 records simply
+//carry no fields.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+//Upper bound on a single frame so a corrupt length prefix cannot make
+//the receiver try to buffer gigabytes before failing.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+pub(crate) const LENGTH_PREFIX: usize = 4;
+const KIND_RECORD: u8 = 1;
+const KIND_HELLO: u8 = 2;
+const KIND_CHALLENGE: u8 = 3;
+const KIND_AUTH: u8 = 4;
+const KIND_WELCOME: u8 = 5;
+const KIND_REJECT: u8 = 6;
+const KIND_SYNC: u8 = 7;
+const KIND_ACK: u8 = 8;
+const KIND_OFFER: u8 = 9;
+const KIND_ACCEPT: u8 = 10;
+const KIND_BATCH: u8 = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Trace => "TRACE",
+            Severity::Debug => "DEBUG",
+            Severity::Info => "INFO",
+            Severity::Warn => "WARN",
+            Severity::Error => "ERROR",
+        }
+    }
+
+    fn to_wire(self) -> u8 {
+        match self {
+            Severity::Trace => 0,
+            Severity::Debug => 1,
+            Severity::Info => 2,
+            Severity::Warn => 3,
+            Severity::Error => 4,
+        }
+    }
+
+    fn from_wire(value: u8) -> Result<Severity, ProtocolError> {
+        match value {
+            0 => Ok(Severity::Trace),
+            1 => Ok(Severity::Debug),
+            2 => Ok(Severity::Info),
+            3 => Ok(Severity::Warn),
+            4 => Ok(Severity::Error),
+            x => Err(ProtocolError::InvalidSeverity(x)),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Severity, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "trace" => Ok(Severity::Trace),
+            "debug" => Ok(Severity::Debug),
+            "info" => Ok(Severity::Info),
+            "warn" | "warning" => Ok(Severity::Warn),
+            "error" => Ok(Severity::Error),
+            _ => Err(format!("unknown severity \"{s}\"")),
+        }
+    }
+}
+
+//One log message as produced by a single log_send call. Fields are
+//key/value pairs kept in the order the caller supplied them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub sender: String,
+    pub timestamp: u64,
+    pub severity: Severity,
+    pub payload: String,
+    pub fields: Vec<(String, String)>,
+}
+
+impl Record {
+    pub fn new(sender: &str, severity: Severity, payload: &str) -> Record {
+        Record {
+            sender: sender.to_string(),
+            timestamp: now_millis(),
+            severity,
+            payload: payload.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn with_fields(mut self, fields: &[(&str, &str)]) -> Record {
+        self.fields
+            .extend(fields.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        self
+    }
+
+    //Value of the first field named `key`, if any.
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Record(Record),
+    //Client: the application name it wants to authenticate as
+    Hello { app: String },
+    //Server: random bytes the client must sign
+    Challenge { nonce: Vec<u8> },
+    //Client: HMAC over the challenge and its application name
+    Auth { mac: Vec<u8> },
+    //Server: the handshake succeeded; records are attributed to `identity`
+    Welcome { identity: String },
+    //Server: the handshake failed and the connection is about to close
+    Reject { reason: String },
+    //Client: acknowledge everything sent before this once it is on disk
+    Sync { seq: u64 },
+    //Server: answers the sync with the same `seq`; `error` says why the
+    //records may not have been persisted
+    Ack { seq: u64, error: Option<String> },
+    //Client: codec names it can compress batches with, preferred first
+    Offer { codecs: Vec<String> },
+    //Server: the codec batches on this connection use, if any
+    Accept { codec: Option<Codec> },
+    //Client: record frames back to back, compressed with `codec`
+    Batch { codec: Codec, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    FrameTooLarge(usize),
+    Truncated,
+    InvalidUtf8,
+    InvalidSeverity(u8),
+    UnknownCodec(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v}"),
+            ProtocolError::UnknownKind(k) => write!(f, "unknown frame kind {k}"),
+            ProtocolError::FrameTooLarge(n) => write!(f, "frame of {n} bytes exceeds the {MAX_FRAME_LEN} byte limit"),
+            ProtocolError::Truncated => write!(f, "frame body is shorter than its header claims"),
+            ProtocolError::InvalidUtf8 => write!(f, "frame contains invalid UTF-8"),
+            ProtocolError::InvalidSeverity(s) => write!(f, "invalid severity {s}"),
+            ProtocolError::UnknownCodec(c) => write!(f, "unknown codec {c}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+//Serializes a frame, length prefix included, ready for write_all.
+pub fn encode_frame(frame: &Frame) -> Vec<u8> {
+    let mut body = vec![PROTOCOL_VERSION];
+    match frame {
+        Frame::Record(record) => {
+            body.push(KIND_RECORD);
+            encode_record(&mut body, record);
+        }
+        Frame::Hello { app } => {
+            body.push(KIND_HELLO);
+            put_str16(&mut body, app);
+        }
+        Frame::Challenge { nonce } => {
+            body.push(KIND_CHALLENGE);
+            put_bytes16(&mut body, nonce);
+        }
+        Frame::Auth { mac } => {
+            body.push(KIND_AUTH);
+            put_bytes16(&mut body, mac);
+        }
+        Frame::Welcome { identity } => {
+            body.push(KIND_WELCOME);
+            put_str16(&mut body, identity);
+        }
+        Frame::Reject { reason } => {
+            body.push(KIND_REJECT);
+            put_str16(&mut body, reason);
+        }
+        Frame::Sync { seq } => {
+            body.push(KIND_SYNC);
+            body.extend_from_slice(&seq.to_be_bytes());
+        }
+        Frame::Ack { seq, error } => {
+            body.push(KIND_ACK);
+            body.extend_from_slice(&seq.to_be_bytes());
+            put_str16(&mut body, error.as_deref().unwrap_or_default());
+        }
+        Frame::Offer { codecs } => {
+            body.push(KIND_OFFER);
+            put_str16(&mut body, &codecs.join(","));
+        }
+        Frame::Accept { codec } => {
+            body.push(KIND_ACCEPT);
+            put_str16(&mut body, codec.map_or("", |c| c.as_str()));
+        }
+        Frame::Batch { codec, data } => {
+            body.push(KIND_BATCH);
+            body.push(codec.to_wire());
+            body.extend_from_slice(data);
+        }
+    }
+
+    let mut out = Vec::with_capacity(LENGTH_PREFIX + body.len());
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+fn encode_record(out: &mut Vec<u8>, record: &Record) {
+    put_str16(out, &record.sender);
+    out.extend_from_slice(&record.timestamp.to_be_bytes());
+    out.push(record.severity.to_wire());
+    put_str32(out, &record.payload);
+
+    let count = record.fields.len().min(u16::MAX as usize);
+    out.extend_from_slice(&(count as u16).to_be_bytes());
+    for (key, value) in &record.fields[..count] {
+        put_str16(out, key);
+        put_str32(out, value);
+    }
+}
+
+fn put_str16(out: &mut Vec<u8>, s: &str) {
+    let mut end = s.len().min(u16::MAX as usize);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    out.extend_from_slice(&(end as u16).to_be_bytes());
+    out.extend_from_slice(&s.as_bytes()[..end]);
+}
+
+fn put_bytes16(out: &mut Vec<u8>, bytes: &[u8]) {
+    let len = bytes.len().min(u16::MAX as usize);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+    out.extend_from_slice(&bytes[..len]);
+}
+
+fn put_str32(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+//Accumulates raw bytes from a stream and hands back complete frames.
+//Feed it whatever read() returned with push, then drain next_frame
+//until it yields Ok(None).
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder { buf: Vec::new() }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    //Number of bytes received that do not yet form a complete frame.
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, ProtocolError> {
+        if self.buf.len() < LENGTH_PREFIX {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(ProtocolError::FrameTooLarge(len));
+        }
+        if self.buf.len() < LENGTH_PREFIX + len {
+            return Ok(None);
+        }
+
+        let body: Vec<u8> = self.buf.drain(..LENGTH_PREFIX + len).skip(LENGTH_PREFIX).collect();
+        decode_body(&body).map(Some)
+    }
+}
+
+fn decode_body(body: &[u8]) -> Result<Frame, ProtocolError> {
+    let mut cursor = Cursor { buf: body, pos: 0 };
+    let version = cursor.u8()?;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    match cursor.u8()? {
+        KIND_RECORD => {
+            let sender = cursor.str16()?;
+            let timestamp = cursor.u64()?;
+            let severity = Severity::from_wire(cursor.u8()?)?;
+            let payload = cursor.str32()?;
+            let mut fields = Vec::new();
+            if version >= 2 {
+                for _ in 0..cursor.u16()? {
+                    let key = cursor.str16()?;
+                    let value = cursor.str32()?;
+                    fields.push((key, value));
+                }
+            }
+            Ok(Frame::Record(Record { sender, timestamp, severity, payload, fields }))
+        }
+        KIND_HELLO => Ok(Frame::Hello { app: cursor.str16()? }),
+        KIND_CHALLENGE => Ok(Frame::Challenge { nonce: cursor.bytes16()? }),
+        KIND_AUTH => Ok(Frame::Auth { mac: cursor.bytes16()? }),
+        KIND_WELCOME => Ok(Frame::Welcome { identity: cursor.str16()? }),
+        KIND_REJECT => Ok(Frame::Reject { reason: cursor.str16()? }),
+        KIND_SYNC => Ok(Frame::Sync { seq: cursor.u64()? }),
+        KIND_ACK => {
+            let seq = cursor.u64()?;
+            let error = cursor.str16()?;
+            Ok(Frame::Ack { seq, error: if error.is_empty() { None } else { Some(error) } })
+        }
+        KIND_OFFER => {
+            let codecs = cursor.str16()?;
+            Ok(Frame::Offer { codecs: codecs.split(',').filter(|c| !c.is_empty()).map(str::to_string).collect() })
+        }
+        KIND_ACCEPT => match cursor.str16()?.as_str() {
+            "" => Ok(Frame::Accept { codec: None }),
+            name => Ok(Frame::Accept { codec: Some(name.parse().map_err(|_| ProtocolError::UnknownCodec(name.to_string()))?) }),
+        },
+        KIND_BATCH => {
+            let wire = cursor.u8()?;
+            let codec = Codec::from_wire(wire).ok_or(ProtocolError::UnknownCodec(wire.to_string()))?;
+            Ok(Frame::Batch { codec, data: cursor.rest().to_vec() })
+        }
+        kind => Err(ProtocolError::UnknownKind(kind)),
+    }
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&mut self) -> &'a [u8] {
+        let out = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        out
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.buf.len() - self.pos < n {
+            return Err(ProtocolError::Truncated);
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, ProtocolError> {
+        let b = self.take(8)?;
+        let mut arr = [0; 8];
+        arr.copy_from_slice(b);
+        Ok(u64::from_be_bytes(arr))
+    }
+
+    fn str16(&mut self) -> Result<String, ProtocolError> {
+        let len = self.u16()? as usize;
+        self.utf8(len)
+    }
+
+    fn bytes16(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn str32(&mut self) -> Result<String, ProtocolError> {
+        let len = self.u32()? as usize;
+        self.utf8(len)
+    }
+
+    fn utf8(&mut self, len: usize) -> Result<String, ProtocolError> {
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidUtf8)
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/* rotation - how rotated log files are named
+ *
+ * Shared by log_component, which rotates its log file, and log_query,
+ * which reads it back with everything rotated before it. A full log file
+ * is renamed to <log_file>.<UTC timestamp>; should that name be taken,
+ * -1, -2 and so on are added after the timestamp. Rotated files may then
+ * be gzipped to <name>.gz.
+ *
+ * The timestamp starts with the year, which keeps unrelated files such as
+ * systemlog.txt.bak out of the list.
+ * */
+
+const GZIP_SUFFIX: &str = ".gz";
+
+//The name for `base` rotated at `stamp`, which no file, gzipped or not,
+//has yet
+pub fn rotated_name( base: &Path, stamp: &str ) -> PathBuf {
+    let base = base.as_os_str().to_string_lossy();
+    let mut candidate = PathBuf::from(format!("{base}.{stamp}"));
+    let mut n = 1;
+    while candidate.exists() || PathBuf::from(format!("{}{GZIP_SUFFIX}", candidate.display())).exists() {
+        candidate = PathBuf::from(format!("{base}.{stamp}-{n}"));
+        n += 1;
+    }
+    candidate
+}
+
+//Rotated files belonging to `base`, oldest first
+pub fn rotated_files( base: &Path ) -> io::Result<Vec<PathBuf>> {
+    let dir = match base.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let prefix = format!("{}.", base.file_name().unwrap_or_default().to_string_lossy());
+    let mut files: Vec<((String, u64), PathBuf)> = fs::read_dir(&dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().into_owned();
+            let order = order(name.strip_prefix(&prefix)?)?;
+            Some((order, e.path()))
+        })
+        .collect();
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+//Sorts a rotated file by its timestamp and then its -n, whether it is
+//gzipped or not; None for names that are not rotated files
+fn order( suffix: &str ) -> Option<(String, u64)> {
+    let suffix = suffix.strip_suffix(GZIP_SUFFIX).unwrap_or(suffix);
+    if !suffix.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    match suffix.rsplit_once('-') {
+        Some((stamp, n)) if n.parse::<u64>().is_ok() => Some((stamp.to_string(), n.parse().ok()?)),
+        _ => Some((suffix.to_string(), 0)),
+    }
+}
@@ -0,0 +1,92 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use config::{Config, Environment, File, FileFormat};
+
+use crate::error::LogError;
+
+/* settings - finding and reading config.toml
+ *
+ * Shared by send_log, log_component and the test programs so they all
+ * resolve the configuration the same way. The first of these is used:
+ *
+ *  1. the path given with --config on the command line
+ *  2. the path in the SLUMCS_CONFIG environment variable
+ *  3. ./config.toml
+ *  4. $XDG_CONFIG_HOME/slumcs/config.toml (~/.config/slumcs/config.toml)
+ *  5. /etc/slumcs/config.toml
+ *
+ * A path given with --config or SLUMCS_CONFIG may leave out ".toml".
+ *
+ * Any key can then be overridden from the environment by upper-casing it
+ * and prefixing SLUMCS_, e.g. SLUMCS_LOG_PORT=9000.
+ * */
+
+pub const CONFIG_ENV: &str = "SLUMCS_CONFIG";
+pub const ENV_PREFIX: &str = "SLUMCS";
+
+//Where to look when neither --config nor SLUMCS_CONFIG is given
+pub fn search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("config.toml")];
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = config_home {
+        paths.push(dir.join("slumcs").join("config.toml"));
+    }
+    paths.push(PathBuf::from("/etc/slumcs/config.toml"));
+    paths
+}
+
+//Picks the configuration file; `explicit` is the --config argument
+pub fn locate( explicit: Option<&str> ) -> Result<PathBuf, LogError> {
+    if let Some(path) = explicit {
+        return Ok(PathBuf::from(path));
+    }
+    if let Some(path) = env::var_os(CONFIG_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+    let candidates = search_paths();
+    match candidates.iter().find(|p| p.is_file()) {
+        Some(path) => Ok(path.clone()),
+        None => {
+            let tried: Vec<String> = candidates.iter().map(|p| p.display().to_string()).collect();
+            Err(LogError::ConfigMissing(format!(
+                "no configuration file given with --config or {CONFIG_ENV}, and none found at {}",
+                tried.join(", ")
+            )))
+        }
+    }
+}
+
+//Reads a configuration file and applies SLUMCS_* overrides on top. As
+//with config::File::with_name, a path without its extension, such as
+//"../config", finds "../config.toml".
+pub fn load( path: &Path ) -> Result<Config, LogError> {
+    let mut toml = path.as_os_str().to_owned();
+    toml.push(".toml");
+    let path = match PathBuf::from(toml) {
+        _ if path.is_file() => path.to_path_buf(),
+        toml if toml.is_file() => toml,
+        _ => return Err(LogError::ConfigMissing(format!("{} does not exist", path.display()))),
+    };
+    Config::builder()
+        .add_source(File::from(path.as_path()).format(FileFormat::Toml))
+        .add_source(Environment::with_prefix(ENV_PREFIX).try_parsing(true))
+        .build()
+        .map_err(|e| LogError::ConfigMissing(format!("{}: {e}", path.display())))
+}
+
+//Takes `--config <path>` or `--config=<path>` out of `args`, leaving the
+//remaining arguments for the program
+pub fn take_config_arg( args: &mut Vec<String> ) -> Result<Option<String>, String> {
+    let Some(i) = args.iter().position(|a| a == "--config" || a.starts_with("--config=")) else {
+        return Ok(None);
+    };
+    let arg = args.remove(i);
+    match arg.strip_prefix("--config=") {
+        Some(path) => Ok(Some(path.to_string())),
+        None if i < args.len() => Ok(Some(args.remove(i))),
+        None => Err("--config needs a path".to_string()),
+    }
+}
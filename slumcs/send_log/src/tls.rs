@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use config::Config;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile::Item;
+
+use crate::error::LogError;
+
+/* tls - encrypting the connection to log_component
+ *
+ * With tls = true in config.toml the Log talks TLS to the server and
+ * checks the server's certificate against tls_ca_file:
+ *
+ *  tls_ca_file           PEM file with the CA certificate(s) to trust
+ *  tls_server_name       name the server certificate must carry (log_ip)
+ *  tls_client_cert_file  certificate chain to present to a server that
+ *  tls_client_key_file   requires client certificates, and its key
+ *
+ * The PEM loaders are public so log_component reads its certificate, key
+ * and client CA the same way.
+ * */
+
+//Everything needed to open a TLS session to the server
+pub(crate) struct TlsClient {
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) server_name: ServerName,
+}
+
+//Reads the tls_* keys; None when tls is off
+pub(crate) fn client_from_config( settings: &Config, log_ip: &str ) -> Result<Option<TlsClient>, LogError> {
+    if !settings.get_bool("tls").unwrap_or(false) {
+        return Ok(None);
+    }
+    let get = |key: &str| settings.get_string(key).ok();
+    let invalid = |key: &str, e: String| LogError::ConfigInvalid(key.to_string(), e);
+
+    let ca_file = get("tls_ca_file").ok_or_else(|| LogError::ConfigKeyMissing("tls_ca_file".to_string()))?;
+    let roots = load_roots(&ca_file).map_err(|e| invalid("tls_ca_file", e.to_string()))?;
+    let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+
+    let config = match (get("tls_client_cert_file"), get("tls_client_key_file")) {
+        (Some(cert_file), Some(key_file)) => {
+            let certs = load_certs(&cert_file).map_err(|e| invalid("tls_client_cert_file", e.to_string()))?;
+            let key = load_private_key(&key_file).map_err(|e| invalid("tls_client_key_file", e.to_string()))?;
+            builder.with_client_auth_cert(certs, key).map_err(|e| invalid("tls_client_key_file", e.to_string()))?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        (Some(_), None) => return Err(LogError::ConfigKeyMissing("tls_client_key_file".to_string())),
+        (None, Some(_)) => return Err(LogError::ConfigKeyMissing("tls_client_cert_file".to_string())),
+    };
+
+    let name = get("tls_server_name").unwrap_or_else(|| log_ip.to_string());
+    let server_name = ServerName::try_from(name.as_str()).map_err(|e| invalid("tls_server_name", e.to_string()))?;
+    Ok(Some(TlsClient { config: Arc::new(config), server_name }))
+}
+
+//All certificates in a PEM file, in file order
+pub fn load_certs( path: &str ) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no certificates in {path}")));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+//The first PKCS#8, PKCS#1 (RSA) or SEC1 (EC) private key in a PEM file
+pub fn load_private_key( path: &str ) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        if let Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) = item {
+            return Ok(PrivateKey(der));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("no private key in {path}")))
+}
+
+//A trust store holding every certificate in a PEM file
+pub fn load_roots( path: &str ) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    }
+    Ok(roots)
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rustls::{ClientConnection, StreamOwned};
+
+/* transport - what a Log's frames travel over
+ *
+ * Picked with log_transport in config.toml:
+ *
+ *  tcp   (default) a TCP connection to log_ip:log_port, optionally TLS
+ *  unix  a Unix domain socket at log_socket, for a server on this host;
+ *        only on Unix-like platforms
+ *  udp   one datagram per record to log_ip:log_port. Fire-and-forget:
+ *        nothing tells the client whether a record arrived, and records
+ *        larger than a datagram are dropped
+ *
+ * log_component listens on each of these when configured to (see its
+ * main.rs), writing what arrives through the same pipeline.
+ * */
+
+//Largest UDP payload over IPv4
+pub(crate) const MAX_DATAGRAM: usize = 65_507;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TransportKind {
+    Tcp,
+    Unix,
+    Udp,
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TransportKind, String> {
+        match s {
+            "tcp" => Ok(TransportKind::Tcp),
+            "unix" => Ok(TransportKind::Unix),
+            "udp" => Ok(TransportKind::Udp),
+            _ => Err(format!("unknown log_transport \"{s}\", expected tcp, unix or udp")),
+        }
+    }
+}
+
+//Where the server is, for the chosen transport
+pub(crate) enum Transport {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Udp(String),
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Transport::Unix(path) => write!(f, "{}", path.display()),
+            Transport::Udp(addr) => write!(f, "{addr} (udp)"),
+        }
+    }
+}
+
+impl Transport {
+    pub(crate) fn open(&self, timeout: Duration) -> io::Result<Stream> {
+        match self {
+            Transport::Tcp(addr) => Ok(Stream::Tcp(connect_tcp(addr, timeout)?)),
+            #[cfg(unix)]
+            Transport::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            Transport::Udp(addr) => {
+                let socket = UdpSocket::bind(if addr.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })?;
+                socket.connect(addr.as_str())?;
+                Ok(Stream::Udp(socket))
+            }
+        }
+    }
+}
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Udp(UdpSocket),
+}
+
+impl Stream {
+    pub(crate) fn is_datagram(&self) -> bool {
+        matches!(self, Stream::Udp(_))
+    }
+
+    pub(crate) fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => {
+                tcp.set_read_timeout(timeout)?;
+                tcp.set_write_timeout(timeout)
+            }
+            Stream::Tls(tls) => {
+                tls.sock.set_read_timeout(timeout)?;
+                tls.sock.set_write_timeout(timeout)
+            }
+            #[cfg(unix)]
+            Stream::Unix(unix) => {
+                unix.set_read_timeout(timeout)?;
+                unix.set_write_timeout(timeout)
+            }
+            Stream::Udp(udp) => {
+                udp.set_read_timeout(timeout)?;
+                udp.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    pub(crate) fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read(buffer),
+            Stream::Tls(tls) => tls.read(buffer),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.read(buffer),
+            Stream::Udp(udp) => udp.recv(buffer),
+        }
+    }
+
+    //Writes one whole frame
+    pub(crate) fn write_all(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.write_all(frame),
+            Stream::Tls(tls) => {
+                tls.write_all(frame)?;
+                tls.flush()
+            }
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.write_all(frame),
+            Stream::Udp(udp) => udp.send(frame).map(|_| ()),
+        }
+    }
+
+    pub(crate) fn shutdown(self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.shutdown(Shutdown::Both),
+            Stream::Tls(mut tls) => {
+                tls.conn.send_close_notify();
+                while tls.conn.wants_write() {
+                    tls.conn.write_tls(&mut tls.sock)?;
+                }
+                tls.sock.shutdown(Shutdown::Both)
+            }
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.shutdown(Shutdown::Both),
+            Stream::Udp(_) => Ok(()),
+        }
+    }
+
+    //A write to a socket the server has already closed usually still
+    //succeeds, silently losing the record. Checking first catches the close.
+    pub(crate) fn peer_closed(&mut self) -> bool {
+        match self {
+            Stream::Tcp(tcp) => tcp_peer_closed(tcp),
+            Stream::Tls(tls) => tls_peer_closed(tls),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix_peer_closed(unix),
+            //There is no connection to lose
+            Stream::Udp(_) => false,
+        }
+    }
+}
+
+//Frames are written whole, and batched when configured to be, so Nagle's
+//algorithm would only hold records back waiting for the handshake's ACKs
+fn connect_tcp(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, format!("{addr} did not resolve"));
+    for sock_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&sock_addr, timeout) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+fn tcp_peer_closed(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let mut byte = [0; 1];
+    let closed = match stream.peek(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    };
+    closed || stream.set_nonblocking(false).is_err()
+}
+
+//Unix sockets cannot peek on stable Rust. The server sends nothing after
+//the handshake, so reading is just as good: anything that does arrive
+//is of no use to the client anyway.
+#[cfg(unix)]
+fn unix_peer_closed(stream: &mut UnixStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let mut byte = [0; 1];
+    let closed = match stream.read(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    };
+    closed || stream.set_nonblocking(false).is_err()
+}
+
+//The server may still send TLS records of its own (session tickets, or
+//the alert for a rejected client certificate), so rather than peeking,
+//read whatever is waiting and let rustls say whether the session is over
+fn tls_peer_closed(tls: &mut StreamOwned<ClientConnection, TcpStream>) -> bool {
+    if tls.sock.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let closed = loop {
+        match tls.conn.read_tls(&mut tls.sock) {
+            Ok(0) => break true,
+            Ok(_) => match tls.conn.process_new_packets() {
+                Ok(state) if state.peer_has_closed() => break true,
+                Ok(_) => continue,
+                Err(_) => break true,
+            },
+            Err(e) => break e.kind() != io::ErrorKind::WouldBlock,
+        }
+    };
+    closed || tls.sock.set_nonblocking(false).is_err()
+}
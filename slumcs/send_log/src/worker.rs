@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::connection::Connection;
+use crate::{deliver, LogError};
+
+/* worker - background sender used when a Log is in async mode
+ *
+ * The Log keeps the sending half of a bounded channel; the worker thread
+ * owns the Connection and does all socket writes, so log_send only has to
+ * enqueue a frame. If the channel is full the record is dropped (and
+ * counted) rather than blocking the caller. Dropping the Log, or calling
+ * log_disconnect, closes the channel; the worker then delivers whatever is
+ * still queued, retrying for up to drain_timeout, before it exits.
+ * */
+
+//How often the worker retries a connection while it has records waiting
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+enum Command {
+    Send(Vec<u8>),
+    //Reply with whether everything sent so far reached the server
+    Flush(mpsc::Sender<bool>),
+    //Reply once the server has acknowledged everything sent so far
+    Sync(mpsc::Sender<Result<(), LogError>>),
+}
+
+pub(crate) struct Worker {
+    tx: Option<SyncSender<Command>>,
+    handle: Option<JoinHandle<()>>,
+    overflowed: Arc<AtomicU64>,
+}
+
+impl Worker {
+    pub(crate) fn spawn(conn: Connection, sender: String, queue: usize, drain_timeout: Duration) -> Worker {
+        let (tx, rx) = mpsc::sync_channel(queue.max(1));
+        let overflowed = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&overflowed);
+        let handle = thread::Builder::new()
+            .name("send_log".to_string())
+            .spawn(move || run(conn, sender, rx, counter, drain_timeout))
+            .expect("failed to spawn send_log worker thread");
+        Worker { tx: Some(tx), handle: Some(handle), overflowed }
+    }
+
+    pub(crate) fn send(&self, frame: Vec<u8>) -> Result<(), LogError> {
+        let tx = self.tx.as_ref().ok_or(LogError::Disconnected)?;
+        match tx.try_send(Command::Send(frame)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(LogError::Disconnected),
+        }
+    }
+
+    //Blocks until the worker has written everything queued before this call
+    pub(crate) fn flush(&self) -> Result<(), LogError> {
+        let tx = self.tx.as_ref().ok_or(LogError::Disconnected)?;
+        let (ack_tx, ack_rx) = mpsc::channel();
+        tx.send(Command::Flush(ack_tx)).map_err(|_| LogError::Disconnected)?;
+        match ack_rx.recv() {
+            Ok(true) => Ok(()),
+            _ => Err(LogError::Disconnected),
+        }
+    }
+
+    //Blocks until the server has persisted everything queued before this call
+    pub(crate) fn sync(&self) -> Result<(), LogError> {
+        let tx = self.tx.as_ref().ok_or(LogError::Disconnected)?;
+        let (ack_tx, ack_rx) = mpsc::channel();
+        tx.send(Command::Sync(ack_tx)).map_err(|_| LogError::Disconnected)?;
+        ack_rx.recv().unwrap_or(Err(LogError::Disconnected))
+    }
+
+    //Closes the queue and waits for the worker to drain it and exit
+    pub(crate) fn stop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(mut conn: Connection, sender: String, rx: Receiver<Command>, overflowed: Arc<AtomicU64>, drain_timeout: Duration) {
+    loop {
+        //With nothing buffered there is no reason to wake up until the next
+        //command; a batch being collected is sent when it is due
+        let command = match conn.batch_wait() {
+            _ if conn.is_drained() => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(wait) if conn.is_connected() => rx.recv_timeout(wait),
+            _ => rx.recv_timeout(RETRY_INTERVAL),
+        };
+        conn.note_dropped(overflowed.swap(0, Ordering::Relaxed));
+
+        match command {
+            Ok(Command::Send(frame)) => {
+                let _ = deliver(&mut conn, &sender, frame);
+            }
+            Ok(Command::Flush(ack)) => {
+                conn.flush();
+                let _ = ack.send(conn.is_drained());
+            }
+            Ok(Command::Sync(ack)) => {
+                let _ = ack.send(conn.sync());
+            }
+            Err(RecvTimeoutError::Timeout) => conn.flush(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    let deadline = Instant::now() + drain_timeout;
+    loop {
+        conn.flush();
+        if conn.is_drained() || Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+    let _ = conn.shutdown();
+}
@@ -0,0 +1,432 @@
+use send_log::protocol::{encode_frame, Frame, FrameDecoder, Record};
+use send_log::settings::{locate, take_config_arg};
+use send_log::{
+    log_flush, log_forward, log_send_sync, log_send_with, log_sync, try_log_connect, try_log_connect_async, try_log_connect_to, try_log_connect_with,
+    try_log_disconnect, try_log_send, LogError, Severity,
+};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+//Writes a throwaway config file and returns its path
+fn write_config(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("send_log_{}_{}.toml", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+//Reads frames off the server side of a connection until `count` records arrived
+fn read_records(stream: &mut TcpStream, count: usize) -> Vec<Record> {
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut decoder = FrameDecoder::new();
+    let mut records = vec![];
+    let mut buf = [0; 4096];
+    while records.len() < count {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed after {} records", records.len());
+        decoder.push(&buf[..n]);
+        while let Some(Frame::Record(record)) = decoder.next_frame().unwrap() {
+            records.push(record);
+        }
+    }
+    records
+}
+
+fn payloads(records: &[Record]) -> Vec<&str> {
+    records.iter().map(|r| r.payload.as_str()).collect()
+}
+
+#[test]
+fn missing_config_file() {
+    let result = try_log_connect("/nonexistent/send_log/config.toml");
+    assert!(matches!(result, Err(LogError::ConfigMissing(_))));
+}
+
+//As with config::File::with_name, ".toml" may be left off
+#[test]
+fn config_path_without_extension() {
+    let path = write_config("no_extension", "log_ip = \"127.0.0.1\"\n");
+    let stem = path.with_extension("");
+    let result = try_log_connect(stem.to_str().unwrap());
+    assert!(matches!(result, Err(LogError::ConfigKeyMissing(ref key)) if key == "log_port"), "{:?}", result.err());
+}
+
+#[test]
+fn missing_config_key() {
+    let path = write_config("missing_key", "log_ip = \"127.0.0.1\"\n");
+    let result = try_log_connect(path.to_str().unwrap());
+    assert!(matches!(result, Err(LogError::ConfigKeyMissing(ref key)) if key == "log_port"));
+}
+
+#[test]
+fn connection_refused() {
+    //Grab a free port, then close it again so nothing is listening there
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let path = write_config("refused", &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\n"));
+    let result = try_log_connect(path.to_str().unwrap());
+    assert!(matches!(result, Err(LogError::ConnectRefused(..))));
+}
+
+#[test]
+fn invalid_port() {
+    let path = write_config("bad_port", "log_ip = \"127.0.0.1\"\nlog_port = 99999\n");
+    let result = try_log_connect(path.to_str().unwrap());
+    assert!(matches!(result, Err(LogError::ConfigInvalid(ref key, _)) if key == "log_port"));
+}
+
+//With connect_required = false a Log starts buffering instead of failing,
+//and forwarded records arrive exactly as they were built
+#[test]
+fn starts_offline_and_forwards_records() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let path = write_config(
+        "offline_start",
+        &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nconnect_required = false\nreconnect_initial_ms = 10\n"),
+    );
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    let mut record = Record::new("billing[42]", Severity::Warn, "relayed").with_fields(&[("origin_peer", "10.0.0.7:5000")]);
+    record.timestamp = 1_700_000_000_000;
+    log_forward(&mut log, record.clone()).unwrap();
+    assert!(matches!(log_flush(&mut log), Err(LogError::Disconnected)));
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let stream = common::accept(&listener);
+    thread::sleep(Duration::from_millis(20));
+    try_log_send(&mut log, "direct").unwrap();
+    let mut stream = stream.join().unwrap();
+    let records = read_records(&mut stream, 2);
+    assert_eq!(records[0], record);
+    assert_eq!(records[1].payload, "direct");
+}
+
+//A record too large for one frame is refused rather than sent for the
+//server to drop
+#[test]
+fn refuses_oversized_records() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let path = write_config("oversized", &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nconnect_required = false\n"));
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    let huge = "x".repeat(16 * 1024 * 1024);
+    assert!(matches!(try_log_send(&mut log, &huge), Err(LogError::RecordTooLarge(len)) if len > huge.len()));
+    let big = Record::new("relay", Severity::Info, &huge);
+    assert!(matches!(log_forward(&mut log, big), Err(LogError::RecordTooLarge(_))));
+    try_log_send(&mut log, "small enough").unwrap();
+}
+
+//Each transport asks for the keys it needs and refuses what it cannot do
+#[test]
+fn transport_config_errors() {
+    let path = write_config("unknown_transport", "log_transport = \"pigeon\"\n");
+    let result = try_log_connect(path.to_str().unwrap());
+    assert!(matches!(result, Err(LogError::ConfigInvalid(ref key, _)) if key == "log_transport"));
+
+    let path = write_config("unix_no_socket", "log_transport = \"unix\"\nlog_ip = \"127.0.0.1\"\nlog_port = 1\n");
+    let result = try_log_connect(path.to_str().unwrap());
+    assert!(matches!(result, Err(LogError::ConfigKeyMissing(ref key)) if key == "log_socket"));
+
+    let path = write_config(
+        "udp_auth",
+        "log_transport = \"udp\"\nlog_ip = \"127.0.0.1\"\nlog_port = 1\nauth_secret = \"x\"\n",
+    );
+    let result = try_log_connect(path.to_str().unwrap());
+    assert!(matches!(result, Err(LogError::ConfigInvalid(ref key, _)) if key == "auth_secret"));
+
+    let path = write_config("udp_tls", "log_transport = \"udp\"\nlog_ip = \"127.0.0.1\"\nlog_port = 1\ntls = true\n");
+    let result = try_log_connect(path.to_str().unwrap());
+    assert!(matches!(result, Err(LogError::ConfigInvalid(ref key, _)) if key == "tls"));
+}
+
+//Callers with a plain address or their own settings need no config file
+#[test]
+fn connect_without_config_file() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = common::accept(&listener);
+    let mut log = try_log_connect_to(&format!("127.0.0.1:{port}")).unwrap();
+    let mut server = server.join().unwrap();
+    try_log_send(&mut log, "by address").unwrap();
+    assert_eq!(payloads(&read_records(&mut server, 1)), ["by address"]);
+
+    let settings = config::Config::builder()
+        .set_override("log_ip", "127.0.0.1")
+        .unwrap()
+        .set_override("log_port", port)
+        .unwrap()
+        .set_override("log_sender", "built-by-hand")
+        .unwrap()
+        .build()
+        .unwrap();
+    let server = common::accept(&listener);
+    let mut log = try_log_connect_with(&settings).unwrap();
+    let mut server = server.join().unwrap();
+    try_log_send(&mut log, "by settings").unwrap();
+    assert_eq!(read_records(&mut server, 1)[0].sender, "built-by-hand");
+
+    assert!(matches!(try_log_connect_to("127.0.0.1"), Err(LogError::ConfigInvalid(..))));
+}
+
+//--config is taken out of the arguments in either spelling and wins over
+//any other location
+#[test]
+fn config_argument() {
+    let mut args: Vec<String> = ["prog", "--config", "a.toml", "4"].map(String::from).to_vec();
+    assert_eq!(take_config_arg(&mut args), Ok(Some("a.toml".to_string())));
+    assert_eq!(args, ["prog", "4"]);
+
+    let mut args: Vec<String> = ["prog", "--config=b.toml"].map(String::from).to_vec();
+    assert_eq!(take_config_arg(&mut args), Ok(Some("b.toml".to_string())));
+    assert_eq!(args, ["prog"]);
+
+    assert!(take_config_arg(&mut vec!["prog".to_string(), "--config".to_string()]).is_err());
+    assert_eq!(take_config_arg(&mut vec!["prog".to_string()]), Ok(None));
+    assert_eq!(locate(Some("a.toml")).unwrap(), PathBuf::from("a.toml"));
+}
+
+//Records arrive intact and anything after disconnecting is refused
+#[test]
+fn send_then_disconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = write_config(
+        "send",
+        &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nlog_sender = \"api-test\"\n"),
+    );
+
+    let server = common::accept(&listener);
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    let mut server = server.join().unwrap();
+
+    try_log_send(&mut log, "first").unwrap();
+    log_send_with(&mut log, Severity::Error, "second", &[("code", "500")]).unwrap();
+    try_log_disconnect(&mut log).unwrap();
+
+    assert!(matches!(try_log_send(&mut log, "third"), Err(LogError::Disconnected)));
+    assert!(matches!(try_log_disconnect(&mut log), Err(LogError::Disconnected)));
+
+    let mut bytes = vec![];
+    server.read_to_end(&mut bytes).unwrap();
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&bytes);
+
+    let Ok(Some(Frame::Record(first))) = decoder.next_frame() else { panic!("expected a record") };
+    assert_eq!((first.sender.as_str(), first.severity, first.payload.as_str()), ("api-test", Severity::Info, "first"));
+    let Ok(Some(Frame::Record(second))) = decoder.next_frame() else { panic!("expected a record") };
+    assert_eq!(second.severity, Severity::Error);
+    assert_eq!(second.field("code"), Some("500"));
+    assert_eq!(decoder.next_frame(), Ok(None));
+}
+
+//log_sync returns once the server acks, and reports the error it acks with
+#[test]
+fn sync_waits_for_ack() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = write_config("sync", &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nack_timeout_ms = 500\n"));
+    let server = common::accept(&listener);
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    let mut server = server.join().unwrap();
+
+    let answers = thread::spawn(move || {
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; 4096];
+        let mut seen = vec![];
+        let mut syncs = 0;
+        while syncs < 3 {
+            let n = server.read(&mut buf).unwrap();
+            assert!(n > 0);
+            decoder.push(&buf[..n]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                let Frame::Sync { seq } = frame else {
+                    seen.push(frame);
+                    continue;
+                };
+                syncs += 1;
+                seen.push(Frame::Sync { seq });
+                //The third sync goes unanswered
+                let error = (syncs == 2).then(|| "disk full".to_string());
+                if syncs < 3 {
+                    server.write_all(&encode_frame(&Frame::Ack { seq, error })).unwrap();
+                }
+            }
+        }
+        seen
+    });
+
+    log_send_sync(&mut log, "durable").unwrap();
+    let result = log_sync(&mut log);
+    assert!(matches!(&result, Err(LogError::NotAcknowledged(reason)) if reason == "disk full"), "{result:?}");
+    let result = log_sync(&mut log);
+    assert!(matches!(&result, Err(LogError::NotAcknowledged(reason)) if reason.starts_with("no acknowledgement")), "{result:?}");
+
+    let seen = answers.join().unwrap();
+    assert!(matches!(&seen[0], Frame::Record(r) if r.payload == "durable"));
+    assert_eq!(&seen[1..], [Frame::Sync { seq: 1 }, Frame::Sync { seq: 2 }, Frame::Sync { seq: 3 }]);
+}
+
+//Batched records go out once enough of them are waiting, or once the
+//oldest has waited batch_delay_ms
+#[test]
+fn batches_by_count_and_time() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = write_config(
+        "batch",
+        &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nbatch_max_records = 3\nbatch_delay_ms = 200\n"),
+    );
+    let server = common::accept(&listener);
+    let mut log = try_log_connect_async(path.to_str().unwrap()).unwrap();
+    let mut server = server.join().unwrap();
+
+    try_log_send(&mut log, "1").unwrap();
+    try_log_send(&mut log, "2").unwrap();
+    server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(server.read(&mut [0; 64]).is_err(), "a partial batch went out early");
+    try_log_send(&mut log, "3").unwrap();
+    assert_eq!(payloads(&read_records(&mut server, 3)), ["1", "2", "3"]);
+
+    let start = std::time::Instant::now();
+    try_log_send(&mut log, "4").unwrap();
+    assert_eq!(payloads(&read_records(&mut server, 1)), ["4"]);
+    assert!(start.elapsed() >= Duration::from_millis(150), "{:?}", start.elapsed());
+}
+
+//Records sent while the server is away are delivered in order on reconnect
+#[test]
+fn reconnects_and_flushes_in_order() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = write_config(
+        "reconnect",
+        &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nreconnect_initial_ms = 10\n"),
+    );
+
+    let first = common::accept(&listener);
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    let mut first = first.join().unwrap();
+    try_log_send(&mut log, "one").unwrap();
+    assert_eq!(payloads(&read_records(&mut first, 1)), ["one"]);
+    drop(first);
+    thread::sleep(Duration::from_millis(50));
+    let second = common::accept(&listener);
+
+    //Noticing the close starts the backoff, so this one is only buffered
+    try_log_send(&mut log, "two").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    try_log_send(&mut log, "three").unwrap();
+
+    let mut second = second.join().unwrap();
+    assert_eq!(payloads(&read_records(&mut second, 2)), ["two", "three"]);
+}
+
+//A full buffer drops the oldest records and the server is told how many
+#[test]
+fn drop_oldest_when_buffer_full() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = write_config(
+        "drop",
+        &format!(
+            "log_ip = \"127.0.0.1\"\nlog_port = {port}\nreconnect_initial_ms = 100\n\
+             buffer_max_records = 2\nbuffer_policy = \"drop_oldest\"\n"
+        ),
+    );
+
+    let first = common::accept(&listener);
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    drop(first.join().unwrap());
+    thread::sleep(Duration::from_millis(50));
+
+    for i in 1..=5 {
+        try_log_send(&mut log, &i.to_string()).unwrap();
+    }
+    thread::sleep(Duration::from_millis(500));
+    let second = common::accept(&listener);
+    try_log_send(&mut log, "6").unwrap();
+
+    let mut second = second.join().unwrap();
+    let records = read_records(&mut second, 3);
+    assert_eq!(payloads(&records[..2]), ["5", "6"]);
+    assert_eq!(records[2].severity, Severity::Warn);
+    assert_eq!(records[2].field("dropped"), Some("4"));
+}
+
+//With a spool file, records buffered by one Log are sent by the next one
+#[test]
+fn spool_file_survives_restart() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let spool = std::env::temp_dir().join(format!("send_log_spool_{}.bin", std::process::id()));
+    let _ = std::fs::remove_file(&spool);
+    let path = write_config(
+        "spool",
+        &format!(
+            "log_ip = \"127.0.0.1\"\nlog_port = {port}\nreconnect_initial_ms = 60000\nbuffer_file = {:?}\n",
+            spool.to_str().unwrap()
+        ),
+    );
+
+    let first = common::accept(&listener);
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    drop(first.join().unwrap());
+    thread::sleep(Duration::from_millis(50));
+    try_log_send(&mut log, "queued 1").unwrap();
+    try_log_send(&mut log, "queued 2").unwrap();
+    drop(log);
+
+    let second = common::accept(&listener);
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    let mut second = second.join().unwrap();
+    try_log_send(&mut log, "fresh").unwrap();
+    assert_eq!(payloads(&read_records(&mut second, 3)), ["queued 1", "queued 2", "fresh"]);
+    assert_eq!(std::fs::metadata(&spool).unwrap().len(), 0);
+}
+
+//In async mode sends return immediately and log_flush waits for delivery
+#[test]
+fn async_send_and_flush() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = write_config("async", &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\n"));
+
+    let server = common::accept(&listener);
+    let mut log = try_log_connect_async(path.to_str().unwrap()).unwrap();
+    let mut server = server.join().unwrap();
+    for i in 0..100 {
+        try_log_send(&mut log, &format!("Message {i}")).unwrap();
+    }
+    log_flush(&mut log).unwrap();
+
+    let records = read_records(&mut server, 100);
+    for (i, record) in records.iter().enumerate() {
+        assert_eq!(record.payload, format!("Message {i}"));
+    }
+}
+
+//Dropping an async Log delivers what is still queued, reconnecting if needed
+#[test]
+fn async_drop_drains_queue() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = write_config(
+        "async_drop",
+        &format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nlog_async = true\nreconnect_initial_ms = 10\n"),
+    );
+
+    let first = common::accept(&listener);
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    drop(first.join().unwrap());
+    thread::sleep(Duration::from_millis(50));
+    let second = common::accept(&listener);
+
+    try_log_send(&mut log, "a").unwrap();
+    try_log_send(&mut log, "b").unwrap();
+    drop(log);
+
+    let mut second = second.join().unwrap();
+    assert_eq!(payloads(&read_records(&mut second, 2)), ["a", "b"]);
+}
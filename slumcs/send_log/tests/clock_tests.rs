@@ -0,0 +1,75 @@
+use send_log::clock::Clock;
+use send_log::protocol::{Frame, FrameDecoder, Record};
+use send_log::{log_clock, log_clock_merge, try_log_connect, try_log_disconnect, try_log_send, LogError};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::net::TcpListener;
+
+mod common;
+
+fn clock(lamport: u64, vector: &[(&str, u64)]) -> Clock {
+    Clock { lamport, vector: vector.iter().map(|(n, c)| (n.to_string(), *c)).collect() }
+}
+
+//The string form is what applications pass between processes
+#[test]
+fn clock_round_trip() {
+    for c in [clock(0, &[]), clock(12, &[]), clock(12, &[("web#1", 4), ("db:5432#1", 7)])] {
+        assert_eq!(c.to_string().parse::<Clock>(), Ok(c));
+    }
+    assert_eq!(clock(12, &[("a", 1), ("b", 2)]).to_string(), "12;a:1,b:2");
+    assert!("twelve".parse::<Clock>().is_err());
+    assert!("12;a".parse::<Clock>().is_err());
+}
+
+#[test]
+fn merge_and_happened_before() {
+    let mut a = clock(3, &[("a", 3)]);
+    let b = clock(5, &[("a", 1), ("b", 4)]);
+    a.merge(&b);
+    assert_eq!(a, clock(5, &[("a", 3), ("b", 4)]));
+
+    assert!(clock(1, &[("a", 1)]).happened_before(&clock(2, &[("a", 1), ("b", 1)])));
+    assert!(!clock(2, &[("a", 2)]).happened_before(&clock(2, &[("a", 1), ("b", 1)])));
+    assert!(!clock(1, &[("a", 1)]).happened_before(&clock(1, &[("a", 1)])));
+    assert_eq!(Clock::default().vector, BTreeMap::new());
+}
+
+//Records carry the Log's clock, and a merged clock moves it forward
+#[test]
+fn records_carry_clocks() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = std::env::temp_dir().join(format!("send_log_clock_{}.toml", std::process::id()));
+    std::fs::write(&path, format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nlogical_clock = \"vector\"\nclock_id = \"web\"\n")).unwrap();
+    let server = common::accept(&listener);
+    let mut log = try_log_connect(path.to_str().unwrap()).unwrap();
+    let mut server = server.join().unwrap();
+
+    try_log_send(&mut log, "first").unwrap();
+    assert_eq!(log_clock(&log), Some(clock(1, &[("web", 1)])));
+    log_clock_merge(&mut log, &"9;db:6".parse().unwrap()).unwrap();
+    try_log_send(&mut log, "after merge").unwrap();
+    try_log_disconnect(&mut log).unwrap();
+
+    let mut bytes = vec![];
+    server.read_to_end(&mut bytes).unwrap();
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&bytes);
+    let mut records: Vec<Record> = vec![];
+    while let Ok(Some(Frame::Record(record))) = decoder.next_frame() {
+        records.push(record);
+    }
+    assert_eq!(records[0].field("lamport"), Some("1"));
+    assert_eq!(records[0].field("vclock"), Some("web:1"));
+    assert_eq!(Clock::of(&records[1]), Some(clock(10, &[("db", 6), ("web", 2)])));
+
+    //Without logical_clock there is nothing to merge into
+    let path = std::env::temp_dir().join(format!("send_log_noclock_{}.toml", std::process::id()));
+    std::fs::write(&path, format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\n")).unwrap();
+    let server = common::accept(&listener);
+    let mut plain = try_log_connect(path.to_str().unwrap()).unwrap();
+    drop(server.join().unwrap());
+    assert_eq!(log_clock(&plain), None);
+    assert!(matches!(log_clock_merge(&mut plain, &clock(1, &[])), Err(LogError::ConfigKeyMissing(_))));
+}
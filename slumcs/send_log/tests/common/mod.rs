@@ -0,0 +1,29 @@
+use send_log::protocol::{encode_frame, Frame, FrameDecoder};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+//Accepts the next client on a thread and welcomes its hello, as a server
+//that does not check credentials would. The client blocks until then, so
+//start this before whatever makes it connect and join it afterwards.
+pub fn accept(listener: &TcpListener) -> JoinHandle<TcpStream> {
+    let listener = listener.try_clone().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; 512];
+        let app = loop {
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                let Frame::Hello { app } = frame else { panic!("expected hello, got {frame:?}") };
+                break app;
+            }
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "closed before hello");
+            decoder.push(&buf[..n]);
+        };
+        stream.write_all(&encode_frame(&Frame::Welcome { identity: app })).unwrap();
+        stream
+    })
+}
@@ -0,0 +1,54 @@
+use send_log::protocol::{Frame, FrameDecoder, Record};
+use send_log::{facade, LogError, Severity};
+use std::io::Read;
+use std::net::TcpListener;
+use std::time::Duration;
+
+mod common;
+
+//The logger is process-wide, so everything is checked in one test
+#[test]
+fn forwards_log_macros() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let path = std::env::temp_dir().join(format!("send_log_facade_{}.toml", std::process::id()));
+    std::fs::write(&path, format!("log_ip = \"127.0.0.1\"\nlog_port = {port}\nlog_level = \"debug\"\n")).unwrap();
+
+    let server = common::accept(&listener);
+    facade::init(path.to_str().unwrap()).unwrap();
+    let mut server = server.join().unwrap();
+
+    log::trace!("not forwarded");
+    log::debug!("counting {}", 3);
+    log::debug!(target: "rustls::client::hs", "sending ClientHello");
+    log::warn!(target: "send_log", "not forwarded either");
+    log::error!(target: "billing", "card declined");
+    log::logger().flush();
+    let second = common::accept(&listener);
+    assert!(matches!(facade::init(path.to_str().unwrap()), Err(LogError::LoggerAlreadySet)));
+    drop(second.join().unwrap());
+
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut decoder = FrameDecoder::new();
+    let mut records: Vec<Record> = vec![];
+    let mut buf = [0; 4096];
+    while records.len() < 2 {
+        let n = server.read(&mut buf).unwrap();
+        assert!(n > 0);
+        decoder.push(&buf[..n]);
+        while let Some(Frame::Record(record)) = decoder.next_frame().unwrap() {
+            records.push(record);
+        }
+    }
+
+    assert_eq!(records[0].payload, "counting 3");
+    assert_eq!(records[0].severity, Severity::Debug);
+    assert_eq!(records[0].field("target"), Some("facade_tests"));
+    assert_eq!(records[0].field("module"), Some("facade_tests"));
+    assert_eq!(records[0].field("file"), Some(file!()));
+    assert!(records[0].field("line").unwrap().parse::<u32>().is_ok());
+
+    assert_eq!(records[1].payload, "card declined");
+    assert_eq!(records[1].severity, Severity::Error);
+    assert_eq!(records[1].field("target"), Some("billing"));
+}
@@ -0,0 +1,151 @@
+use send_log::compress::{parse_codecs, Codec};
+use send_log::protocol::{encode_frame, Frame, FrameDecoder, ProtocolError, Record, Severity, MAX_FRAME_LEN};
+
+fn record(payload: &str) -> Record {
+    Record {
+        sender: "tester[42]".to_string(),
+        timestamp: 1_700_000_000_123,
+        severity: Severity::Warn,
+        payload: payload.to_string(),
+        fields: vec![("request".to_string(), "7".to_string())],
+    }
+}
+
+//A single frame survives the trip through the decoder unchanged
+#[test]
+fn round_trip() {
+    let sent = record("hello world\n");
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&encode_frame(&Frame::Record(sent.clone())));
+
+    assert_eq!(decoder.next_frame(), Ok(Some(Frame::Record(sent))));
+    assert_eq!(decoder.next_frame(), Ok(None));
+    assert_eq!(decoder.pending(), 0);
+}
+
+//Bytes arriving one at a time only produce a record once it is complete
+#[test]
+fn split_across_reads() {
+    let sent = record(&"x".repeat(5000));
+    let bytes = encode_frame(&Frame::Record(sent.clone()));
+    let mut decoder = FrameDecoder::new();
+
+    for byte in &bytes[..bytes.len() - 1] {
+        decoder.push(&[*byte]);
+        assert_eq!(decoder.next_frame(), Ok(None));
+    }
+    decoder.push(&bytes[bytes.len() - 1..]);
+    assert_eq!(decoder.next_frame(), Ok(Some(Frame::Record(sent))));
+}
+
+//Several frames delivered in one read come back out as separate records
+#[test]
+fn glued_frames() {
+    let mut bytes = vec![];
+    for i in 0..10 {
+        bytes.extend(encode_frame(&Frame::Record(record(&format!("Message {i}")))));
+    }
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&bytes);
+
+    for i in 0..10 {
+        assert_eq!(decoder.next_frame(), Ok(Some(Frame::Record(record(&format!("Message {i}"))))));
+    }
+    assert_eq!(decoder.next_frame(), Ok(None));
+}
+
+#[test]
+fn rejects_oversized_frame() {
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&((MAX_FRAME_LEN + 1) as u32).to_be_bytes());
+    assert_eq!(decoder.next_frame(), Err(ProtocolError::FrameTooLarge(MAX_FRAME_LEN + 1)));
+}
+
+#[test]
+fn rejects_unknown_version() {
+    let mut bytes = encode_frame(&Frame::Record(record("hi")));
+    bytes[4] = 99;
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&bytes);
+    assert_eq!(decoder.next_frame(), Err(ProtocolError::UnsupportedVersion(99)));
+}
+
+//Fields and severity travel with the record in the order they were given
+#[test]
+fn fields_round_trip() {
+    let sent = Record::new("tester", Severity::Error, "disk full")
+        .with_fields(&[("mount", "/var"), ("used pct", "99"), ("note", "")]);
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&encode_frame(&Frame::Record(sent.clone())));
+
+    let Ok(Some(Frame::Record(received))) = decoder.next_frame() else {
+        panic!("expected a record");
+    };
+    assert_eq!(received, sent);
+    assert_eq!(received.field("mount"), Some("/var"));
+    assert_eq!(received.field("missing"), None);
+}
+
+//Records written by version 1 clients still decode, just without fields
+#[test]
+fn decodes_version_one_records() {
+    let mut body = vec![1, 1];
+    body.extend_from_slice(&2u16.to_be_bytes());
+    body.extend_from_slice(b"v1");
+    body.extend_from_slice(&7u64.to_be_bytes());
+    body.push(2);
+    body.extend_from_slice(&5u32.to_be_bytes());
+    body.extend_from_slice(b"hello");
+    let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+    bytes.extend(body);
+
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&bytes);
+    let Ok(Some(Frame::Record(received))) = decoder.next_frame() else {
+        panic!("expected a record");
+    };
+    assert_eq!(received.payload, "hello");
+    assert_eq!(received.severity, Severity::Info);
+    assert!(received.fields.is_empty());
+}
+
+//Handshake frames round-trip, including binary nonces and macs
+#[test]
+fn control_frames_round_trip() {
+    let frames = vec![
+        Frame::Hello { app: "billing".to_string() },
+        Frame::Challenge { nonce: (0..=255).collect() },
+        Frame::Auth { mac: vec![0, 255, 10, 13] },
+        Frame::Welcome { identity: "billing".to_string() },
+        Frame::Reject { reason: "bad mac".to_string() },
+        Frame::Sync { seq: u64::MAX },
+        Frame::Ack { seq: 7, error: None },
+        Frame::Ack { seq: 8, error: Some("No space left on device".to_string()) },
+        Frame::Offer { codecs: vec!["zstd".to_string(), "lz4".to_string()] },
+        Frame::Accept { codec: Some(Codec::Deflate) },
+        Frame::Accept { codec: None },
+        Frame::Batch { codec: Codec::Zstd, data: vec![1, 2, 3] },
+    ];
+    let mut decoder = FrameDecoder::new();
+    for frame in &frames {
+        decoder.push(&encode_frame(frame));
+    }
+    for frame in frames {
+        assert_eq!(decoder.next_frame(), Ok(Some(frame)));
+    }
+}
+
+//A batch unpacks to the record frames that went into it
+#[test]
+fn codecs_round_trip() {
+    let frames: Vec<u8> = (0..50).flat_map(|i| encode_frame(&Frame::Record(record(&format!("line {i}"))))).collect();
+    for codec in [Codec::Zstd, Codec::Deflate] {
+        let packed = codec.compress(&frames).unwrap();
+        assert!(packed.len() < frames.len() / 4, "{codec:?} only got {} bytes down to {}", frames.len(), packed.len());
+        assert_eq!(codec.decompress(&packed).unwrap(), frames);
+        assert!(codec.decompress(b"not compressed").is_err());
+    }
+    assert_eq!(parse_codecs("zstd,deflate"), Ok(vec![Codec::Zstd, Codec::Deflate]));
+    assert_eq!(parse_codecs("none"), Ok(vec![]));
+    assert!(parse_codecs("lz4").is_err());
+}
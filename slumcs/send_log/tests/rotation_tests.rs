@@ -0,0 +1,30 @@
+use send_log::rotation::{rotated_files, rotated_name};
+
+//Rotated files come back oldest first, -n collisions and gzipped ones
+//included, and nothing else in the directory does
+#[test]
+fn lists_rotated_files_in_order() {
+    let dir = std::env::temp_dir().join(format!("send_log_rotation_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let base = dir.join("systemlog.txt");
+    let stamp = "20240101T000000000Z";
+
+    let mut expected = vec![];
+    for gzipped in [true, false, true, false] {
+        let name = rotated_name(&base, stamp);
+        let path = if gzipped { dir.join(format!("{}.gz", name.file_name().unwrap().to_str().unwrap())) } else { name };
+        std::fs::write(&path, "").unwrap();
+        expected.push(path);
+    }
+    let later = rotated_name(&base, "20240102T000000000Z");
+    std::fs::write(&later, "").unwrap();
+    expected.push(later);
+    for other in ["systemlog.txt", "systemlog.txt.bak", "other.txt.20240101T000000000Z"] {
+        std::fs::write(dir.join(other), "").unwrap();
+    }
+
+    assert_eq!(expected[1], dir.join(format!("systemlog.txt.{stamp}-1")));
+    assert_eq!(rotated_files(&base).unwrap(), expected);
+    let _ = std::fs::remove_dir_all(&dir);
+}
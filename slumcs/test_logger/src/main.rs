@@ -1,25 +1,40 @@
 //Test the send_log and log_component features of lab 1
 
-use send_log::{log_connect, log_send, log_disconnect};
+use send_log::{log_connect, log_connect_async, log_send, log_disconnect};
+use send_log::settings;
 use std::env;
 use std::{thread, time};
 use rand::Rng;
 
 fn main(){
 
-	let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        println!("Usage: cargo run <num_connections> <log_messages>");
+	let mut args: Vec<String> = env::args().collect();
+    let explicit = match settings::take_config_arg(&mut args) {
+        Ok(x) => x,
+        Err(e) => { println!("{e}"); return; }
+    };
+    if args.len() != 3 && args.len() != 4 {
+        println!("Usage: cargo run [--config <path>] <num_connections> <log_messages> [blocking|async]");
         return;
     }
+    let config_path = match settings::locate(explicit.as_deref()) {
+        Ok(x) => x.to_string_lossy().into_owned(),
+        Err(e) => { println!("{e}"); return; }
+    };
     let connections = args[1].parse::<i32>().unwrap();
     let num_messages = args[2].parse::<i32>().unwrap();
+    let background = match args.get(3).map(|s| s.as_str()) {
+        None | Some("blocking") => false,
+        Some("async") => true,
+        Some(x) => { println!("Unknown mode \"{x}\", expected blocking or async"); return; }
+    };
 
     let mut children = vec![];
     
 	for i in 0..connections {
+        let config_path = config_path.clone();
 		children.push(
-            thread::spawn( move || {thread_body(i, num_messages);} )
+            thread::spawn( move || {thread_body(i, num_messages, background, &config_path);} )
             );
 	}
 
@@ -30,14 +45,10 @@ fn main(){
 }
 
 
-fn thread_body( id: i32, num_messages: i32 ){
-
-    let current_dir = std::env::current_dir().unwrap();
-    let config_path_buf = current_dir.join("../config.toml");
-    let config_path = config_path_buf.to_str().unwrap();
+fn thread_body( id: i32, num_messages: i32, background: bool, config_path: &str ){
 
     //Connect to the logging server
-    let mut log = log_connect(config_path);
+    let mut log = if background { log_connect_async(config_path) } else { log_connect(config_path) };
     let mut rng = rand::thread_rng();
 
     for i in 0..num_messages{
@@ -52,6 +63,7 @@ fn thread_body( id: i32, num_messages: i32 ){
         log_send( &mut log, &message );
     }
 
+    //In async mode this also waits for the queued messages to be delivered
     log_disconnect( &mut log );
 }
 